@@ -7,6 +7,7 @@ pub struct PathStats {
     pub latency: Duration,
     pub packet_loss: f32, // 0.0 to 1.0
     pub bandwidth: u64,   // bits per second
+    pub hop_failures: u32, // port hops failed in a row
     pub last_updated: Instant,
 }
 
@@ -16,6 +17,7 @@ impl PathStats {
             latency: Duration::from_millis(100), // Default assumption
             packet_loss: 0.0,
             bandwidth: 1_000_000,
+            hop_failures: 0,
             last_updated: Instant::now(),
         }
     }
@@ -24,7 +26,15 @@ impl PathStats {
     pub fn score(&self) -> u64 {
         let latency_ms = self.latency.as_millis() as u64;
         let loss_penalty = (self.packet_loss * 1000.0) as u64;
-        latency_ms + loss_penalty
+        // A blocked port says less about the path than a failed connect
+        let hop_penalty = self.hop_failures.min(10) as u64 * 50;
+        latency_ms + loss_penalty + hop_penalty
+    }
+}
+
+impl Default for PathStats {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -50,7 +60,8 @@ impl Router {
         if let Some(stats) = paths.get_mut(name) {
             // Exponential moving average for smoothing
             stats.latency = stats.latency.mul_f32(0.8) + latency.mul_f32(0.2);
-            stats.packet_loss = stats.packet_loss * 0.9; // Decay loss over time if successful
+            stats.packet_loss *= 0.9; // Decay loss over time if successful
+            stats.hop_failures = 0;
             stats.last_updated = Instant::now();
         }
     }
@@ -64,6 +75,25 @@ impl Router {
         }
     }
 
+    /// Loss measured on the path (e.g. by FEC), replacing the estimate.
+    pub fn report_loss(&self, name: &str, loss: f32) {
+        let mut paths = self.paths.lock().unwrap();
+        if let Some(stats) = paths.get_mut(name) {
+            stats.packet_loss = loss.clamp(0.0, 1.0);
+            stats.last_updated = Instant::now();
+        }
+    }
+
+    /// A port hop failed while the path itself still works: likely just
+    /// that port blocked, so the path is only nudged down, not written off.
+    pub fn report_hop_failure(&self, name: &str) {
+        let mut paths = self.paths.lock().unwrap();
+        if let Some(stats) = paths.get_mut(name) {
+            stats.hop_failures += 1;
+            stats.last_updated = Instant::now();
+        }
+    }
+
     pub fn get_best_path(&self) -> Option<String> {
         let paths = self.paths.lock().unwrap();
         paths.iter()
@@ -71,8 +101,24 @@ impl Router {
             .map(|(name, _)| name.clone())
     }
     
+    /// Every path, best first.
+    pub fn ranked_paths(&self) -> Vec<String> {
+        let paths = self.paths.lock().unwrap();
+        let mut ranked: Vec<(&String, u64)> = paths.iter()
+            .map(|(name, stats)| (name, stats.score()))
+            .collect();
+        ranked.sort_by_key(|(_, score)| *score);
+        ranked.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+
     pub fn get_stats(&self, name: &str) -> Option<PathStats> {
         let paths = self.paths.lock().unwrap();
         paths.get(name).cloned()
     }
 }
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,9 +1,14 @@
-use chimera_core::handshake::EncryptedConnection;
-use chimera_transport::tcp::TcpTransport;
-use chimera_transport::blocked::BlockedTransport;
-use chimera_transport::Transport;
+use chimera_core::bonding::BondedConnection;
+use chimera_core::racing::{self, RacingConnector};
+use chimera_core::hopping;
+use chimera_transport::fault::{FaultPlan, FaultTransport};
+use chimera_transport::shadowsocks::ShadowsocksClient;
+use chimera_transport::hopping::{HopSchedule, HoppingTransport};
+use chimera_transport::registry::{Side, TransportParams, TransportRegistry, TransportSpec};
+use chimera_transport::tcp::Desync;
+use chimera_transport::{Connection, Endpoint, Transport};
 use chimera_ai::Router;
-use chimera_core::client_proxy::ClientProxy;
+use chimera_core::client_proxy::{self, ClientProxy};
 use chimera_core::socks::Socks5Listener;
 use chimera_core::protocol::Frame;
 use anyhow::Result;
@@ -11,6 +16,7 @@ use bytes::BytesMut;
 use tracing::{Level, info, error, warn};
 use tracing_subscriber::FmtSubscriber;
 use tokio::sync::mpsc;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use chimera_core::system::MacProxyManager;
@@ -24,34 +30,142 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     let host = std::env::var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let addr_str = format!("{}:8080", host);
+    let port: u16 = std::env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string()).parse()?;
+    let addr_str = format!("{}:{}", host, port);
     
     // Resolve Server Address
     use std::net::ToSocketAddrs;
-    let addr = addr_str.to_socket_addrs()?.next().ok_or(anyhow::anyhow!("Could not resolve hostname"))?;
+    // Every address is raced, so a broken IPv6 (or IPv4) route costs little.
+    // Through CLIENT_UPSTREAM_PROXY a host name is left to the proxy, which
+    // is asked for it in place of this placeholder, so it is not looked up here
+    let proxied_name = std::env::var("CLIENT_UPSTREAM_PROXY").is_ok() && host.parse::<std::net::IpAddr>().is_err();
+    let addrs: Vec<std::net::SocketAddr> = if proxied_name {
+        vec![std::net::SocketAddr::from(([0, 0, 0, 0], port))]
+    } else {
+        addr_str.to_socket_addrs()?.collect()
+    };
+    let addr = *addrs.first().ok_or(anyhow::anyhow!("Could not resolve hostname"))?;
     info!("Target Server: {}", addr);
 
-    // 2. Setup AI Router
+    let mut registry = TransportRegistry::builtin();
+    let mut params = TransportParams::new(Side::Client, addr);
+
+    // Transports with a port of their own are reached on SERVER_<KEY>_PORT
+    // (e.g. SERVER_KCP_PORT), or at a whole endpoint given in SERVER_<KEY>
+    // (e.g. SERVER_UNIX=unix:/run/chimera.sock for a server on this host)
+    for key in registry.specs().filter_map(|spec| spec.key()) {
+        if let Ok(endpoint) = std::env::var(format!("SERVER_{}", key)) {
+            params = params.with_endpoint(key, endpoint.parse()?);
+        } else if let Ok(port) = std::env::var(format!("SERVER_{}_PORT", key)) {
+            params = params.with_endpoint(key, std::net::SocketAddr::new(addr.ip(), port.parse()?).into());
+        }
+    }
+
+    // Everything else a transport takes (SERVER_WS_PATH, SERVER_TLS_PIN,
+    // CLIENT_SSH_KEY, CLIENT_UPSTREAM_PROXY, CLIENT_TCP_OPTIONS, CLIENT_FEC, ...)
+    // it reads from the environment itself
+    params = params.with_settings(std::env::vars()).with_notices(|notice| info!("{}", notice));
+
+    // CLIENT_FAULT_PLAN (e.g. "latency=200ms,loss=0.01,outage=30s:10s,period=60s")
+    // puts every path behind scripted faults, to exercise failover and reconnects
+    let fault_plan: Option<FaultPlan> = match std::env::var("CLIENT_FAULT_PLAN") {
+        Ok(plan) => Some(plan.parse()?),
+        Err(_) => None,
+    };
+
+    // CLIENT_DESYNC (e.g. "split=4,delay=50ms,disorder=3") adds a TCP-Desync path that
+    // cuts up its handshake that way, next to the stock TCP-Split/-Disorder/-Delay
+    if let Ok(desync) = std::env::var("CLIENT_DESYNC") {
+        let desync: Desync = desync.parse()?;
+        registry.register(TransportSpec::new("TCP-Desync", move |params| {
+            Ok(Box::new(params.tcp()?.with_desync(desync.clone())))
+        }).with_latency(std::time::Duration::from_millis(105)).client_only());
+    }
+
+    // CLIENT_BOND (e.g. "TCP,QUIC") stripes the tunnel over several paths at once
+    let bond_paths: Vec<String> = std::env::var("CLIENT_BOND")
+        .map(|paths| paths.split(',').map(|path| path.trim().to_string()).filter(|path| !path.is_empty()).collect())
+        .unwrap_or_default();
+
+    // 2. Setup AI Router, with a path per available transport
     let router = Arc::new(Router::new());
-    router.register_path("BlockedProtocol");
-    router.update_latency("BlockedProtocol", std::time::Duration::from_millis(10));
-    router.register_path("TCP");
-    router.update_latency("TCP", std::time::Duration::from_millis(100));
+    registry.register_paths(&router, &params);
+
+    // CLIENT_FEC (e.g. "group=10,min=0.1,max=0.5") reports the loss its sessions
+    // measure as the KCP path's, which new sessions size their parity from
+    params = params.with_router(router.clone());
+
+    // Each connect races the CLIENT_RACE_WIDTH best paths (default 3) to every
+    // server address, starting one more every CLIENT_RACE_STAGGER_MS (250)
+    let mut racer = RacingConnector::new(router.clone())
+        .with_addresses(addrs.iter().map(|addr| addr.ip()).collect());
+    if let Ok(width) = std::env::var("CLIENT_RACE_WIDTH") {
+        racer = racer.with_width(width.parse()?);
+    }
+    if let Ok(stagger) = std::env::var("CLIENT_RACE_STAGGER_MS") {
+        racer = racer.with_stagger(std::time::Duration::from_millis(stagger.parse()?));
+    }
+
+    // SERVER_HOP_PORTS (e.g. "40000-40999") and SERVER_HOP_SECRET, as given to
+    // the server, move the SERVER_HOP_TRANSPORT path (default TCP) to another
+    // port every SERVER_HOP_INTERVAL_SECS (60), streams and all
+    let hop = HopSchedule::from_env()?;
+
+    // Transports are built once, so e.g. HTTP/2 reuses its connection across reconnects
+    let transports: HashMap<String, (Arc<dyn Transport>, Endpoint)> = registry.build_all(&params)?
+        .into_iter()
+        .map(|(name, transport, endpoint)| {
+            let transport: Arc<dyn Transport> = match &hop {
+                Some((hop_path, schedule)) if *hop_path == name => Arc::new(HoppingTransport::new(transport, schedule.clone())),
+                _ => Arc::from(transport),
+            };
+            (name, (transport, endpoint))
+        })
+        .collect();
 
     // 3. Initialize Persistent Components (Proxy, SOCKS, System Config)
     // Increased global channel buffer to 50000 to prevent backpressure on heavy load
     let (tunnel_tx, mut tunnel_rx) = mpsc::channel::<Frame>(50000);
     let proxy = Arc::new(ClientProxy::new(tunnel_tx));
 
+    // CLIENT_SHADOWSOCKS (ss://method:key@host:port, a 2022-blake3-* method) sends
+    // every SOCKS connection through that Shadowsocks server instead of a tunnel
+    let shadowsocks: Option<ShadowsocksClient> = match std::env::var("CLIENT_SHADOWSOCKS") {
+        Ok(url) => {
+            let client = url.parse::<ShadowsocksClient>()?.with_tcp_options(params.tcp_options()?);
+            Some(match params.upstream_proxy()? {
+                Some(proxy) => client.with_upstream_proxy(proxy),
+                None => client,
+            })
+        }
+        Err(_) => None,
+    };
+
     let socks_addr = "127.0.0.1:1080".parse()?;
     let listener = Socks5Listener::bind(socks_addr).await?;
     let proxy_clone = proxy.clone();
+    let shadowsocks_clone = shadowsocks.clone();
     
     // Start SOCKS5 Listener in background (persists across reconnections)
     tokio::spawn(async move {
         loop {
             if let Ok((socket, target, port)) = listener.accept().await {
-                 proxy_clone.start_new_stream(socket, target, port).await;
+                match &shadowsocks_clone {
+                    Some(shadowsocks) => {
+                        let shadowsocks = shadowsocks.clone();
+                        tokio::spawn(async move {
+                            let target = format!("{}:{}", target, port);
+                            let result = match shadowsocks.connect(&target).await {
+                                Ok(conn) => client_proxy::relay(socket, conn).await,
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = result {
+                                warn!("Shadowsocks connection to {}: {}", target, e);
+                            }
+                        });
+                    }
+                    None => proxy_clone.start_new_stream(socket, target, port).await,
+                }
             }
         }
     });
@@ -63,100 +177,146 @@ async fn main() -> Result<()> {
     if let Err(e) = sys_proxy.enable("127.0.0.1", 1080) {
         error!("Failed to enable System Proxy: {}", e);
     }
+
+    // Nothing to tunnel when the Shadowsocks server does it all
+    if let Some(shadowsocks) = &shadowsocks {
+        info!("Sending connections through {}", shadowsocks);
+        tokio::signal::ctrl_c().await?;
+        info!("Shutdown signal received.");
+        sys_proxy.disable();
+        return Ok(());
+    }
     
+    // Transport and endpoint for each Router path
+    let path_transport = |name: &str| -> Result<(Box<dyn Transport>, Endpoint)> {
+        let (transport, target) = transports.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown path '{}'", name))?;
+        let transport: Box<dyn Transport> = match &fault_plan {
+            Some(plan) => Box::new(FaultTransport::new(transport.clone(), plan.clone())),
+            None => Box::new(transport.clone()),
+        };
+        Ok((transport, target.clone()))
+    };
+
     // 4. Main Reconnection Loop
     // If the tunnel drops, we loop back here and reconnect.
+    let mut hopper: Option<tokio::task::JoinHandle<()>> = None;
     loop {
         info!("Connecting to tunnel...");
         let mut attempt = 0;
-        let mut secure_conn = loop {
+        let secure_conn: Box<dyn Connection> = loop {
             attempt += 1;
-            
-            // AI Path Selection logic
-            let best_path_name = router.get_best_path().unwrap_or("TCP".to_string());
-            if attempt > 1 {
-                 warn!("Attempt {}: connecting via '{}'...", attempt, best_path_name);
-            }
 
-            let transport: Box<dyn Transport> = match best_path_name.as_str() {
-                "BlockedProtocol" => Box::new(BlockedTransport),
-                "TCP" => Box::new(TcpTransport),
-                _ => Box::new(TcpTransport),
-            };
-
-            match transport.connect(addr).await {
-                Ok(raw_conn) => {
-                    let mimic = Some(Box::new(chimera_core::mimic::HttpMimic) as Box<dyn chimera_core::mimic::Mimic>);
-                    
-                    // Add 10-second timeout for client handshake
-                    let handshake_future = EncryptedConnection::new(raw_conn, false, mimic);
-                    match tokio::time::timeout(std::time::Duration::from_secs(10), handshake_future).await {
-                        Ok(Ok(conn)) => {
-                            info!("Tunnel established via {}!", best_path_name);
-                            router.update_latency(&best_path_name, std::time::Duration::from_millis(50));
-                            break conn;
+            if !bond_paths.is_empty() {
+                // Bonding: bring up every member path and stripe over the ones that made it
+                let mut members: Vec<(String, Box<dyn Connection>)> = Vec::new();
+                for name in &bond_paths {
+                    let (transport, target) = path_transport(name)?;
+                    match racing::dial(transport, target).await {
+                        Ok(conn) => {
+                            router.update_latency(name, std::time::Duration::from_millis(50));
+                            members.push((name.clone(), Box::new(conn)));
                         }
-                        Ok(Err(e)) => {
-                            warn!("Handshake failed: {}", e);
-                            router.report_failure(&best_path_name);
+                        Err(e) => {
+                            warn!("Bond member {}: {}", name, e);
+                            router.report_failure(name);
                         }
-                        Err(_) => {
-                            warn!("Handshake timed out (10s)");
-                            router.report_failure(&best_path_name);
+                    }
+                }
+                if !members.is_empty() {
+                    let count = members.len();
+                    match BondedConnection::connect(members, router.clone()).await {
+                        Ok(bond) => {
+                            info!("Tunnel established over {} bonded paths!", count);
+                            break Box::new(bond);
                         }
+                        Err(e) => warn!("Bond setup failed: {}", e),
                     }
                 }
-                Err(e) => {
-                     warn!("Transport connect failed: {}", e);
-                     router.report_failure(&best_path_name);
+                tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+                continue;
+            }
+            
+            // AI Path Selection logic: the best paths race, the router hears how each did
+            if attempt > 1 {
+                 warn!("Attempt {}: racing {:?}...", attempt, router.ranked_paths());
+            }
+
+            match racer.connect(&path_transport).await {
+                Ok((path, conn)) => {
+                    info!("Tunnel established via {}!", path);
+                    let Some((_, schedule)) = hop.as_ref().filter(|(hop_path, _)| *hop_path == path) else {
+                        break Box::new(conn);
+                    };
+                    // A bond of one, so each hop can move it and its streams along
+                    match BondedConnection::connect(vec![(path.clone(), Box::new(conn))], router.clone()).await {
+                        Ok(bond) => {
+                            let (transport, target) = path_transport(&path)?;
+                            let run = hopping::run(bond.handle(), path, Arc::from(transport), target, schedule.clone(), router.clone());
+                            hopper = Some(tokio::spawn(run));
+                            break Box::new(bond);
+                        }
+                        Err(e) => warn!("Hopping setup failed: {}", e),
+                    }
                 }
+                Err(e) => warn!("{}", e),
             }
             tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
         };
 
         // 5. Data Transfer Loop (The "Active" State)
-        let mut buf = BytesMut::with_capacity(8192);
-        
-        // We use a nested select loop. If `secure_conn` fails, we break this inner loop, 
-        // which returns us to the outer `loop` (Reconnection).
-        // If Ctrl+C happens, we return from Main entirely.
-        
-        let disconnect_reason = loop {
-             tokio::select! {
-                // A. Handle Cleanup Signal and EXIT APP
-                _ = tokio::signal::ctrl_c() => {
-                    info!("Shutdown signal received.");
-                    sys_proxy.disable();
-                    return Ok(());
-                }
-                
-                // B. Read from Tunnel -> Forward to Proxy 
-                res = secure_conn.recv() => {
-                    match res {
-                        Ok(Some(data)) => {
-                            buf.extend_from_slice(&data);
-                            while let Ok(Some(len)) = Frame::check(&mut std::io::Cursor::new(&buf[..])) {
-                                let frame_bytes = buf.split_to(len).freeze();
-                                if let Ok(frame) = Frame::parse(&mut bytes::Bytes::from(frame_bytes)) {
-                                    let _ = proxy.handle_frame(frame).await;
-                                }
+        // The tunnel splits so that reading from it and writing to it run side
+        // by side. Whichever direction fails first ends it and returns us to
+        // the outer `loop` (Reconnection). If Ctrl+C happens, we return from
+        // Main entirely.
+        let (mut reader, mut writer) = secure_conn.split();
+
+        // B. Read from Tunnel -> Forward to Proxy
+        let receiving = async {
+            let mut buf = BytesMut::with_capacity(8192);
+            loop {
+                match reader.recv().await {
+                    Ok(Some(data)) => {
+                        buf.extend_from_slice(&data);
+                        while let Ok(Some(len)) = Frame::check(&mut std::io::Cursor::new(&buf[..])) {
+                            let mut frame_bytes = buf.split_to(len).freeze();
+                            if let Ok(frame) = Frame::parse(&mut frame_bytes) {
+                                let _ = proxy.handle_frame(frame).await;
                             }
                         }
-                        Ok(None) => break "Tunnel Closed (EOF)",
-                        Err(e) => break "Tunnel Error (Read)", 
                     }
+                    Ok(None) => return "Tunnel Closed (EOF)",
+                    Err(_) => return "Tunnel Error (Read)",
                 }
+            }
+        };
 
-                // C. Read from Proxy -> Forward to Tunnel
-                Some(frame) = tunnel_rx.recv() => {
-                    let bytes = frame.to_bytes();
-                    if let Err(_) = secure_conn.send(&bytes).await {
-                         break "Tunnel Error (Write)";
-                    }
+        // C. Read from Proxy -> Forward to Tunnel, whatever is queued in one go
+        let sending = async {
+            let mut frames = Vec::with_capacity(64);
+            while tunnel_rx.recv_many(&mut frames, 64).await > 0 {
+                let batch: Vec<_> = frames.drain(..).map(|frame| frame.to_bytes()).collect();
+                if writer.send_vectored(&batch).await.is_err() {
+                    return "Tunnel Error (Write)";
                 }
             }
+            "Proxy Closed"
+        };
+
+        let disconnect_reason = tokio::select! {
+            // A. Handle Cleanup Signal and EXIT APP
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutdown signal received.");
+                sys_proxy.disable();
+                return Ok(());
+            }
+            reason = receiving => reason,
+            reason = sending => reason,
         };
         
+        if let Some(hopper) = hopper.take() {
+            hopper.abort();
+        }
         warn!("Disconnected: {}. Reconnecting in 1s...", disconnect_reason);
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
@@ -1,9 +1,13 @@
 use chimera_core::ChimeraNode;
-use chimera_transport::tcp::TcpTransport;
+use chimera_transport::Endpoint;
+use chimera_transport::hopping::{HopSchedule, HoppingTransport};
+use chimera_transport::shadowsocks::ShadowsocksServer;
+use chimera_transport::registry::{Side, TransportParams, TransportRegistry};
 use anyhow::Result;
-use tracing::Level;
+use tracing::{Level, info};
 use tracing_subscriber::FmtSubscriber;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -14,14 +18,83 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting default subscriber failed");
 
+    let registry = TransportRegistry::builtin();
+    // SERVER_BIND may list several addresses, e.g. "0.0.0.0:8080,0.0.0.0:443",
+    // or "[::]:8080" for IPv4 and IPv6 alike
+    let binds: Vec<SocketAddr> = parse_list(&std::env::var("SERVER_BIND").unwrap_or_else(|_| "0.0.0.0:8080".to_string()))?;
+    let mut params = TransportParams::new(Side::Server, binds[0]);
+
+    // Transports with a port of their own bind SERVER_<KEY>_BIND (e.g.
+    // SERVER_KCP_BIND), by default their stock port on SERVER_BIND's addresses.
+    // Some only run when given one (SERVER_UNIX_BIND=unix:/run/chimera.sock).
+    let mut key_binds: HashMap<String, Vec<Endpoint>> = HashMap::new();
+    for key in registry.specs().filter_map(|spec| spec.key()) {
+        if let Ok(endpoints) = std::env::var(format!("SERVER_{}_BIND", key)) {
+            let endpoints: Vec<Endpoint> = parse_list(&endpoints)?;
+            params = params.with_endpoint(key, endpoints[0].clone());
+            key_binds.insert(key.to_string(), endpoints);
+        }
+    }
+
+    // Behind NAT: SERVER_PUNCH is a rendezvous (e.g. "203.0.113.7:3478") to
+    // register with as SERVER_PUNCH_ID, for clients to punch through to
+    if let Ok(rendezvous) = std::env::var("SERVER_PUNCH") {
+        let rendezvous: Endpoint = rendezvous.parse()?;
+        params = params.with_endpoint("PUNCH", rendezvous.clone());
+        key_binds.insert("PUNCH".to_string(), vec![rendezvous]);
+    }
+
+    // Everything else a transport takes (SERVER_WS_PATH, SERVER_TLS_CERT,
+    // SERVER_FEC, SERVER_PROXY_PROTOCOL, ...) it reads from the environment itself
+    params = params.with_settings(std::env::vars()).with_notices(|notice| info!("{}", notice));
+
+    // One node, each transport on its own endpoints
     let mut node = ChimeraNode::new();
-    
-    // Add transports
-    node.add_transport(Box::new(TcpTransport));
+    for (name, transport, endpoint) in registry.build_all(&params)? {
+        let key = registry.specs().find(|spec| spec.name() == name).and_then(|spec| spec.key());
+        let endpoints = match (key, endpoint) {
+            (Some(key), _) if key_binds.contains_key(key) => key_binds[key].clone(),
+            // On the main address: every SERVER_BIND address
+            (None, Endpoint::Inet(_)) => binds.iter().map(|&bind| bind.into()).collect(),
+            // Its stock port on every SERVER_BIND address
+            (Some(_), Endpoint::Inet(addr)) => bind_ips(&binds).into_iter()
+                .map(|ip| SocketAddr::new(ip, addr.port()).into())
+                .collect(),
+            (_, endpoint) => vec![endpoint],
+        };
+        node.add_transport_on(transport, endpoints);
+    }
+
+    // Port hopping (SERVER_HOP_PORTS, e.g. "40000-40999", and SERVER_HOP_SECRET):
+    // SERVER_HOP_TRANSPORT (default TCP) also listens on whichever ports of the
+    // range the schedule has in use, changing every SERVER_HOP_INTERVAL_SECS (60)
+    if let Some((name, schedule)) = HopSchedule::from_env()? {
+        let (transport, _) = registry.build(&name, &params)?;
+        let endpoints = bind_ips(&binds).into_iter().map(|ip| SocketAddr::new(ip, 0).into()).collect();
+        node.add_transport_on(Box::new(HoppingTransport::new(transport, schedule)), endpoints);
+    }
+
+    // Shadowsocks 2022 clients (SERVER_SHADOWSOCKS, e.g. "2022-blake3-aes-256-gcm:<base64 key>")
+    // on SERVER_SHADOWSOCKS_BIND, by default port 8388 on SERVER_BIND's addresses
+    if let Ok(config) = std::env::var("SERVER_SHADOWSOCKS") {
+        let mut server = ShadowsocksServer::new(config.parse()?).with_tcp_options(params.tcp_options()?);
+        if let Some(trusted) = params.proxy_protocol()? {
+            server = server.with_proxy_protocol(trusted);
+        }
+        let endpoints = match std::env::var("SERVER_SHADOWSOCKS_BIND") {
+            Ok(endpoints) => parse_list(&endpoints)?,
+            Err(_) => bind_ips(&binds).into_iter().map(|ip| SocketAddr::new(ip, 8388).into()).collect(),
+        };
+        node.add_shadowsocks(server, endpoints);
+    }
 
-    // Bind address
-    let bind_addr = std::env::var("SERVER_BIND").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let addr: SocketAddr = bind_addr.parse()?;
+    // Be the rendezvous for nodes behind NAT (SERVER_RENDEZVOUS_BIND, e.g. "0.0.0.0:3478"),
+    // for those given the same SERVER_PUNCH_KEY
+    if let Ok(endpoints) = std::env::var("SERVER_RENDEZVOUS_BIND") {
+        let key = std::env::var("SERVER_PUNCH_KEY")
+            .map_err(|_| anyhow::anyhow!("A rendezvous needs a key to share with its nodes (SERVER_PUNCH_KEY)"))?;
+        node.add_rendezvous(key.as_bytes(), parse_list(&endpoints)?);
+    }
 
     // Create a shutdown signal
     let shutdown_signal = async {
@@ -32,8 +105,8 @@ async fn main() -> Result<()> {
     };
 
     tokio::select! {
-        res = node.run_server(addr) => {
-            if let Err(e) = res {
+        result = node.run_server(binds[0].into()) => {
+            if let Err(e) = result {
                 tracing::error!("Server error: {}", e);
             }
         }
@@ -42,3 +115,36 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// The distinct SERVER_BIND addresses, for listening on other ports.
+fn bind_ips(binds: &[SocketAddr]) -> Vec<IpAddr> {
+    // `[::]` already takes IPv4, so `0.0.0.0` would only collide
+    let dual_stack = binds.iter().any(|bind| bind.ip() == Ipv6Addr::UNSPECIFIED);
+    let mut ips = Vec::new();
+    for bind in binds {
+        if dual_stack && bind.ip() == Ipv4Addr::UNSPECIFIED {
+            continue;
+        }
+        if !ips.contains(&bind.ip()) {
+            ips.push(bind.ip());
+        }
+    }
+    ips
+}
+
+/// Parses a comma-separated list with at least one item.
+fn parse_list<T>(list: &str) -> Result<Vec<T>>
+where
+    T: std::str::FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    let items = list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(Into::into))
+        .collect::<Result<Vec<T>>>()?;
+    if items.is_empty() {
+        return Err(anyhow::anyhow!("Expected at least one address"));
+    }
+    Ok(items)
+}
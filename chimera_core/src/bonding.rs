@@ -0,0 +1,577 @@
+//! Multipath bonding: one tunnel striped over several member connections
+//! (e.g. Wi-Fi and cellular, or TCP and QUIC) at once.
+//!
+//! Members are ordinary secured tunnels that keep record boundaries, such
+//! as `EncryptedConnection`. The first record on each announces the bond it
+//! belongs to; after that every record is one bond packet:
+//!
+//! * `HELLO [bond id: 16]`
+//! * `DATA [seq: 8] [payload]`
+//! * `ACK [next undelivered seq: 8]`
+//! * `FIN [seq: 8]`
+//!
+//! Records are numbered across the whole bond, sent on a member picked by
+//! its `Router` score and put back in order on receipt. Records not yet
+//! acknowledged are kept, and resent on the survivors when a member dies.
+//! Acknowledgements only cover records handed to the local reader, so the
+//! window also bounds what a slow reader makes the bond buffer.
+
+use chimera_transport::{ChannelRecvHalf, ChannelSendHalf, Connection, RecvHalf, SendHalf};
+use chimera_ai::Router;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+const HELLO: u8 = 0xB0;
+const DATA: u8 = 0xB1;
+const ACK: u8 = 0xB2;
+const FIN: u8 = 0xB3;
+
+type BondId = [u8; 16];
+
+/// A member connection and the `Router` path it came over.
+type NamedMember = (String, Box<dyn Connection>);
+
+/// Changes to a running bond's members.
+enum Control {
+    Join(NamedMember),
+    /// Join, then retire every other member once the peer is heard on it.
+    MoveTo(NamedMember),
+}
+
+/// Records in flight (sent, not yet acknowledged) before sending waits.
+const WINDOW: usize = 1024;
+
+/// How often received records are acknowledged when nothing forces it sooner.
+const ACK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Whether the first record of a secured tunnel announces a bond member.
+pub fn is_hello(record: &[u8]) -> bool {
+    record.len() == 1 + 16 && record[0] == HELLO
+}
+
+/// A tunnel striped over several member connections; survives losing
+/// members as long as one is left.
+pub struct BondedConnection {
+    // Dropping the sender ends the bond once everything is acknowledged
+    tx: Option<mpsc::Sender<Bytes>>,
+    rx: mpsc::Receiver<Result<Option<Bytes>>>,
+    handle: BondHandle,
+}
+
+impl BondedConnection {
+    /// Client side: announces a new bond on every member (named after its
+    /// `Router` path) and starts striping over them.
+    pub async fn connect(members: Vec<NamedMember>, router: Arc<Router>) -> Result<Self> {
+        if members.is_empty() {
+            return Err(anyhow!("A bond needs at least one member"));
+        }
+        let id: BondId = rand::thread_rng().gen();
+        let mut hello = BytesMut::with_capacity(1 + 16);
+        hello.put_u8(HELLO);
+        hello.put_slice(&id);
+        let hello = hello.freeze();
+
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        for (name, mut connection) in members {
+            connection.send(hello.clone()).await?;
+            let _ = control_tx.send(Control::Join((name, connection)));
+        }
+        Ok(Self::spawn(BondHandle { hello, control_tx }, control_rx, router))
+    }
+
+    fn spawn(handle: BondHandle, control_rx: mpsc::UnboundedReceiver<Control>, router: Arc<Router>) -> Self {
+        let (up_tx, up_rx) = mpsc::channel(100);
+        let (down_tx, down_rx) = mpsc::channel(100);
+        tokio::spawn(Driver::new(router).run(control_rx, up_rx, down_tx));
+        Self { tx: Some(up_tx), rx: down_rx, handle }
+    }
+
+    /// For changing the members while the bond runs.
+    pub fn handle(&self) -> BondHandle {
+        self.handle.clone()
+    }
+}
+
+/// Adds members to a running bond (client side), e.g. to move it to a
+/// fresh connection.
+#[derive(Clone)]
+pub struct BondHandle {
+    hello: Bytes,
+    control_tx: mpsc::UnboundedSender<Control>,
+}
+
+impl BondHandle {
+    /// Announces `connection` as a member and adds it to the bond.
+    pub async fn join(&self, name: &str, connection: Box<dyn Connection>) -> Result<()> {
+        let member = self.announce(name, connection).await?;
+        self.control(Control::Join(member))
+    }
+
+    /// Adds `connection` and, once the peer has taken it into the bond,
+    /// retires every other member: what they had in flight is resent, and
+    /// they close without counting as failures.
+    pub async fn move_to(&self, name: &str, connection: Box<dyn Connection>) -> Result<()> {
+        let member = self.announce(name, connection).await?;
+        self.control(Control::MoveTo(member))
+    }
+
+    /// Whether the bond has ended.
+    pub fn is_closed(&self) -> bool {
+        self.control_tx.is_closed()
+    }
+
+    async fn announce(&self, name: &str, mut connection: Box<dyn Connection>) -> Result<NamedMember> {
+        connection.send(self.hello.clone()).await?;
+        Ok((name.to_string(), connection))
+    }
+
+    fn control(&self, control: Control) -> Result<()> {
+        self.control_tx.send(control).map_err(|_| anyhow!("Bond terminated"))
+    }
+}
+
+#[async_trait]
+impl Connection for BondedConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| anyhow!("Bond closed"))?;
+        tx.send(data).await.map_err(|_| anyhow!("Bond terminated"))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.rx.recv().await.transpose()?.flatten())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn RecvHalf>, Box<dyn SendHalf>) {
+        (Box::new(ChannelRecvHalf::new(self.rx)), Box::new(ChannelSendHalf::new(self.tx)))
+    }
+}
+
+/// Server side: groups incoming members by bond.
+pub struct BondRegistry {
+    bonds: Mutex<HashMap<BondId, mpsc::UnboundedSender<Control>>>,
+    router: Arc<Router>,
+}
+
+impl BondRegistry {
+    pub fn new(router: Arc<Router>) -> Self {
+        Self { bonds: Mutex::new(HashMap::new()), router }
+    }
+
+    /// Adds a member whose first record was `hello`. Returns the bond if
+    /// this member started it; later members join the running bond.
+    pub fn join(&self, name: &str, connection: Box<dyn Connection>, hello: &[u8]) -> Option<BondedConnection> {
+        let id: BondId = hello[1..].try_into().ok()?;
+        let mut bonds = self.bonds.lock().unwrap();
+        bonds.retain(|_, join_tx| !join_tx.is_closed());
+
+        let member = Control::Join((name.to_string(), connection));
+        let member = match bonds.get(&id) {
+            Some(control_tx) => match control_tx.send(member) {
+                Ok(()) => return None,
+                Err(mpsc::error::SendError(member)) => member,
+            },
+            None => member,
+        };
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let _ = control_tx.send(member);
+        bonds.insert(id, control_tx.clone());
+        let handle = BondHandle { hello: Bytes::copy_from_slice(hello), control_tx };
+        Some(BondedConnection::spawn(handle, control_rx, self.router.clone()))
+    }
+}
+
+enum Event {
+    Packet(usize, Bytes),
+    Dead(usize, anyhow::Error),
+}
+
+struct Member {
+    name: String,
+    tx: mpsc::UnboundedSender<Bytes>,
+}
+
+struct Driver {
+    router: Arc<Router>,
+    // Indexed by member number; dead members leave a hole
+    members: Vec<Option<Member>>,
+    next_seq: u64,
+    // Sent records not yet acknowledged: (seq, member, packet)
+    unacked: VecDeque<(u64, usize, Bytes)>,
+    next_expected: u64,
+    // Early records, and the FIN (`None`), waiting for the gap before them
+    reorder: BTreeMap<u64, Option<Bytes>>,
+    // In order, waiting for room in the reader's channel
+    ready: VecDeque<Option<Bytes>>,
+    delivered: u64,
+    acked_to_peer: u64,
+    fin_sent: bool,
+    fin_received: bool,
+    // Member to move to once something arrives on it
+    moving_to: Option<usize>,
+}
+
+impl Driver {
+    fn new(router: Arc<Router>) -> Self {
+        Self {
+            router,
+            members: Vec::new(),
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            next_expected: 0,
+            reorder: BTreeMap::new(),
+            ready: VecDeque::new(),
+            delivered: 0,
+            acked_to_peer: 0,
+            fin_sent: false,
+            fin_received: false,
+            moving_to: None,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut control_rx: mpsc::UnboundedReceiver<Control>,
+        mut up_rx: mpsc::Receiver<Bytes>,
+        down_tx: mpsc::Sender<Result<Option<Bytes>>>,
+    ) {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let mut ack_timer = tokio::time::interval(ACK_INTERVAL);
+        let mut joining = true;
+
+        loop {
+            tokio::select! {
+                // Members first, so nothing is sent before there is one to
+                // send it on
+                biased;
+                control = control_rx.recv(), if joining => match control {
+                    Some(Control::Join((name, connection))) => {
+                        self.add_member(name, connection, &events_tx);
+                    }
+                    Some(Control::MoveTo((name, connection))) => {
+                        self.moving_to = Some(self.add_member(name, connection, &events_tx));
+                    }
+                    None => joining = false,
+                },
+                Some(event) = events_rx.recv() => match event {
+                    Event::Packet(member, packet) => {
+                        // The peer has it: the others can go
+                        if self.moving_to == Some(member) {
+                            self.moving_to = None;
+                            for other in 0..member {
+                                self.retire_member(other);
+                            }
+                        }
+                        if let Err(e) = self.handle_packet(packet, down_tx.is_closed()) {
+                            warn!("Bond: dropping member {}: {}", member, e);
+                            self.remove_member(member);
+                        }
+                    }
+                    Event::Dead(member, e) => {
+                        warn!("Bond: member {} lost: {}", member, e);
+                        self.remove_member(member);
+                    }
+                },
+                // Never wait on the reader here: it may itself be waiting to send
+                permit = down_tx.reserve(), if !self.ready.is_empty() => match permit {
+                    Ok(permit) => {
+                        let record = self.ready.pop_front().flatten();
+                        self.mark_delivered(record.is_none());
+                        permit.send(Ok(record));
+                    }
+                    // The local side went away; keep acknowledging regardless
+                    Err(_) => self.discard_ready(),
+                },
+                data = up_rx.recv(), if !self.fin_sent && self.unacked.len() < WINDOW => {
+                    if data.is_none() {
+                        self.fin_sent = true;
+                    }
+                    self.send_record(data);
+                }
+                _ = ack_timer.tick() => {
+                    if self.delivered > self.acked_to_peer {
+                        self.send_ack();
+                    }
+                }
+            }
+
+            let finished = self.fin_sent && self.unacked.is_empty() && (self.fin_received || down_tx.is_closed());
+            if finished {
+                // Let the peer know its last records arrived, then close the members
+                self.send_ack();
+                return;
+            }
+            // Members never leave the list, only their slot empties
+            if !self.members.is_empty() && self.members.iter().all(Option::is_none) {
+                // Fail the writer first, so the reader is not stuck behind it
+                drop(up_rx);
+                for record in self.ready.drain(..) {
+                    let _ = down_tx.send(Ok(record)).await;
+                }
+                if !self.fin_received {
+                    let _ = down_tx.send(Err(anyhow!("All bond members lost"))).await;
+                }
+                return;
+            }
+        }
+    }
+
+    fn add_member(&mut self, name: String, connection: Box<dyn Connection>, events_tx: &mpsc::UnboundedSender<Event>) -> usize {
+        let index = self.members.len();
+        info!("Bond: member {} joined via {}", index, name);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_member(index, connection, rx, events_tx.clone()));
+        // Something for the peer to hear on it right away, so a move can go ahead
+        let _ = tx.send(self.ack_packet());
+        self.members.push(Some(Member { name, tx }));
+        index
+    }
+
+    /// Drops a member that failed and resends whatever it had in flight on
+    /// the others.
+    fn remove_member(&mut self, index: usize) {
+        let Some(member) = self.members.get_mut(index).and_then(Option::take) else {
+            return;
+        };
+        self.router.report_failure(&member.name);
+        self.reassign(index);
+    }
+
+    /// Closes a healthy member once its queue drains, resending whatever
+    /// it had in flight on the others.
+    fn retire_member(&mut self, index: usize) {
+        if self.members.get_mut(index).and_then(Option::take).is_some() {
+            info!("Bond: member {} retired", index);
+            self.reassign(index);
+        }
+    }
+
+    fn reassign(&mut self, index: usize) {
+        if self.members.iter().all(Option::is_none) {
+            return;
+        }
+        for i in 0..self.unacked.len() {
+            if self.unacked[i].1 == index {
+                let packet = self.unacked[i].2.clone();
+                self.unacked[i].1 = self.send_packet(packet);
+            }
+        }
+    }
+
+    /// Picks a live member at random, weighted by its path score (lower
+    /// is better), and hands it the packet. Returns the member used.
+    fn send_packet(&self, packet: Bytes) -> usize {
+        let weights: Vec<(usize, f64)> = self.members.iter().enumerate()
+            .filter_map(|(i, member)| member.as_ref().map(|member| (i, member)))
+            .map(|(i, member)| {
+                let score = self.router.get_stats(&member.name).map(|stats| stats.score()).unwrap_or(100);
+                (i, 1.0 / score.max(1) as f64)
+            })
+            .collect();
+        let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+        let mut pick = rand::thread_rng().gen::<f64>() * total;
+        let mut chosen = weights.last().map(|&(i, _)| i).unwrap_or(0);
+        for &(i, weight) in &weights {
+            if pick < weight {
+                chosen = i;
+                break;
+            }
+            pick -= weight;
+        }
+        if let Some(Some(member)) = self.members.get(chosen) {
+            // A member that just died reports it; its records are resent then
+            let _ = member.tx.send(packet);
+        }
+        chosen
+    }
+
+    fn send_record(&mut self, data: Option<Bytes>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let payload = data.as_deref().unwrap_or_default();
+        let mut packet = BytesMut::with_capacity(1 + 8 + payload.len());
+        packet.put_u8(if data.is_some() { DATA } else { FIN });
+        packet.put_u64(seq);
+        packet.put_slice(payload);
+        let packet = packet.freeze();
+
+        let member = self.send_packet(packet.clone());
+        self.unacked.push_back((seq, member, packet));
+    }
+
+    fn send_ack(&mut self) {
+        let packet = self.ack_packet();
+        self.send_packet(packet);
+    }
+
+    fn ack_packet(&mut self) -> Bytes {
+        self.acked_to_peer = self.delivered;
+        let mut packet = BytesMut::with_capacity(1 + 8);
+        packet.put_u8(ACK);
+        packet.put_u64(self.delivered);
+        packet.freeze()
+    }
+
+    fn mark_delivered(&mut self, fin: bool) {
+        self.delivered += 1;
+        if fin {
+            self.fin_received = true;
+        }
+        if self.delivered - self.acked_to_peer >= (WINDOW / 4) as u64 {
+            self.send_ack();
+        }
+    }
+
+    fn discard_ready(&mut self) {
+        while let Some(record) = self.ready.pop_front() {
+            self.mark_delivered(record.is_none());
+        }
+    }
+
+    fn handle_packet(&mut self, mut packet: Bytes, discard: bool) -> Result<()> {
+        if packet.len() < 1 + 8 {
+            return Err(anyhow!("Short bond packet"));
+        }
+        let kind = packet.get_u8();
+        let seq = packet.get_u64();
+        match kind {
+            ACK => {
+                while self.unacked.front().is_some_and(|&(acked, _, _)| acked < seq) {
+                    self.unacked.pop_front();
+                }
+                return Ok(());
+            }
+            DATA if seq >= self.next_expected => {
+                self.reorder.insert(seq, Some(packet));
+            }
+            FIN if seq >= self.next_expected => {
+                self.reorder.insert(seq, None);
+            }
+            // Resent after we already had it
+            DATA | FIN => {}
+            _ => return Err(anyhow!("Unknown bond packet type {}", kind)),
+        }
+
+        while let Some(record) = self.reorder.remove(&self.next_expected) {
+            self.next_expected += 1;
+            self.ready.push_back(record);
+        }
+        if discard {
+            self.discard_ready();
+        }
+        Ok(())
+    }
+}
+
+/// Moves packets between one member connection and the driver. Sending
+/// and receiving run on separate halves, so a member blocked sending to a
+/// peer that is itself blocked sending still reads what the peer has.
+async fn run_member(
+    index: usize,
+    connection: Box<dyn Connection>,
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let (mut recv, mut send) = connection.split();
+    let sending = async {
+        while let Some(packet) = rx.recv().await {
+            send.send(packet).await?;
+        }
+        // Bond finished
+        let _ = send.close().await;
+        Ok(())
+    };
+    let receiving = async {
+        while let Some(packet) = recv.recv().await? {
+            let _ = events.send(Event::Packet(index, packet));
+        }
+        Err(anyhow!("closed by peer"))
+    };
+    let result = tokio::select! {
+        res = sending => res,
+        res = receiving => res,
+    };
+    if let Err(e) = result {
+        let _ = events.send(Event::Dead(index, e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::EncryptedConnection;
+    use chimera_transport::fault::{FaultPlan, FaultTransport};
+    use chimera_transport::memory::MemoryTransport;
+    use chimera_transport::{Endpoint, Transport};
+
+    /// Both ends of a secured tunnel over `transport`: (client, server).
+    async fn member(transport: &dyn Transport, name: &str) -> (Box<dyn Connection>, Box<dyn Connection>) {
+        let endpoint = Endpoint::Memory(name.to_string());
+        let mut listener = transport.listen(&endpoint).await.unwrap();
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            EncryptedConnection::new(conn, true, None).await.unwrap()
+        });
+        let conn = transport.connect(&endpoint).await.unwrap();
+        let client = EncryptedConnection::new(conn, false, None).await.unwrap();
+        (Box::new(client), Box::new(server.await.unwrap()))
+    }
+
+    #[tokio::test]
+    async fn losing_a_member_mid_transfer_keeps_the_order() {
+        let steady = member(&MemoryTransport::new(), "bond-steady").await;
+        // Resets once 64 KiB have crossed it, well into the transfer
+        let doomed = FaultTransport::new(MemoryTransport::new(), FaultPlan::new().with_reset_after(64 * 1024));
+        let doomed = member(&doomed, "bond-doomed").await;
+
+        let router = Arc::new(Router::new());
+        router.register_path("steady");
+        router.register_path("doomed");
+        let mut client = BondedConnection::connect(
+            vec![("steady".to_string(), steady.0), ("doomed".to_string(), doomed.0)],
+            router.clone(),
+        ).await.unwrap();
+
+        let registry = BondRegistry::new(Arc::new(Router::new()));
+        let mut server = None;
+        for (name, mut conn) in [("steady", steady.1), ("doomed", doomed.1)] {
+            let hello = conn.recv().await.unwrap().unwrap();
+            assert!(is_hello(&hello));
+            server = server.or(registry.join(name, conn, &hello));
+        }
+        let mut server = server.expect("the first member starts the bond");
+
+        const RECORDS: u32 = 2000;
+        let sending = tokio::spawn(async move {
+            for i in 0..RECORDS {
+                let mut record = BytesMut::from(&i.to_be_bytes()[..]);
+                record.resize(1000, i as u8);
+                client.send(record.freeze()).await.unwrap();
+            }
+            client.close().await.unwrap();
+            client
+        });
+        for i in 0..RECORDS {
+            let record = server.recv().await.unwrap().expect("bond ended early");
+            assert_eq!(&record[..4], &i.to_be_bytes(), "record out of order");
+            assert_eq!(record.len(), 1000);
+        }
+        assert!(server.recv().await.unwrap().is_none());
+        server.close().await.unwrap();
+        drop(sending.await.unwrap());
+
+        assert_eq!(router.get_stats("doomed").unwrap().packet_loss, 1.0, "doomed member survived");
+        assert_eq!(router.get_stats("steady").unwrap().packet_loss, 0.0);
+    }
+}
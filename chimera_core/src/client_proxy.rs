@@ -6,8 +6,9 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use anyhow::Result;
 use bytes::Bytes;
-use tracing::{info, warn};
+use tracing::info;
 use crate::protocol::{Frame, FrameType};
+use chimera_transport::Connection;
 
 /// Manages SOCKS connections on the client side
 pub struct ClientProxy {
@@ -121,3 +122,27 @@ impl ClientProxy {
         });
     }
 }
+
+/// Bridges a SOCKS connection to one that already leads to its target
+/// (e.g. through a Shadowsocks server), bypassing the tunnel.
+pub async fn relay(mut socket: TcpStream, mut conn: Box<dyn Connection>) -> Result<()> {
+    let (mut rd, mut wr) = socket.split();
+    let mut buf = vec![0u8; 16384];
+    let mut socket_open = true;
+    loop {
+        tokio::select! {
+            res = rd.read(&mut buf), if socket_open => match res? {
+                0 => {
+                    socket_open = false;
+                    conn.close().await?;
+                }
+                n => conn.send(Bytes::copy_from_slice(&buf[..n])).await?,
+            },
+            res = conn.recv() => match res? {
+                Some(data) => wr.write_all(&data).await?,
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
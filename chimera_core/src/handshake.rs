@@ -1,19 +1,43 @@
-use chimera_transport::Connection;
+use chimera_transport::{Connection, RecvHalf, SendHalf};
 use chimera_crypto::{ChimeraCrypto, Cipher};
 use anyhow::{Result, anyhow};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use tracing::info;
 use async_trait::async_trait;
 
 use crate::mimic::Mimic;
 
+/// Length of an X25519 public key, the whole of an unmimicked handshake.
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// Most a peer may send before its handshake message is complete.
+const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+
+/// Reads the peer's handshake message, which a byte stream may deliver
+/// in pieces (e.g. when the sender split it against DPI), or together
+/// with the first records. Returns the message and whatever followed it.
+async fn recv_handshake(inner: &mut Box<dyn Connection>, mimic: Option<&dyn Mimic>) -> Result<(Vec<u8>, BytesMut)> {
+    let mut received = BytesMut::new();
+    loop {
+        let data = inner.recv().await?.ok_or_else(|| anyhow!("Connection closed during handshake"))?;
+        received.extend_from_slice(&data);
+        let payload = match mimic {
+            Some(m) => m.decapsulate(&received)?,
+            None => (received.len() >= PUBLIC_KEY_LEN).then(|| (received[..PUBLIC_KEY_LEN].to_vec(), PUBLIC_KEY_LEN)),
+        };
+        if let Some((payload, consumed)) = payload {
+            return Ok((payload, received.split_off(consumed)));
+        }
+        if received.len() > MAX_HANDSHAKE_LEN {
+            return Err(anyhow!("Handshake message too long"));
+        }
+    }
+}
+
 pub struct EncryptedConnection {
     inner: Box<dyn Connection>,
-    cipher_in: Cipher,
-    cipher_out: Cipher,
-    seq_in: u64,
-    seq_out: u64,
-    buffer: bytes::BytesMut,
+    sealer: Sealer,
+    opener: Opener,
 }
 
 impl EncryptedConnection {
@@ -21,15 +45,9 @@ impl EncryptedConnection {
         // 1. Generate ephemeral keypair
         let (my_private, my_public) = ChimeraCrypto::generate_ephemeral_key()?;
         
-        let peer_public = if is_server {
+        let (peer_public, leftover) = if is_server {
             // Server waits for client's public key (possibly masqueraded)
-            let received_data = inner.recv().await?.ok_or_else(|| anyhow!("Connection closed during handshake"))?;
-            
-            let peer_pub = if let Some(ref m) = mimic {
-                m.decapsulate(&received_data)?.ok_or_else(|| anyhow!("Mimic decapsulation failed"))?
-            } else {
-                received_data.to_vec()
-            };
+            let peer = recv_handshake(&mut inner, mimic.as_deref()).await?;
 
             // Send own public key
             let my_data = if let Some(ref m) = mimic {
@@ -39,7 +57,7 @@ impl EncryptedConnection {
             };
             inner.send(my_data).await?;
             
-            peer_pub
+            peer
         } else {
             // Client sends public key first
             let my_data = if let Some(ref m) = mimic {
@@ -50,13 +68,7 @@ impl EncryptedConnection {
             inner.send(my_data).await?;
 
             // Wait for server's public key
-            let received_data = inner.recv().await?.ok_or_else(|| anyhow!("Connection closed during handshake"))?;
-            
-            if let Some(ref m) = mimic {
-                 m.decapsulate(&received_data)?.ok_or_else(|| anyhow!("Mimic decapsulation failed"))?
-            } else {
-                received_data.to_vec()
-            }
+            recv_handshake(&mut inner, mimic.as_deref()).await?
         };
 
         // 2. Derive shared secret
@@ -68,64 +80,111 @@ impl EncryptedConnection {
 
         Ok(Self {
             inner,
-            cipher_in,
-            cipher_out,
-            seq_in: 0,
-            seq_out: 0,
-            buffer: bytes::BytesMut::with_capacity(4096),
+            sealer: Sealer { cipher: cipher_out, seq: 0 },
+            // Records the peer sent right behind its handshake
+            opener: Opener { cipher: cipher_in, seq: 0, buffer: leftover },
         })
     }
 
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let framed = self.sealer.seal(data)?;
+        self.inner.send(framed).await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<Bytes>> {
+        self.opener.recv(&mut self.inner).await
+    }
+}
+
+/// Encrypts and frames outgoing records.
+struct Sealer {
+    cipher: Cipher,
+    seq: u64,
+}
+
+impl Sealer {
+    fn seal(&mut self, data: &[u8]) -> Result<Bytes> {
         let mut encrypted = data.to_vec();
-        self.cipher_out.encrypt(self.seq_out, &mut encrypted)?;
-        self.seq_out += 1;
+        self.cipher.encrypt(self.seq, &mut encrypted)?;
+        self.seq += 1;
         
         // Framing: [Length: u32][Encrypted Data]
         let len = encrypted.len() as u32;
-        let mut framed = bytes::BytesMut::with_capacity(4 + encrypted.len());
+        let mut framed = BytesMut::with_capacity(4 + encrypted.len());
         use bytes::BufMut;
         framed.put_u32(len);
         framed.put_slice(&encrypted);
-        
-        self.inner.send(framed.freeze()).await?;
-        Ok(())
+        Ok(framed.freeze())
     }
 
-    pub async fn recv(&mut self) -> Result<Option<Bytes>> {
+    fn seal_all(&mut self, data: &[Bytes]) -> Result<Vec<Bytes>> {
+        data.iter().map(|chunk| self.seal(chunk)).collect()
+    }
+}
+
+/// What an `Opener` reads records from: the whole connection, or its
+/// receiving half once split.
+#[async_trait]
+trait Source: Send {
+    async fn recv(&mut self) -> Result<Option<Bytes>>;
+}
+
+#[async_trait]
+impl Source for Box<dyn Connection> {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        (**self).recv().await
+    }
+}
+
+#[async_trait]
+impl Source for Box<dyn RecvHalf> {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        (**self).recv().await
+    }
+}
+
+/// Collects incoming bytes and decrypts the records they complete.
+struct Opener {
+    cipher: Cipher,
+    seq: u64,
+    buffer: BytesMut,
+}
+
+impl Opener {
+    /// The next record, once the buffer holds all of it.
+    fn open(&mut self) -> Result<Option<Bytes>> {
         use bytes::Buf;
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+        let mut cursor = std::io::Cursor::new(&self.buffer[..]);
+        let len = cursor.get_u32() as usize;
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+
+        // Full packet available
+        self.buffer.advance(4); // Consume len
+        let mut encrypted_chunk = self.buffer.split_to(len).to_vec();
+
+        let decrypted_len = self.cipher.decrypt(self.seq, &mut encrypted_chunk)?;
+        encrypted_chunk.truncate(decrypted_len);
+        self.seq += 1;
+
+        Ok(Some(Bytes::from(encrypted_chunk)))
+    }
+
+    /// The next record, reading from `source` until one is complete.
+    async fn recv(&mut self, source: &mut impl Source) -> Result<Option<Bytes>> {
         loop {
-            // 1. Try to parse a frame from current buffer
-            if self.buffer.len() >= 4 {
-                let mut cursor = std::io::Cursor::new(&self.buffer[..]);
-                let len = cursor.get_u32() as usize;
-                
-                if self.buffer.len() >= 4 + len {
-                    // Full packet available
-                    self.buffer.advance(4); // Consume len
-                    let mut encrypted_chunk = self.buffer.split_to(len).to_vec();
-                    
-                    let decrypted_len = self.cipher_in.decrypt(self.seq_in, &mut encrypted_chunk)?;
-                    encrypted_chunk.truncate(decrypted_len);
-                    self.seq_in += 1;
-                    
-                    return Ok(Some(Bytes::from(encrypted_chunk)));
-                }
+            if let Some(record) = self.open()? {
+                return Ok(Some(record));
             }
-            
-            // 2. Need more data
-            let data = self.inner.recv().await?;
-            match data {
-                Some(chunk) => {
-                    self.buffer.extend_from_slice(&chunk);
-                }
-                None => {
-                    if self.buffer.is_empty() {
-                        return Ok(None); // Clean EOF
-                    } else {
-                        return Err(anyhow!("Connection closed with partial data"));
-                    }
-                }
+            match source.recv().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None if self.buffer.is_empty() => return Ok(None), // Clean EOF
+                None => return Err(anyhow!("Connection closed with partial data")),
             }
         }
     }
@@ -137,6 +196,12 @@ impl Connection for EncryptedConnection {
         self.send(&data).await
     }
 
+    /// Each chunk is a record of its own; the records go down in one batch.
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        let records = self.sealer.seal_all(data)?;
+        self.inner.send_vectored(&records).await
+    }
+
     async fn recv(&mut self) -> Result<Option<Bytes>> {
         self.recv().await
     }
@@ -144,4 +209,119 @@ impl Connection for EncryptedConnection {
     async fn close(&mut self) -> Result<()> {
         self.inner.close().await
     }
+
+    fn split(self: Box<Self>) -> (Box<dyn RecvHalf>, Box<dyn SendHalf>) {
+        let (recv, send) = self.inner.split();
+        (
+            Box::new(EncryptedRecvHalf { inner: recv, opener: self.opener }),
+            Box::new(EncryptedSendHalf { inner: send, sealer: self.sealer }),
+        )
+    }
+}
+
+struct EncryptedRecvHalf {
+    inner: Box<dyn RecvHalf>,
+    opener: Opener,
+}
+
+#[async_trait]
+impl RecvHalf for EncryptedRecvHalf {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        self.opener.recv(&mut self.inner).await
+    }
+}
+
+struct EncryptedSendHalf {
+    inner: Box<dyn SendHalf>,
+    sealer: Sealer,
+}
+
+#[async_trait]
+impl SendHalf for EncryptedSendHalf {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let framed = self.sealer.seal(&data)?;
+        self.inner.send(framed).await
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        let records = self.sealer.seal_all(data)?;
+        self.inner.send_vectored(&records).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mimic::HttpMimic;
+    use chimera_transport::memory::MemoryTransport;
+    use chimera_transport::{Endpoint, Transport};
+
+    fn mimic(enabled: bool) -> Option<Box<dyn Mimic>> {
+        enabled.then(|| Box::new(HttpMimic) as Box<dyn Mimic>)
+    }
+
+    /// Runs the handshake on both ends of an in-process pipe.
+    async fn pair(transport: MemoryTransport, name: &str, mimicked: bool) -> (EncryptedConnection, EncryptedConnection) {
+        let endpoint = Endpoint::Memory(name.to_string());
+        let mut listener = transport.listen(&endpoint).await.unwrap();
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            EncryptedConnection::new(conn, true, mimic(mimicked)).await.unwrap()
+        });
+        let conn = transport.connect(&endpoint).await.unwrap();
+        let client = EncryptedConnection::new(conn, false, mimic(mimicked)).await.unwrap();
+        (client, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn http_mimic_survives_one_byte_reads() {
+        let transport = MemoryTransport::new().with_chunk_size(1).with_buffer_size(16);
+        let (mut client, mut server) = pair(transport, "handshake-one-byte", true).await;
+
+        // Far more than the pipe holds, so both ends have to take turns
+        let upstream: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let (sent, received) = tokio::join!(client.send(&upstream), server.recv());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap(), upstream);
+
+        let (sent, received) = tokio::join!(server.send(b"pong"), client.recv());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap(), &b"pong"[..]);
+    }
+
+    #[tokio::test]
+    async fn records_right_behind_the_handshake_are_kept() {
+        for mimicked in [false, true] {
+            let endpoint = Endpoint::Memory(format!("handshake-coalesced-{}", mimicked));
+            let transport = MemoryTransport::new();
+            let mut listener = transport.listen(&endpoint).await.unwrap();
+            let client = tokio::spawn({
+                let conn = transport.connect(&endpoint).await.unwrap();
+                async move { EncryptedConnection::new(conn, false, mimic(mimicked)).await.unwrap() }
+            });
+
+            // A server that sends its key and first records in one write
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let (peer_public, _) = recv_handshake(&mut conn, mimic(mimicked).as_deref()).await.unwrap();
+            let (private, public) = ChimeraCrypto::generate_ephemeral_key().unwrap();
+            let secret = ChimeraCrypto::derive_secret(private, &peer_public).unwrap();
+            let mut sealer = Sealer { cipher: Cipher::new(&secret).unwrap(), seq: 0 };
+            let mut flight = BytesMut::new();
+            match mimic(mimicked) {
+                Some(m) => flight.extend_from_slice(&m.encapsulate(&public, true).unwrap()),
+                None => flight.extend_from_slice(&public),
+            }
+            flight.extend_from_slice(&sealer.seal(b"first").unwrap());
+            flight.extend_from_slice(&sealer.seal(b"second").unwrap());
+            conn.send(flight.freeze()).await.unwrap();
+
+            let mut client = client.await.unwrap();
+            assert_eq!(client.recv().await.unwrap().unwrap(), &b"first"[..]);
+            assert_eq!(client.recv().await.unwrap().unwrap(), &b"second"[..]);
+        }
+    }
 }
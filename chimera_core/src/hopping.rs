@@ -0,0 +1,55 @@
+//! Keeps a tunnel on the move between ports: on every hop of a
+//! `HopSchedule` a fresh connection to the new port takes over the bond,
+//! with the streams running over it.
+
+use chimera_transport::hopping::HopSchedule;
+use chimera_transport::{Endpoint, Transport};
+use chimera_ai::Router;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::bonding::BondHandle;
+use crate::racing;
+
+/// Grace after an interval starts before hopping, so a server whose clock
+/// is a little behind has its listener up.
+const HOP_DELAY: Duration = Duration::from_millis(500);
+
+/// Moves the bond behind `handle` to the port of each new interval, over
+/// `transport` (a `HoppingTransport` of the Router path `name`), until the
+/// bond ends.
+///
+/// A failed hop leaves the bond where it is and is reported as such: the
+/// path still works, only that port may be blocked.
+pub async fn run(
+    handle: BondHandle,
+    name: String,
+    transport: Arc<dyn Transport>,
+    target: Endpoint,
+    schedule: HopSchedule,
+    router: Arc<Router>,
+) {
+    loop {
+        tokio::time::sleep(schedule.until_next() + HOP_DELAY).await;
+        if handle.is_closed() {
+            return;
+        }
+        let port = schedule.port(schedule.epoch());
+        let started = Instant::now();
+        match racing::dial(Box::new(transport.clone()), target.clone()).await {
+            Ok(conn) => {
+                if handle.move_to(&name, Box::new(conn)).await.is_err() {
+                    return;
+                }
+                info!("Hopped {} to port {}", name, port);
+                router.update_latency(&name, started.elapsed());
+            }
+            Err(e) => {
+                warn!("Hop of {} to port {} failed: {}", name, port, e);
+                router.report_hop_failure(&name);
+            }
+        }
+    }
+}
@@ -1,52 +1,115 @@
-use chimera_transport::{Transport, Listener, Connection};
+use chimera_transport::{Transport, Connection, Endpoint};
+use chimera_transport::shadowsocks::ShadowsocksServer;
+use chimera_transport::punch::RendezvousServer;
 use anyhow::Result;
-use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::mpsc;
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
 use chimera_ai::Router;
 
 /// The main Chimera node.
-/// It can listen on multiple transports simultaneously.
+/// It can listen on multiple transports simultaneously, each on endpoints
+/// of its own.
 pub struct ChimeraNode {
-    transports: Vec<Box<dyn Transport>>,
+    // Transports with the endpoints they listen on; none means the
+    // address given to `run_server`
+    transports: Vec<(Box<dyn Transport>, Vec<Endpoint>)>,
+    // Shadowsocks inbounds, served without a tunnel
+    shadowsocks: Vec<(ShadowsocksServer, Vec<Endpoint>)>,
+    // Where to be the rendezvous for nodes behind NAT, and under which key
+    rendezvous: Vec<(Vec<u8>, Vec<Endpoint>)>,
     router: Arc<Router>,
+    bonds: Arc<bonding::BondRegistry>,
 }
 
 impl ChimeraNode {
     pub fn new() -> Self {
+        let router = Arc::new(Router::new());
         Self {
             transports: Vec::new(),
-            router: Arc::new(Router::new()),
+            shadowsocks: Vec::new(),
+            rendezvous: Vec::new(),
+            bonds: Arc::new(bonding::BondRegistry::new(router.clone())),
+            router,
         }
     }
 
+    /// Adds a transport that listens on the address given to `run_server`.
     pub fn add_transport(&mut self, transport: Box<dyn Transport>) {
+        self.add_transport_on(transport, Vec::new());
+    }
+
+    /// Adds a transport that listens on `endpoints` (e.g. several ports, or
+    /// `[::]` for IPv4 and IPv6 alike).
+    pub fn add_transport_on(&mut self, transport: Box<dyn Transport>, endpoints: Vec<Endpoint>) {
         info!("Adding transport: {}", transport.name());
         self.router.register_path(transport.name());
-        self.transports.push(transport);
+        self.transports.push((transport, endpoints));
     }
 
-    pub async fn run_server(&self, bind_addr: SocketAddr) -> Result<()> {
-        info!("Starting Chimera Server on {}", bind_addr);
+    /// Also accepts Shadowsocks 2022 clients on `endpoints`; the destination
+    /// each asks for is connected to as if it had come through a tunnel.
+    pub fn add_shadowsocks(&mut self, server: ShadowsocksServer, endpoints: Vec<Endpoint>) {
+        info!("Adding Shadowsocks inbound ({})", server.config());
+        self.shadowsocks.push((server, endpoints));
+    }
+
+    /// Also runs a rendezvous on `endpoints` (UDP): nodes behind NAT register
+    /// there, and the peers dialling them are put in touch to punch through,
+    /// or relayed when that fails. Nodes and peers need `key` to use it.
+    pub fn add_rendezvous(&mut self, key: &[u8], endpoints: Vec<Endpoint>) {
+        info!("Adding rendezvous");
+        self.rendezvous.push((key.to_vec(), endpoints));
+    }
+
+    /// Shares bonds with other nodes, so members arriving on different
+    /// ports (e.g. TCP and KCP) end up in the same bond.
+    pub fn use_bond_registry(&mut self, bonds: Arc<bonding::BondRegistry>) {
+        self.bonds = bonds;
+    }
+
+    /// Listens on every transport's endpoints (`bind_addr` for those added
+    /// without any) and serves the tunnels that come in. Endpoints that
+    /// cannot be bound are reported and skipped; it fails only if none can.
+    pub async fn run_server(&self, bind_addr: Endpoint) -> Result<()> {
+        info!("Starting Chimera Server");
         
-        let (tx, mut rx) = mpsc::channel::<Box<dyn Connection>>(100);
+        // Each connection carries its transport's name and whether it wants the handshake mimicked
+        let (tx, mut rx) = mpsc::channel::<(Box<dyn Connection>, String, bool)>(100);
 
-        // Start listeners for each transport
-        for transport in &self.transports {
+        // Start listeners for each transport on each of its endpoints
+        let mut bound = 0;
+        let mut failed = Vec::new();
+        let bindings = self.transports.iter().flat_map(|(transport, endpoints)| {
+            let endpoints = match endpoints.is_empty() {
+                true => std::slice::from_ref(&bind_addr),
+                false => endpoints.as_slice(),
+            };
+            endpoints.iter().map(move |endpoint| (transport, endpoint))
+        }).collect::<Vec<_>>();
+        for (transport, endpoint) in bindings {
             let transport_name = transport.name().to_string();
-            let mut listener = transport.listen(bind_addr).await?;
+            let wants_mimic = transport.wants_mimic();
+            let mut listener = match transport.listen(endpoint).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Transport {} could not listen on {}: {}", transport_name, endpoint, e);
+                    failed.push(format!("{} on {}", transport_name, endpoint));
+                    continue;
+                }
+            };
             let tx = tx.clone();
+            bound += 1;
             
-            info!("Transport {} listening on {}", transport_name, bind_addr);
+            info!("Transport {} listening on {}", transport_name, endpoint);
 
             tokio::spawn(async move {
                 loop {
                     match listener.accept().await {
                         Ok((connection, remote_addr)) => {
                             info!("[{}] New connection from {}", transport_name, remote_addr);
-                            if let Err(e) = tx.send(connection).await {
+                            if let Err(e) = tx.send((connection, transport_name.clone(), wants_mimic)).await {
                                 error!("Failed to send connection to main loop: {}", e);
                                 break;
                             }
@@ -59,25 +122,81 @@ impl ChimeraNode {
             });
         }
 
+        for (server, endpoint) in self.shadowsocks.iter().flat_map(|(server, endpoints)| endpoints.iter().map(move |endpoint| (server, endpoint))) {
+            let mut listener = match server.listen(endpoint).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Shadowsocks could not listen on {}: {}", endpoint, e);
+                    failed.push(format!("Shadowsocks on {}", endpoint));
+                    continue;
+                }
+            };
+            bound += 1;
+            info!("Shadowsocks listening on {}", endpoint);
+
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((connection, remote_addr, target)) => {
+                            info!("[Shadowsocks] {} connects to {}", remote_addr, target);
+                            tokio::spawn(async move {
+                                if let Err(e) = serve_shadowsocks(connection, target).await {
+                                    error!("Shadowsocks connection error: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("[Shadowsocks] Accept error: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        let rendezvous = self.rendezvous.iter()
+            .flat_map(|(key, endpoints)| endpoints.iter().map(move |endpoint| (key, endpoint)));
+        for (key, endpoint) in rendezvous {
+            match RendezvousServer::bind(endpoint, key).await {
+                Ok(server) => {
+                    bound += 1;
+                    info!("Rendezvous listening on {}", endpoint);
+                    tokio::spawn(server.run());
+                }
+                Err(e) => {
+                    error!("Rendezvous could not listen on {}: {}", endpoint, e);
+                    failed.push(format!("Rendezvous on {}", endpoint));
+                }
+            }
+        }
+
+        if bound == 0 {
+            return Err(anyhow::anyhow!("No transport could listen ({} failed)", failed.len()));
+        }
+        match failed.is_empty() {
+            true => info!("All {} bindings up", bound),
+            false => warn!("{} bindings up, {} failed: {}", bound, failed.len(), failed.join(", ")),
+        }
+
         // Main connection handler loop
-        while let Some(raw_connection) = rx.recv().await {
+        while let Some((raw_connection, transport_name, wants_mimic)) = rx.recv().await {
             let router = self.router.clone();
+            let bonds = self.bonds.clone();
             tokio::spawn(async move {
                 // Heuristic Check: Log the best path
                 if let Some(best) = router.get_best_path() {
                      info!("AI Logic: Best path for new connection is {}", best);
                 }
 
-                // Use HttpMimic for now
-                let mimic = Some(Box::new(mimic::HttpMimic) as Box<dyn mimic::Mimic>);
+                // Use HttpMimic for now, unless the transport already looks like HTTP
+                let mimic = wants_mimic.then(|| Box::new(mimic::HttpMimic) as Box<dyn mimic::Mimic>);
                 
                 // Add 5 second timeout for handshake
                 let handshake_future = handshake::EncryptedConnection::new(raw_connection, true, mimic);
                 match tokio::time::timeout(std::time::Duration::from_secs(5), handshake_future).await {
                     Ok(result) => match result {
-                        Ok(mut conn) => {
+                        Ok(conn) => {
                         info!("Handshake successful. Connection secured.");
-                        if let Err(e) = handle_connection(&mut conn).await {
+                        if let Err(e) = serve_tunnel(conn, &transport_name, &bonds).await {
                             error!("Connection error: {}", e);
                         }
                     }
@@ -97,57 +216,124 @@ impl ChimeraNode {
     }
 }
 
+impl Default for ChimeraNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub mod handshake;
 pub mod mimic;
 pub mod protocol;
 pub mod socks;
+pub mod bonding;
+pub mod racing;
+pub mod hopping;
 pub mod server_proxy;
 pub mod client_proxy;
 pub mod system;
 
 use crate::server_proxy::ServerProxy;
-use crate::protocol::Frame;
-use bytes::BytesMut;
+use crate::protocol::{Frame, FrameType};
+use bytes::{Bytes, BytesMut};
+
+/// Serves a secured tunnel, unless it turns out to be a bond member:
+/// those announce themselves in their first record.
+async fn serve_tunnel(mut conn: handshake::EncryptedConnection, transport_name: &str, bonds: &bonding::BondRegistry) -> Result<()> {
+    let Some(first) = conn.recv().await? else {
+        return Ok(());
+    };
+    if !bonding::is_hello(&first) {
+        return handle_connection(Box::new(conn), first).await;
+    }
+    match bonds.join(transport_name, Box::new(conn), &first) {
+        Some(bond) => handle_connection(Box::new(bond), Bytes::new()).await,
+        // Joined a bond that is already being served
+        None => Ok(()),
+    }
+}
 
-async fn handle_connection(conn: &mut dyn Connection) -> Result<()> {
+/// Serves one Shadowsocks client: its destination goes to a `ServerProxy`
+/// as the only stream of a tunnel would.
+async fn serve_shadowsocks(mut conn: Box<dyn Connection>, target: String) -> Result<()> {
+    const STREAM_ID: u32 = 1;
+    let (tx, mut rx) = mpsc::channel::<Frame>(10000);
+    let proxy = ServerProxy::new(tx);
+    proxy.handle_frame(Frame::new(FrameType::Connect, STREAM_ID, Bytes::from(target))).await?;
+
+    let mut client_open = true;
+    loop {
+        tokio::select! {
+            res = conn.recv(), if client_open => match res? {
+                Some(data) => proxy.handle_frame(Frame::new(FrameType::Data, STREAM_ID, data)).await?,
+                None => {
+                    client_open = false;
+                    proxy.handle_frame(Frame::new(FrameType::Disconnect, STREAM_ID, Bytes::new())).await?;
+                }
+            },
+            frame = rx.recv() => match frame {
+                Some(frame) if frame.frame_type == FrameType::Data => conn.send(frame.payload).await?,
+                // The destination closed, or could not be reached
+                _ => break,
+            },
+        }
+    }
+    conn.close().await
+}
+
+/// Most frames a tunnel sends in one write.
+const SEND_BATCH: usize = 64;
+
+/// Serves the streams of one tunnel. Frames for the client go out while
+/// the next record is still being read, so neither direction waits on the
+/// other.
+async fn handle_connection(conn: Box<dyn Connection>, initial: Bytes) -> Result<()> {
     // Increased buffer to 10000 to prevent backpressure
     let (tx, mut rx) = mpsc::channel::<Frame>(10000);
     let proxy = Arc::new(ServerProxy::new(tx));
-    
+    let (mut reader, mut writer) = conn.split();
+
     let mut buf = BytesMut::with_capacity(4096);
-    
+    buf.extend_from_slice(&initial);
+    dispatch_frames(&mut buf, &proxy).await?;
+
+    // 1. Read from Tunnel
+    let receiving = async {
+        while let Some(data) = reader.recv().await? {
+            buf.extend_from_slice(&data);
+            dispatch_frames(&mut buf, &proxy).await?;
+        }
+        Ok(()) // EOF
+    };
+
+    // 2. Write to Tunnel (from Proxy), whatever is queued in one go
+    let sending = async {
+        let mut frames = Vec::with_capacity(SEND_BATCH);
+        while rx.recv_many(&mut frames, SEND_BATCH).await > 0 {
+            let batch: Vec<_> = frames.drain(..).map(|frame| frame.to_bytes()).collect();
+            writer.send_vectored(&batch).await?;
+        }
+        Ok(())
+    };
+
+    tokio::select! {
+        res = receiving => res,
+        res = sending => res,
+    }
+}
+
+/// Hands every complete frame in `buf` to the proxy.
+async fn dispatch_frames(buf: &mut BytesMut, proxy: &ServerProxy) -> Result<()> {
     loop {
-        tokio::select! {
-             // 1. Read from Tunnel
-            res = conn.recv() => {
-                match res? {
-                    Some(data) => {
-                        buf.extend_from_slice(&data);
-                        // Parse Frames
-                        loop {
-                            let mut cursor = std::io::Cursor::new(&buf[..]);
-                            match Frame::check(&mut cursor)? {
-                                Some(len) => {
-                                    let frame_bytes = buf.split_to(len).freeze();
-                                    // Removed unnecessary clone/cursor
-                                    let frame = Frame::parse(&mut bytes::Bytes::from(frame_bytes))?;
-                                    proxy.handle_frame(frame).await?;
-                                }
-                                None => break, // Need more data
-                            }
-                        }
-                    }
-                    None => break, // EOF
-                }
-            }
-            
-             // 2. Write to Tunnel (from Proxy)
-            Some(frame) = rx.recv() => {
-                let bytes = frame.to_bytes();
-                conn.send(bytes).await?;
+        let mut cursor = std::io::Cursor::new(&buf[..]);
+        match Frame::check(&mut cursor)? {
+            Some(len) => {
+                let mut frame_bytes = buf.split_to(len).freeze();
+                // Removed unnecessary clone/cursor
+                let frame = Frame::parse(&mut frame_bytes)?;
+                proxy.handle_frame(frame).await?;
             }
+            None => return Ok(()), // Need more data
         }
     }
-    
-    Ok(())
 }
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use bytes::Bytes;
 
 /// Trait for disguising handshake data as other protocols.
@@ -6,9 +6,11 @@ pub trait Mimic: Send + Sync {
     /// Wrap the initial handshake payload (e.g. public key) into a cover protocol.
     fn encapsulate(&self, payload: &[u8], is_server: bool) -> Result<Bytes>;
 
-    /// Extract the handshake payload from a cover protocol packet.
-    /// Returns None if the packet doesn't match the expected format.
-    fn decapsulate(&self, packet: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Extract the handshake payload from the start of `packet`, along with
+    /// how many bytes the cover message took up (anything after it is
+    /// already tunnel data). Returns None if it is not complete yet, and an
+    /// error if it doesn't match the expected format.
+    fn decapsulate(&self, packet: &[u8]) -> Result<Option<(Vec<u8>, usize)>>;
     
     /// Name of the cover protocol (e.g. "HTTP", "TLS")
     fn protocol_name(&self) -> &str;
@@ -40,14 +42,19 @@ impl Mimic for HttpMimic {
         }
     }
 
-    fn decapsulate(&self, packet: &[u8]) -> Result<Option<Vec<u8>>> {
-        let text = String::from_utf8_lossy(packet);
+    fn decapsulate(&self, packet: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        // Wait for the end of the headers; whatever follows is the tunnel's
+        let Some(end) = packet.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let consumed = end + 4;
+        let text = String::from_utf8_lossy(&packet[..consumed]);
         // Simple parser: look for GET /api/v1/resource/
         if let Some(start) = text.find("GET /api/v1/resource/") {
             if let Some(end) = text[start..].find(" HTTP/1.1") {
                 let encoded_part = &text[start + 21..start + end];
                 let decoded = URL_SAFE_NO_PAD.decode(encoded_part)?;
-                return Ok(Some(decoded));
+                return Ok(Some((decoded, consumed)));
             }
         }
         // Also handle Server response: HTTP/1.1 200 OK\r\n...X-Data: <payload>
@@ -56,11 +63,11 @@ impl Mimic for HttpMimic {
              if let Some(end) = text[start..].find("\r\n") {
                  let encoded_part = &text[start + 8..start + end];
                  let decoded = URL_SAFE_NO_PAD.decode(encoded_part)?;
-                 return Ok(Some(decoded));
+                 return Ok(Some((decoded, consumed)));
              }
         }
-        
-        Ok(None)
+
+        Err(anyhow!("Handshake does not match the {} cover", self.protocol_name()))
     }
 
     fn protocol_name(&self) -> &str {
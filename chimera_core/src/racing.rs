@@ -0,0 +1,212 @@
+//! Happy-eyeballs connecting (after RFC 8305): the best few `Router` paths,
+//! each to every address of the server, are raced with staggered starts.
+//!
+//! The first attempt to finish the handshake wins and the rest are
+//! cancelled. A failed attempt lets the next one start right away instead
+//! of waiting out the stagger. Every path that took part is scored: the
+//! winner by its connect time, paths that only failed as failures, and
+//! paths still connecting when the winner finished by how long they had
+//! been at it.
+
+use chimera_transport::{Endpoint, Transport};
+use chimera_ai::Router;
+use anyhow::{Result, anyhow};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::handshake::EncryptedConnection;
+use crate::mimic::{HttpMimic, Mimic};
+
+/// Head start each attempt gets before the next one begins (RFC 8305's
+/// recommended Connection Attempt Delay).
+const STAGGER: Duration = Duration::from_millis(250);
+
+/// How long the handshake may take once the transport is connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects over one path and secures the tunnel.
+pub async fn dial(transport: Box<dyn Transport>, target: Endpoint) -> Result<EncryptedConnection> {
+    let raw_conn = transport.connect(&target).await
+        .map_err(|e| anyhow!("Transport connect failed: {}", e))?;
+
+    // HTTP/2 already looks like web traffic, so it skips the mimic
+    let mimic = transport.wants_mimic()
+        .then(|| Box::new(HttpMimic) as Box<dyn Mimic>);
+
+    let handshake_future = EncryptedConnection::new(raw_conn, false, mimic);
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake_future).await {
+        Ok(Ok(conn)) => Ok(conn),
+        Ok(Err(e)) => Err(anyhow!("Handshake failed: {}", e)),
+        Err(_) => Err(anyhow!("Handshake timed out ({}s)", HANDSHAKE_TIMEOUT.as_secs())),
+    }
+}
+
+/// One path to one address, not started yet.
+struct Attempt {
+    path: String,
+    transport: Box<dyn Transport>,
+    target: Endpoint,
+}
+
+/// How a started attempt ended, if it has.
+struct Started {
+    path: String,
+    at: Instant,
+    failed: bool,
+}
+
+/// Races the client's paths to the server; see the module docs.
+pub struct RacingConnector {
+    router: Arc<Router>,
+    width: usize,
+    stagger: Duration,
+    addresses: Vec<IpAddr>,
+}
+
+impl RacingConnector {
+    pub fn new(router: Arc<Router>) -> Self {
+        Self {
+            router,
+            width: 3,
+            stagger: STAGGER,
+            addresses: Vec::new(),
+        }
+    }
+
+    /// How many of the best paths take part in each race.
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = width.max(1);
+        self
+    }
+
+    pub fn with_stagger(mut self, stagger: Duration) -> Self {
+        self.stagger = stagger;
+        self
+    }
+
+    /// Every address of the server (e.g. its IPv4 and IPv6 ones). A path
+    /// whose endpoint is one of them is tried at each, alternating address
+    /// families.
+    pub fn with_addresses(mut self, addresses: Vec<IpAddr>) -> Self {
+        self.addresses = interleave_families(addresses);
+        self
+    }
+
+    /// Races the best paths, `path` giving the transport and endpoint of
+    /// each, and returns the winner with the name of its path.
+    pub async fn connect<F>(&self, path: F) -> Result<(String, EncryptedConnection)>
+    where
+        F: Fn(&str) -> Result<(Box<dyn Transport>, Endpoint)>,
+    {
+        let ranked: Vec<String> = self.router.ranked_paths().into_iter().take(self.width).collect();
+        let mut attempts = VecDeque::new();
+        for name in &ranked {
+            let (_, endpoint) = path(name)?;
+            for target in self.targets(&endpoint) {
+                let (transport, _) = path(name)?;
+                attempts.push_back(Attempt { path: name.clone(), transport, target });
+            }
+        }
+
+        let mut running = JoinSet::new();
+        let mut started: Vec<Started> = Vec::new();
+        let mut errors = Vec::new();
+        let mut next_start = Instant::now();
+        let winner = loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_start), if !attempts.is_empty() => {
+                    let Some(attempt) = attempts.pop_front() else { continue };
+                    info!("Racing {} to {}", attempt.path, attempt.target);
+                    let id = started.len();
+                    started.push(Started { path: attempt.path, at: Instant::now(), failed: false });
+                    running.spawn(async move { (id, dial(attempt.transport, attempt.target).await) });
+                    next_start = Instant::now() + self.stagger;
+                }
+                Some(joined) = running.join_next() => {
+                    let (id, result) = joined?;
+                    match result {
+                        Ok(conn) => break Some((id, conn)),
+                        Err(e) => {
+                            warn!("{}: {}", started[id].path, e);
+                            errors.push(format!("{}: {}", started[id].path, e));
+                            started[id].failed = true;
+                            next_start = Instant::now();
+                        }
+                    }
+                }
+                else => break None,
+            }
+        };
+        // Cancels the losers still connecting
+        running.abort_all();
+
+        let now = Instant::now();
+        let won_in = winner.as_ref().map(|(id, _)| now - started[*id].at);
+        for name in &ranked {
+            let tried: Vec<&Started> = started.iter().filter(|attempt| &attempt.path == name).collect();
+            if tried.is_empty() {
+                continue;
+            }
+            if let (Some((id, _)), Some(won_in)) = (&winner, won_in) {
+                if &started[*id].path == name {
+                    self.router.update_latency(name, won_in);
+                    continue;
+                }
+            }
+            if tried.iter().all(|attempt| attempt.failed) {
+                self.router.report_failure(name);
+                continue;
+            }
+            // Still connecting: at least as slow as it has been going, which
+            // only says something if that is longer than the winner took
+            let pending = tried.iter()
+                .filter(|attempt| !attempt.failed)
+                .map(|attempt| now - attempt.at)
+                .max()
+                .unwrap_or_default();
+            if won_in.is_some_and(|won_in| pending >= won_in) {
+                self.router.update_latency(name, pending);
+            }
+        }
+
+        match winner {
+            Some((id, conn)) => Ok((started[id].path.clone(), conn)),
+            None if errors.is_empty() => Err(anyhow!("No paths to race")),
+            None => Err(anyhow!("Every path failed ({})", errors.join("; "))),
+        }
+    }
+
+    /// The endpoints to try a path at: each server address when it targets
+    /// one of them, otherwise just its own.
+    fn targets(&self, endpoint: &Endpoint) -> Vec<Endpoint> {
+        match endpoint {
+            Endpoint::Inet(addr) if self.addresses.contains(&addr.ip()) => self.addresses.iter()
+                .map(|ip| SocketAddr::new(*ip, addr.port()).into())
+                .collect(),
+            _ => vec![endpoint.clone()],
+        }
+    }
+}
+
+/// Reorders `addresses` so the families alternate, starting with the
+/// family of the first one (which the resolver preferred).
+fn interleave_families(addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+    let Some(first) = addresses.first() else {
+        return addresses;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (VecDeque<IpAddr>, VecDeque<IpAddr>) = addresses.iter()
+        .partition(|ip| ip.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(addresses.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -4,11 +4,10 @@ use tokio::sync::mpsc;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use bytes::Bytes;
-use tracing::{info, error, warn};
+use tracing::{info, warn};
 use crate::protocol::{Frame, FrameType};
-use crate::Connection;
 
 /// Manages multiple outgoing TCP connections multiplexed over a single transport
 pub struct ServerProxy {
@@ -34,19 +33,16 @@ impl ServerProxy {
                 let tunnel_tx = self.tunnel_tx.clone();
                 let streams = self.streams.clone();
 
+                // Register the stream before connecting, so data that follows
+                // the Connect right away waits instead of being dropped
+                // Increased buffer to 10000 to prevent HOL blocking
+                let (tx, mut rx) = mpsc::channel::<Bytes>(10000);
+                self.streams.lock().await.insert(stream_id, tx);
+
                 tokio::spawn(async move {
                     match TcpStream::connect(&target).await {
                         Ok(mut socket) => {
                             info!("Connected to {}", target);
-                            
-                            // Split stream handling
-                            // Increased buffer to 10000 to prevent HOL blocking
-                            let (tx, mut rx) = mpsc::channel::<Bytes>(10000);
-                            
-                            {
-                                let mut map = streams.lock().await;
-                                map.insert(stream_id, tx);
-                            }
 
                             let (mut rd, mut wr) = socket.split();
                             
@@ -89,6 +85,7 @@ impl ServerProxy {
                         }
                         Err(e) => {
                             warn!("Failed to connect to {}: {}", target, e);
+                            streams.lock().await.remove(&stream_id);
                             // Send disconnect immediately
                              let _ = tunnel_tx.send(Frame::new(FrameType::Disconnect, stream_id, Bytes::new())).await;
                         }
@@ -2,7 +2,7 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use anyhow::{Result, anyhow};
 use std::net::{SocketAddr, Ipv4Addr};
-use tracing::{info, debug, error};
+use tracing::{info, debug};
 
 pub struct Socks5Listener {
     listener: TcpListener,
@@ -56,29 +56,26 @@ impl Socks5Listener {
             return Err(anyhow!("Unsupported command: {}", cmd));
         }
 
-        let target_host;
-        let target_port;
-
-        match atyp {
+        let target_host = match atyp {
             0x01 => { // IPv4
                 let mut ip_buf = [0u8; 4];
                 socket.read_exact(&mut ip_buf).await?;
                 let ip = Ipv4Addr::from(ip_buf);
-                target_host = ip.to_string();
+                ip.to_string()
             }
             0x03 => { // Domain Name
                 let len = socket.read_u8().await? as usize;
                 let mut name_buf = vec![0u8; len];
                 socket.read_exact(&mut name_buf).await?;
-                target_host = String::from_utf8(name_buf)?;
+                String::from_utf8(name_buf)?
             }
             _ => {
                  socket.write_all(&[0x05, 0x08, 0x00, 0x01, 0,0,0,0, 0,0]).await?;
                  return Err(anyhow!("Unsupported address type: {}", atyp));
             }
-        }
+        };
 
-        target_port = socket.read_u16().await?;
+        let target_port = socket.read_u16().await?;
 
         // Send Success Reply immediately (we lie and say we connected)
         // [VER, REP, RSV, ATYP, BND.ADDR(0.0.0.0), BND.PORT(0)]
@@ -1,6 +1,6 @@
 use std::process::Command;
 use anyhow::{Result, anyhow};
-use tracing::{info, warn, error};
+use tracing::{info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -32,7 +32,7 @@ impl MacProxyManager {
         info!("Enabling System SOCKS Proxy on {}...", self.interface_name);
         
         let status = Command::new("networksetup")
-            .args(&["-setsocksfirewallproxy", &self.interface_name, host, &port.to_string()])
+            .args(["-setsocksfirewallproxy", &self.interface_name, host, &port.to_string()])
             .status();
 
         match status {
@@ -42,7 +42,7 @@ impl MacProxyManager {
                 
                 // Also enable the state (sometimes required separately)
                  let _ = Command::new("networksetup")
-                    .args(&["-setsocksfirewallproxystate", &self.interface_name, "on"])
+                    .args(["-setsocksfirewallproxystate", &self.interface_name, "on"])
                     .status();
                 
                 Ok(())
@@ -60,7 +60,7 @@ impl MacProxyManager {
 
         info!("Disabling System SOCKS Proxy on {}...", self.interface_name);
         let _ = Command::new("networksetup")
-            .args(&["-setsocksfirewallproxystate", &self.interface_name, "off"])
+            .args(["-setsocksfirewallproxystate", &self.interface_name, "off"])
             .status();
             
         self.active.store(false, Ordering::SeqCst);
@@ -68,13 +68,19 @@ impl MacProxyManager {
     }
 }
 
+impl Default for MacProxyManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for MacProxyManager {
     fn drop(&mut self) {
         if self.active.load(Ordering::SeqCst) {
             warn!("ProxyManager dropped while active! Attempting panic-cleanup...");
             // Start a new process to cleanup because current one is dying
              let _ = Command::new("networksetup")
-                .args(&["-setsocksfirewallproxystate", &self.interface_name, "off"])
+                .args(["-setsocksfirewallproxystate", &self.interface_name, "off"])
                 .status();
         }
     }
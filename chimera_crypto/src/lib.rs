@@ -53,7 +53,7 @@ impl Cipher {
         Ok(())
     }
 
-    pub fn decrypt(&self, nonce_val: u64, data: &mut Vec<u8>) -> Result<usize> {
+    pub fn decrypt(&self, nonce_val: u64, data: &mut [u8]) -> Result<usize> {
         let nonce = self.create_nonce(nonce_val);
         let decrypted_data = self.key.open_in_place(nonce, aead::Aad::empty(), data)
             .map_err(|_| anyhow!("Decryption failed"))?;
@@ -0,0 +1,129 @@
+//! Loopback bulk transfer through `TcpConnection`.
+//!
+//! `recv/legacy` is the receive path `TcpConnection` used to have (a fresh
+//! 1 KiB `Vec` per read, copied into `Bytes`), next to the current one;
+//! `send` compares small frames sent one by one with the same frames handed
+//! to `send_vectored` in batches.
+//!
+//!   cargo bench -p chimera_transport --bench tcp_throughput
+
+use bytes::Bytes;
+use chimera_transport::tcp::TcpTransport;
+use chimera_transport::{Connection, Transport};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Bytes moved per iteration.
+const TOTAL: usize = 32 * 1024 * 1024;
+/// Size of the frames the send benchmarks write, and how many go per batch.
+const FRAME: usize = 512;
+const BATCH: usize = 64;
+
+/// A `TcpConnection` to a peer that `peer` is run against.
+async fn connection<F, Fut>(peer: F) -> (Box<dyn Connection>, tokio::task::JoinHandle<()>)
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let task = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        peer(stream).await;
+    });
+    let conn = TcpTransport::new().connect(&addr.into()).await.unwrap();
+    (conn, task)
+}
+
+/// Writes `TOTAL` bytes, then closes.
+async fn write_bulk(mut stream: TcpStream) {
+    let chunk = vec![0x5a; 64 * 1024];
+    for _ in 0..TOTAL / chunk.len() {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+}
+
+/// Reads until the other side closes.
+async fn drain(mut stream: TcpStream) {
+    let mut buf = vec![0; 256 * 1024];
+    while stream.read(&mut buf).await.unwrap() > 0 {}
+}
+
+async fn legacy_recv(stream: &mut TcpStream) -> Option<Bytes> {
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    (n > 0).then(|| Bytes::copy_from_slice(&buf[..n]))
+}
+
+fn recv(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("recv");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+
+    group.bench_function("legacy", |b| {
+        b.to_async(&rt).iter(|| async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let writer = tokio::spawn(async move { write_bulk(listener.accept().await.unwrap().0).await });
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut received = 0;
+            while let Some(chunk) = legacy_recv(&mut stream).await {
+                received += chunk.len();
+            }
+            assert_eq!(received, TOTAL);
+            writer.await.unwrap();
+        })
+    });
+
+    group.bench_function("TcpConnection", |b| {
+        b.to_async(&rt).iter(|| async {
+            let (mut conn, writer) = connection(write_bulk).await;
+            let mut received = 0;
+            while let Some(chunk) = conn.recv().await.unwrap() {
+                received += chunk.len();
+            }
+            assert_eq!(received, TOTAL);
+            writer.await.unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let batch = vec![Bytes::from(vec![0x5a; FRAME]); BATCH];
+    let mut group = c.benchmark_group("send");
+    group.throughput(Throughput::Bytes(TOTAL as u64));
+    group.sample_size(10);
+
+    group.bench_function("one by one", |b| {
+        b.to_async(&rt).iter(|| async {
+            let (mut conn, reader) = connection(drain).await;
+            for _ in 0..TOTAL / (FRAME * BATCH) {
+                for frame in &batch {
+                    conn.send(frame.clone()).await.unwrap();
+                }
+            }
+            conn.close().await.unwrap();
+            reader.await.unwrap();
+        })
+    });
+
+    group.bench_function("send_vectored", |b| {
+        b.to_async(&rt).iter(|| async {
+            let (mut conn, reader) = connection(drain).await;
+            for _ in 0..TOTAL / (FRAME * BATCH) {
+                conn.send_vectored(&batch).await.unwrap();
+            }
+            conn.close().await.unwrap();
+            reader.await.unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, recv, send);
+criterion_main!(benches);
@@ -0,0 +1,626 @@
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use data_encoding::BASE32_DNSSEC;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::Rng;
+use tokio::net::UdpSocket;
+
+use crate::polling::{self, PollConfig, Reply, RoundTrip, SessionTable, SESSION_TIMEOUT};
+
+/// Query payload header: [Session: 4] [Seq: 4] [Flags: 1]
+const QUERY_HEADER_LEN: usize = 9;
+/// Query: the client is closing. Answer: the session is over.
+const FLAG_CLOSE: u8 = 1;
+
+const TYPE_NULL: u16 = 10;
+const TYPE_TXT: u16 = 16;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_REFUSED: u16 = 5;
+
+/// Longest name in presentation form (without the trailing dot).
+const MAX_NAME: usize = 253;
+const MAX_LABEL: usize = 63;
+/// UDP payload size advertised via EDNS(0); the usual safe value.
+const EDNS_SIZE: u16 = 1232;
+/// Answer budget for queries without EDNS(0).
+const CLASSIC_SIZE: usize = 512;
+
+/// Resolvers give up on slow answers, so keep each attempt short.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRIES: u32 = 5;
+/// How long the server holds a query waiting for downstream data.
+const RESPONSE_HOLD: Duration = Duration::from_millis(100);
+
+/// Answer record used to carry downstream data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsRecordType {
+    Txt,
+    Null,
+}
+
+impl DnsRecordType {
+    fn code(self) -> u16 {
+        match self {
+            DnsRecordType::Txt => TYPE_TXT,
+            DnsRecordType::Null => TYPE_NULL,
+        }
+    }
+}
+
+/// Last-resort tunnel for networks where only DNS gets out.
+///
+/// Upstream bytes are base32-encoded into the labels of queries under
+/// `domain`; the server is the authoritative name server for that zone and
+/// answers with downstream bytes in TXT (or NULL) records. Queries go
+/// through a recursive resolver, or straight to the server if none is set.
+/// Every query is a numbered request in the `polling` scheme, so a resolver
+/// retrying or duplicating a query does not corrupt the stream. Expect a
+/// few KiB/s at best.
+pub struct DnsTransport {
+    domain: String,
+    resolver: Option<SocketAddr>,
+    record_type: DnsRecordType,
+    min_poll: Duration,
+    max_poll: Duration,
+}
+
+impl DnsTransport {
+    /// `domain` is the zone delegated to the server (e.g. "t.example.com").
+    pub fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.trim_end_matches('.').to_ascii_lowercase(),
+            resolver: None,
+            record_type: DnsRecordType::Txt,
+            min_poll: Duration::from_millis(100),
+            max_poll: Duration::from_secs(2),
+        }
+    }
+
+    /// Resolver the client sends its queries to. Without one the client
+    /// queries the address passed to `connect()` directly.
+    pub fn with_resolver(mut self, resolver: SocketAddr) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Record type the client asks for. The server answers either.
+    pub fn with_record_type(mut self, record_type: DnsRecordType) -> Self {
+        self.record_type = record_type;
+        self
+    }
+
+    /// Bounds of the adaptive polling interval of an idle client.
+    pub fn with_poll_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.min_poll = min;
+        self.max_poll = max.max(min);
+        self
+    }
+
+    /// Upstream bytes that fit in one query name under `domain`.
+    fn max_upstream(&self) -> usize {
+        // Labels of up to 63 characters, each followed by a dot
+        let room = MAX_NAME.saturating_sub(self.domain.len() + 1);
+        let chars = room / (MAX_LABEL + 1) * MAX_LABEL + (room % (MAX_LABEL + 1)).saturating_sub(1);
+        (chars * 5 / 8).saturating_sub(QUERY_HEADER_LEN)
+    }
+}
+
+#[async_trait]
+impl super::Transport for DnsTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let max_body = self.max_upstream();
+        if max_body == 0 {
+            return Err(anyhow!("DNS tunnel domain is too long"));
+        }
+        let resolver = self.resolver.unwrap_or(addr);
+        let bind_addr: SocketAddr = if resolver.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(resolver).await?;
+
+        let session = DnsSession {
+            socket,
+            domain: self.domain.clone(),
+            qtype: self.record_type.code(),
+            id: rand::thread_rng().gen(),
+            seq: 0,
+            buf: vec![0u8; 65536],
+        };
+        let config = PollConfig {
+            min_poll: self.min_poll,
+            max_poll: self.max_poll,
+            max_body,
+        };
+        Ok(Box::new(polling::connect(session, config, "DNS").await?))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let (sessions, listener) = SessionTable::new(RESPONSE_HOLD, "DNS");
+        let server = Arc::new(DnsServer {
+            domain: self.domain.split('.').map(str::to_string).collect(),
+            sessions,
+        });
+        tokio::spawn(serve(socket, server));
+        Ok(Box::new(listener))
+    }
+
+    fn name(&self) -> &str {
+        "DNS"
+    }
+
+    // An HTTP-looking handshake is pointless inside DNS, and would not
+    // fit in the single query the handshake expects it to arrive in
+    fn wants_mimic(&self) -> bool {
+        false
+    }
+}
+
+/// Client side of one session: one query per request, retried on loss.
+struct DnsSession {
+    socket: UdpSocket,
+    domain: String,
+    qtype: u16,
+    id: u32,
+    seq: u32,
+    buf: Vec<u8>,
+}
+
+impl DnsSession {
+    fn query_name(&self, payload: &[u8]) -> String {
+        let encoded = BASE32_DNSSEC.encode(payload);
+        let mut name = String::with_capacity(MAX_NAME);
+        for label in encoded.as_bytes().chunks(MAX_LABEL) {
+            name.push_str(std::str::from_utf8(label).unwrap_or_default());
+            name.push('.');
+        }
+        name.push_str(&self.domain);
+        name
+    }
+
+    /// Sends one query and waits for its answer; `Ok(None)` on timeout or
+    /// a transient resolver error.
+    async fn attempt(&mut self, name: &str) -> Result<Option<Bytes>> {
+        let txid: u16 = rand::thread_rng().gen();
+        self.socket.send(&build_query(txid, name, self.qtype)?).await?;
+
+        let deadline = tokio::time::Instant::now() + QUERY_TIMEOUT;
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut self.buf)).await {
+                Ok(res) => res?,
+                Err(_) => return Ok(None),
+            };
+            // Late answers to earlier attempts carry other IDs
+            match parse_response(&self.buf[..len], txid) {
+                Some(Ok(rdata)) => return Ok(Some(rdata)),
+                Some(Err(RCODE_NXDOMAIN)) => return Err(anyhow!("DNS tunnel session rejected by server")),
+                Some(Err(RCODE_REFUSED)) => return Err(anyhow!("DNS query refused (wrong domain or resolver)")),
+                Some(Err(_)) => return Ok(None),
+                None => continue,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RoundTrip for DnsSession {
+    async fn round_trip(&mut self, body: Bytes, close: bool) -> Result<Reply> {
+        let mut payload = BytesMut::with_capacity(QUERY_HEADER_LEN + body.len());
+        payload.put_u32(self.id);
+        payload.put_u32(self.seq);
+        payload.put_u8(if close { FLAG_CLOSE } else { 0 });
+        payload.extend_from_slice(&body);
+        let name = self.query_name(&payload);
+
+        for _ in 0..=MAX_RETRIES {
+            if let Some(answer) = self.attempt(&name).await? {
+                let (&flags, data) = answer.split_first()
+                    .ok_or_else(|| anyhow!("Empty DNS tunnel answer"))?;
+                self.seq = self.seq.wrapping_add(1);
+                return Ok(Reply {
+                    body: answer.slice_ref(data),
+                    closed: flags & FLAG_CLOSE != 0,
+                });
+            }
+        }
+        Err(anyhow!("DNS tunnel query timed out"))
+    }
+}
+
+/// Authoritative side: decodes queries under the tunnel zone into session
+/// requests and answers with their replies.
+struct DnsServer {
+    domain: Vec<String>,
+    sessions: SessionTable<u32>,
+}
+
+impl DnsServer {
+    async fn answer(&self, query: &Query, peer: SocketAddr) -> Vec<u8> {
+        // Queries outside our zone: we are not a resolver
+        let Some(prefix_len) = query.labels.len().checked_sub(self.domain.len()) else {
+            return build_response(query, RCODE_REFUSED, None);
+        };
+        if !query.labels[prefix_len..].iter().zip(&self.domain).all(|(a, b)| a.eq_ignore_ascii_case(b)) {
+            return build_response(query, RCODE_REFUSED, None);
+        }
+        // Other types (e.g. A lookups from QNAME minimisation) get an empty answer
+        if query.qtype != TYPE_TXT && query.qtype != TYPE_NULL {
+            return build_response(query, 0, None);
+        }
+
+        let encoded: String = query.labels[..prefix_len].concat();
+        let payload = match BASE32_DNSSEC.decode(encoded.as_bytes()) {
+            Ok(payload) if payload.len() >= QUERY_HEADER_LEN => Bytes::from(payload),
+            _ => return build_response(query, RCODE_NXDOMAIN, None),
+        };
+        let id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        let seq = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+        let close = payload[8] & FLAG_CLOSE != 0;
+        let body = payload.slice(QUERY_HEADER_LEN..);
+
+        let max_body = answer_capacity(query);
+        match self.sessions.exchange(id, seq as u64, body, close, max_body, peer).await {
+            Some(reply) => {
+                let mut rdata = Vec::with_capacity(1 + reply.body.len());
+                rdata.push(if reply.closed { FLAG_CLOSE } else { 0 });
+                rdata.extend_from_slice(&reply.body);
+                build_response(query, 0, Some(&rdata))
+            }
+            None => build_response(query, RCODE_NXDOMAIN, None),
+        }
+    }
+}
+
+async fn serve(socket: Arc<UdpSocket>, server: Arc<DnsServer>) {
+    let mut buf = vec![0u8; 65536];
+    let mut expiry = tokio::time::interval(SESSION_TIMEOUT / 4);
+    loop {
+        let (len, peer) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(received) => received,
+                // e.g. ICMP port unreachable reported on the socket
+                Err(_) => continue,
+            },
+            _ = expiry.tick() => {
+                server.sessions.expire();
+                continue;
+            }
+            _ = server.sessions.closed() => break,
+        };
+        let Some(query) = parse_query(&buf[..len]) else {
+            continue;
+        };
+        // Answering may wait for downstream data; don't hold up other queries
+        let socket = socket.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            let response = server.answer(&query, peer).await;
+            let _ = socket.send_to(&response, peer).await;
+        });
+    }
+}
+
+/// Downstream bytes that fit in the answer to `query`.
+fn answer_capacity(query: &Query) -> usize {
+    let size = query.edns_size.map_or(CLASSIC_SIZE, |size| (size as usize).clamp(CLASSIC_SIZE, EDNS_SIZE as usize));
+    // Header, question, OPT record, answer record header and the flags byte
+    let overhead = 12 + query.question.len() + if query.edns_size.is_some() { 11 } else { 0 } + 12 + 1;
+    let room = size.saturating_sub(overhead);
+    // TXT spends one length byte per 255-byte string
+    room - room.div_ceil(256)
+}
+
+/// The parts of a query the server needs.
+struct Query {
+    id: u16,
+    flags: u16,
+    /// Question section as received, echoed verbatim (keeps 0x20 casing)
+    question: Vec<u8>,
+    labels: Vec<String>,
+    qtype: u16,
+    edns_size: Option<u16>,
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(12 + name.len() + 2 + 4 + 11);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x0100u16.to_be_bytes()); // RD
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]); // QD=1, AR=1
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL {
+            return Err(anyhow!("Invalid DNS label in {}", name));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    push_opt(&mut packet);
+    Ok(packet)
+}
+
+/// EDNS(0) OPT pseudo-record advertising `EDNS_SIZE`.
+fn push_opt(packet: &mut Vec<u8>) {
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    packet.extend_from_slice(&EDNS_SIZE.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // TTL, RDLEN
+}
+
+fn parse_query(packet: &[u8]) -> Option<Query> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 != 0 || read_u16(packet, 4)? != 1 {
+        return None;
+    }
+    let records = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize;
+    let additional = read_u16(packet, 10)?;
+
+    let (labels, pos) = read_name(packet, 12)?;
+    let qtype = read_u16(packet, pos)?;
+    let question = packet.get(12..pos + 4)?.to_vec();
+    let labels = labels.iter().map(|l| String::from_utf8_lossy(l).into_owned()).collect();
+
+    let mut pos = pos + 4;
+    for _ in 0..records {
+        pos = read_record(packet, pos)?.0;
+    }
+    let mut edns_size = None;
+    for _ in 0..additional {
+        let (next, rtype, class, _) = read_record(packet, pos)?;
+        if rtype == TYPE_OPT {
+            edns_size = Some(class);
+        }
+        pos = next;
+    }
+    Some(Query { id, flags, question, labels, qtype, edns_size })
+}
+
+fn build_response(query: &Query, rcode: u16, rdata: Option<&[u8]>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(512);
+    // QR, AA, RD copied from the query
+    let flags = 0x8000 | 0x0400 | (query.flags & 0x0100) | rcode;
+    packet.extend_from_slice(&query.id.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&(rdata.is_some() as u16).to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&(query.edns_size.is_some() as u16).to_be_bytes());
+    packet.extend_from_slice(&query.question);
+
+    if let Some(rdata) = rdata {
+        let rdata = if query.qtype == TYPE_TXT {
+            let mut strings = Vec::with_capacity(rdata.len() + rdata.len() / 255 + 1);
+            for chunk in rdata.chunks(255) {
+                strings.push(chunk.len() as u8);
+                strings.extend_from_slice(chunk);
+            }
+            strings
+        } else {
+            rdata.to_vec()
+        };
+        packet.extend_from_slice(&[0xc0, 0x0c]); // pointer to the question name
+        packet.extend_from_slice(&query.qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&0u32.to_be_bytes()); // TTL 0: never cache
+        packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&rdata);
+    }
+    if query.edns_size.is_some() {
+        push_opt(&mut packet);
+    }
+    packet
+}
+
+/// Returns the payload of the first TXT/NULL answer, the rcode if the
+/// query failed, or `None` if the packet is not the answer to `txid`.
+fn parse_response(packet: &[u8], txid: u16) -> Option<std::result::Result<Bytes, u16>> {
+    if read_u16(packet, 0)? != txid {
+        return None;
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    let rcode = flags & 0x000f;
+    if rcode != 0 {
+        return Some(Err(rcode));
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    for _ in 0..answers {
+        let (next, rtype, _, rdata) = read_record(packet, pos)?;
+        match rtype {
+            TYPE_TXT => {
+                let mut payload = Vec::with_capacity(rdata.len());
+                let mut rest = rdata;
+                while let Some((&len, tail)) = rest.split_first() {
+                    payload.extend_from_slice(tail.get(..len as usize)?);
+                    rest = &tail[len as usize..];
+                }
+                return Some(Ok(Bytes::from(payload)));
+            }
+            TYPE_NULL => return Some(Ok(Bytes::copy_from_slice(rdata))),
+            _ => pos = next,
+        }
+    }
+    // An answer without data (e.g. mangled by a middlebox) counts as lost
+    Some(Err(0xffff))
+}
+
+/// Reads one resource record; returns the position after it, its type,
+/// class and data.
+fn read_record(packet: &[u8], pos: usize) -> Option<(usize, u16, u16, &[u8])> {
+    let (_, pos) = read_name(packet, pos)?;
+    let rtype = read_u16(packet, pos)?;
+    let class = read_u16(packet, pos + 2)?;
+    let rdlen = read_u16(packet, pos + 8)? as usize;
+    let rdata = packet.get(pos + 10..pos + 10 + rdlen)?;
+    Some((pos + 10 + rdlen, rtype, class, rdata))
+}
+
+/// Reads a possibly compressed name; returns its labels and the position
+/// right after it in the original packet.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some((labels, end.unwrap_or(pos + 1))),
+            l if l & 0xc0 == 0xc0 => {
+                let target = (read_u16(packet, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l if l <= MAX_LABEL => {
+                labels.push(packet.get(pos + 1..pos + 1 + l)?.to_vec());
+                pos += 1 + l;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(pos)?, *packet.get(pos + 1)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Endpoint, Transport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const DOMAIN: &str = "t.example.com";
+
+    /// Stand-in for a recursive resolver: forwards each query to `upstream`
+    /// under a fresh ID and with the letters of its name in random case
+    /// (0x20 encoding), and like a real one drops answers that do not echo
+    /// that exact question. Returns its address and the number of answers
+    /// it passed on.
+    async fn resolver(upstream: SocketAddr) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let count = forwarded.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let Ok((len, client)) = socket.recv_from(&mut buf).await else { break };
+                let query = buf[..len].to_vec();
+                let socket = socket.clone();
+                let count = count.clone();
+                tokio::spawn(async move {
+                    if let Some(answer) = forward(&query, upstream).await {
+                        count.fetch_add(1, Ordering::Relaxed);
+                        let _ = socket.send_to(&answer, client).await;
+                    }
+                });
+            }
+        });
+        (addr, forwarded)
+    }
+
+    async fn forward(query: &[u8], upstream: SocketAddr) -> Option<Vec<u8>> {
+        let question_end = read_name(query, 12)?.1 + 4;
+        let mut outgoing = query.to_vec();
+        let id: u16 = rand::thread_rng().gen();
+        outgoing[..2].copy_from_slice(&id.to_be_bytes());
+        for byte in &mut outgoing[12..question_end] {
+            if byte.is_ascii_alphabetic() && rand::thread_rng().gen_bool(0.5) {
+                *byte ^= 0x20;
+            }
+        }
+        let mixed = outgoing[12..question_end].to_vec();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.ok()?;
+        socket.send_to(&outgoing, upstream).await.ok()?;
+        let mut buf = vec![0u8; 65536];
+        let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await.ok()?.ok()?;
+        let mut answer = buf[..len].to_vec();
+        if read_u16(&answer, 0)? != id || answer.get(12..question_end)? != mixed.as_slice() {
+            return None;
+        }
+        answer[..2].copy_from_slice(&query[..2]);
+        answer[12..question_end].copy_from_slice(&query[12..question_end]);
+        Some(answer)
+    }
+
+    async fn free_udp_port() -> SocketAddr {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn answer_echoes_question_casing() {
+        let packet = build_query(0x1234, "AbCd.T.Example.COM", TYPE_TXT).unwrap();
+        let query = parse_query(&packet).unwrap();
+        let response = build_response(&query, 0, Some(b"\0data"));
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(&response[12..12 + query.question.len()], &packet[12..12 + query.question.len()]);
+        assert_eq!(parse_response(&response, 0x1234), Some(Ok(Bytes::from_static(b"\0data"))));
+    }
+
+    #[tokio::test]
+    async fn tunnel_through_resolver() {
+        let server_addr = free_udp_port().await;
+        let transport = DnsTransport::new(DOMAIN)
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50));
+        let mut listener = transport.listen(&Endpoint::Inet(server_addr)).await.unwrap();
+
+        let (resolver_addr, forwarded) = resolver(server_addr).await;
+        let client = DnsTransport::new(DOMAIN)
+            .with_resolver(resolver_addr)
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50));
+        // The listener has to outlive the test: dropping it stops the server
+        let accept = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            (conn, listener)
+        });
+        let mut client_conn = client.connect(&Endpoint::Inet(server_addr)).await.unwrap();
+        let (mut server_conn, _listener) = accept.await.unwrap();
+
+        // Several queries' worth in each direction
+        let upstream: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+        client_conn.send(Bytes::from(upstream.clone())).await.unwrap();
+        assert_eq!(read_exact(&mut server_conn, upstream.len()).await, upstream);
+
+        let downstream: Vec<u8> = (0..4000u32).map(|i| (i * 7) as u8).collect();
+        server_conn.send(Bytes::from(downstream.clone())).await.unwrap();
+        assert_eq!(read_exact(&mut client_conn, downstream.len()).await, downstream);
+
+        client_conn.close().await.unwrap();
+        assert!(forwarded.load(Ordering::Relaxed) > 0);
+    }
+
+    async fn read_exact(conn: &mut Box<dyn Connection>, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            let chunk = tokio::time::timeout(Duration::from_secs(10), conn.recv()).await
+                .expect("tunnel stalled")
+                .unwrap()
+                .expect("tunnel closed early");
+            received.extend_from_slice(&chunk);
+        }
+        received
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{Result, anyhow};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where a transport connects to or listens on.
+///
+/// The textual form (used for configuration) is `host:port` for IP
+/// endpoints, `unix:/path/to/socket` for filesystem sockets, `@name`
+/// for Linux abstract sockets and `memory:name` for in-process ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+    /// Linux abstract socket namespace (no file on disk).
+    Abstract(Vec<u8>),
+    /// In-process endpoint of a `MemoryTransport`.
+    Memory(String),
+}
+
+impl Endpoint {
+    /// The IP address of an `Inet` endpoint; an error for the other kinds,
+    /// which IP-based transports cannot use.
+    pub fn inet(&self) -> Result<SocketAddr> {
+        match self {
+            Endpoint::Inet(addr) => Ok(*addr),
+            other => Err(anyhow!("{} is not an IP endpoint", other)),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Inet(addr)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("Empty Unix socket path"));
+            }
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        if let Some(name) = s.strip_prefix("memory:") {
+            return Ok(Endpoint::Memory(name.to_string()));
+        }
+        if let Some(name) = s.strip_prefix('@') {
+            return Ok(Endpoint::Abstract(name.as_bytes().to_vec()));
+        }
+        s.parse()
+            .map(Endpoint::Inet)
+            .map_err(|_| anyhow!("Invalid endpoint '{}' (expected host:port, unix:/path, @name or memory:name)", s))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Inet(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Abstract(name) => write!(f, "@{}", name.escape_ascii()),
+            Endpoint::Memory(name) => write!(f, "memory:{}", name),
+        }
+    }
+}
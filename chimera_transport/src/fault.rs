@@ -0,0 +1,541 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use anyhow::{Result, anyhow};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use super::{ChannelRecvHalf, ChannelSendHalf, Endpoint};
+
+/// Chunks queued per direction before the pump stops reading more.
+const QUEUE_LIMIT: usize = 64;
+
+/// A scripted set of network faults for `FaultTransport`.
+///
+/// Data faults apply per connection and direction; byte counts cover both
+/// directions together. Outages are windows of time, measured from when
+/// the plan was created, during which connects (and accepts) fail; clones
+/// share that starting point, so a plan can be reused across transports.
+///
+/// A plan can also be written as text, e.g.
+/// `latency=80ms,jitter=20ms,loss=0.01,bandwidth=250000,reset_after=1000000,stall=50000:3s,outage=10s:5s,period=60s`;
+/// `blocked` makes every connect fail.
+#[derive(Debug, Clone)]
+pub struct FaultPlan {
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+    bandwidth: Option<u64>,
+    reset_after: Option<u64>,
+    stall: Option<(u64, Duration)>,
+    outages: Vec<(Duration, Duration)>,
+    period: Option<Duration>,
+    connect_delay: Duration,
+    epoch: Instant,
+}
+
+impl FaultPlan {
+    /// A plan without faults.
+    pub fn new() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            bandwidth: None,
+            reset_after: None,
+            stall: None,
+            outages: Vec::new(),
+            period: None,
+            connect_delay: Duration::from_millis(500),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Delay added to every chunk, plus up to `jitter` at random.
+    /// Chunks are never reordered.
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    /// Probability (0.0 to 1.0) that a chunk is silently dropped. Nothing
+    /// retransmits it, so the tunnel on top breaks the way it would behind
+    /// a lossy middlebox.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Caps throughput in each direction, in bytes per second.
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Resets the connection once this many bytes went through it.
+    pub fn with_reset_after(mut self, bytes: u64) -> Self {
+        self.reset_after = Some(bytes);
+        self
+    }
+
+    /// Freezes the connection for `duration` once `bytes` went through it.
+    pub fn with_stall(mut self, bytes: u64, duration: Duration) -> Self {
+        self.stall = Some((bytes, duration));
+        self
+    }
+
+    /// Connects fail from `start` for `duration`. Several windows make up
+    /// a script; with `with_period` it repeats.
+    pub fn with_outage(mut self, start: Duration, duration: Duration) -> Self {
+        self.outages.push((start, duration));
+        self
+    }
+
+    /// Repeats the outage script every `period`.
+    pub fn with_period(mut self, period: Duration) -> Self {
+        self.period = Some(period).filter(|period| !period.is_zero());
+        self
+    }
+
+    /// Every connect fails, as if the protocol were blocked outright.
+    pub fn blocked(self) -> Self {
+        self.with_outage(Duration::ZERO, Duration::MAX)
+    }
+
+    /// How long a failing connect takes to report, like a reset or
+    /// timed-out dial (default 500 ms).
+    pub fn with_connect_delay(mut self, delay: Duration) -> Self {
+        self.connect_delay = delay;
+        self
+    }
+
+    fn is_down(&self) -> bool {
+        let elapsed = self.epoch.elapsed();
+        let t = match self.period {
+            Some(period) => Duration::from_nanos((elapsed.as_nanos() % period.as_nanos()) as u64),
+            None => elapsed,
+        };
+        self.outages.iter().any(|&(start, duration)| t >= start && t - start < duration)
+    }
+
+    fn is_lost(&self) -> bool {
+        self.loss > 0.0 && rand::thread_rng().gen_bool(self.loss)
+    }
+
+    fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        self.latency + self.jitter.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+impl Default for FaultPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for FaultPlan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut plan = FaultPlan::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            plan = match key {
+                "latency" => {
+                    let jitter = plan.jitter;
+                    plan.with_latency(parse_duration(value)?, jitter)
+                }
+                "jitter" => {
+                    let latency = plan.latency;
+                    plan.with_latency(latency, parse_duration(value)?)
+                }
+                "loss" => plan.with_loss(value.parse()?),
+                "bandwidth" => plan.with_bandwidth(value.parse()?),
+                "reset_after" => plan.with_reset_after(value.parse()?),
+                "stall" => {
+                    let (bytes, duration) = split_pair(value)?;
+                    plan.with_stall(bytes.parse()?, parse_duration(duration)?)
+                }
+                "outage" => {
+                    let (start, duration) = split_pair(value)?;
+                    plan.with_outage(parse_duration(start)?, parse_duration(duration)?)
+                }
+                "period" => plan.with_period(parse_duration(value)?),
+                "connect_delay" => plan.with_connect_delay(parse_duration(value)?),
+                "blocked" => plan.blocked(),
+                _ => return Err(anyhow!("Unknown fault '{}'", key)),
+            };
+        }
+        Ok(plan)
+    }
+}
+
+fn split_pair(value: &str) -> Result<(&str, &str)> {
+    value.split_once(':').ok_or_else(|| anyhow!("Expected a:b, got '{}'", value))
+}
+
+/// Parses `250ms` or `5s`.
+pub(crate) fn parse_duration(value: &str) -> Result<Duration> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        return Ok(Duration::from_millis(ms.parse()?));
+    }
+    if let Some(secs) = value.strip_suffix('s') {
+        return Ok(Duration::try_from_secs_f64(secs.parse()?)?);
+    }
+    Err(anyhow!("Invalid duration '{}' (expected e.g. 250ms or 5s)", value))
+}
+
+/// Wraps another transport and injects the faults of a `FaultPlan`, to
+/// exercise failover and reconnect logic against a misbehaving network.
+pub struct FaultTransport<T> {
+    inner: T,
+    plan: Arc<FaultPlan>,
+}
+
+impl<T: super::Transport> FaultTransport<T> {
+    pub fn new(inner: T, plan: FaultPlan) -> Self {
+        Self {
+            inner,
+            plan: Arc::new(plan),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: super::Transport> super::Transport for FaultTransport<T> {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Connection>> {
+        if self.plan.is_down() {
+            tokio::time::sleep(self.plan.connect_delay).await;
+            return Err(anyhow!("Connection reset by peer (injected fault)"));
+        }
+        let inner = self.inner.connect(endpoint).await?;
+        Ok(Box::new(FaultConnection::new(inner, self.plan.clone())))
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Listener>> {
+        let inner = self.inner.listen(endpoint).await?;
+        Ok(Box::new(FaultListener { inner, plan: self.plan.clone() }))
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn wants_mimic(&self) -> bool {
+        self.inner.wants_mimic()
+    }
+}
+
+struct FaultListener {
+    inner: Box<dyn super::Listener>,
+    plan: Arc<FaultPlan>,
+}
+
+#[async_trait]
+impl super::Listener for FaultListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, Endpoint)> {
+        loop {
+            let (connection, peer) = self.inner.accept().await?;
+            // During an outage incoming connections are dropped on the floor
+            if self.plan.is_down() {
+                continue;
+            }
+            return Ok((Box::new(FaultConnection::new(connection, self.plan.clone())), peer));
+        }
+    }
+}
+
+/// Hands data to a background pump that owns the real connection and
+/// delays, drops or cuts it according to the plan.
+struct FaultConnection {
+    // Dropping the sender closes the inner connection once the queue drains
+    tx: Option<mpsc::Sender<Bytes>>,
+    rx: mpsc::Receiver<Result<Option<Bytes>>>,
+}
+
+impl FaultConnection {
+    fn new(inner: Box<dyn super::Connection>, plan: Arc<FaultPlan>) -> Self {
+        let (up_tx, up_rx) = mpsc::channel(QUEUE_LIMIT);
+        let (down_tx, down_rx) = mpsc::channel(QUEUE_LIMIT);
+        tokio::spawn(pump(inner, plan, up_rx, down_tx));
+        Self { tx: Some(up_tx), rx: down_rx }
+    }
+}
+
+#[async_trait]
+impl super::Connection for FaultConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| anyhow!("Connection closed"))?;
+        // The pump is gone: the connection failed or was reset
+        tx.send(data).await.map_err(|_| std::io::Error::from(ErrorKind::ConnectionReset).into())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.rx.recv().await.transpose()?.flatten())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        (Box::new(ChannelRecvHalf::new(self.rx)), Box::new(ChannelSendHalf::new(self.tx)))
+    }
+}
+
+/// What both directions of a faulty connection share: how much went
+/// through it, which decides when it stalls or is reset.
+struct Link {
+    plan: Arc<FaultPlan>,
+    moved: AtomicU64,
+    // Set once the stall began: nothing is delivered until then
+    stalled_until: Mutex<Option<Instant>>,
+}
+
+impl Link {
+    fn new(plan: Arc<FaultPlan>) -> Self {
+        Self { plan, moved: AtomicU64::new(0), stalled_until: Mutex::new(None) }
+    }
+
+    /// Counts `bytes` going through; an error once the connection is to
+    /// be reset.
+    fn carry(&self, bytes: usize) -> Result<()> {
+        let moved = self.moved.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if let Some((after, duration)) = self.plan.stall {
+            let mut stalled_until = self.stalled_until.lock().unwrap();
+            if stalled_until.is_none() && moved >= after {
+                *stalled_until = Some(Instant::now() + duration);
+            }
+        }
+        if self.plan.reset_after.is_some_and(|limit| moved >= limit) {
+            return Err(reset_error());
+        }
+        Ok(())
+    }
+
+    fn held_until(&self) -> Option<Instant> {
+        *self.stalled_until.lock().unwrap()
+    }
+}
+
+fn reset_error() -> anyhow::Error {
+    std::io::Error::new(ErrorKind::ConnectionReset, "Connection reset by peer (injected fault)").into()
+}
+
+/// One direction of a faulty link: chunks wait here until their
+/// (latency, jitter and bandwidth dependent) delivery time. `None` marks
+/// the end of the stream.
+struct DelayLine {
+    queue: VecDeque<(Instant, Option<Bytes>)>,
+    // When the bandwidth cap lets the next chunk start
+    next_free: Instant,
+}
+
+impl DelayLine {
+    fn new() -> Self {
+        Self { queue: VecDeque::new(), next_free: Instant::now() }
+    }
+
+    fn push(&mut self, data: Option<Bytes>, plan: &FaultPlan) {
+        let now = Instant::now();
+        let mut sent = now;
+        if let (Some(rate), Some(data)) = (plan.bandwidth, &data) {
+            let start = self.next_free.max(now);
+            self.next_free = start + Duration::from_secs_f64(data.len() as f64 / rate as f64);
+            sent = self.next_free;
+        }
+        let mut due = sent + plan.delay();
+        // Jitter must not reorder the stream
+        if let Some(&(last, _)) = self.queue.back() {
+            due = due.max(last);
+        }
+        self.queue.push_back((due, data));
+    }
+
+    /// When the next chunk is to be delivered, given a stall that holds
+    /// everything until `held_until`.
+    fn due(&self, held_until: Option<Instant>) -> Option<Instant> {
+        let due = self.queue.front().map(|&(due, _)| due)?;
+        Some(held_until.map_or(due, |held| due.max(held)))
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        self.queue.pop_front().and_then(|(_, data)| data)
+    }
+
+    fn is_full(&self) -> bool {
+        self.queue.len() >= QUEUE_LIMIT
+    }
+}
+
+/// Sleeps until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Owns the real connection and moves data through both directions of
+/// the link. The directions run side by side on the two halves, so a
+/// send waiting for the peer never holds up reading from it.
+async fn pump(
+    inner: Box<dyn super::Connection>,
+    plan: Arc<FaultPlan>,
+    up_rx: mpsc::Receiver<Bytes>,
+    down_tx: mpsc::Sender<Result<Option<Bytes>>>,
+) {
+    let reset = inner.reset_handle();
+    let (recv, send) = inner.split();
+    let link = Link::new(plan);
+    let result = tokio::try_join!(
+        forward_up(&link, up_rx, send),
+        forward_down(&link, recv, &down_tx),
+    );
+    // Both halves are gone by now
+    if let Err(e) = result {
+        if link.plan.reset_after.is_some_and(|limit| link.moved.load(Ordering::Relaxed) >= limit) {
+            if let Some(reset) = reset {
+                reset.reset();
+            }
+        }
+        let _ = down_tx.send(Err(e)).await;
+    }
+}
+
+/// Our side to the peer: ends once our close went out.
+async fn forward_up(link: &Link, mut up_rx: mpsc::Receiver<Bytes>, mut send: Box<dyn super::SendHalf>) -> Result<()> {
+    let mut line = DelayLine::new();
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            data = up_rx.recv(), if !closing && !line.is_full() => match data {
+                Some(data) => {
+                    link.carry(data.len())?;
+                    if !link.plan.is_lost() {
+                        line.push(Some(data), &link.plan);
+                    }
+                }
+                None => {
+                    closing = true;
+                    line.push(None, &link.plan);
+                }
+            },
+            _ = sleep_until(line.due(link.held_until())) => match line.pop() {
+                Some(data) => send.send(data).await?,
+                None => {
+                    let _ = send.close().await;
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+/// The peer to our side: ends once its close was delivered, or nobody is
+/// left to read.
+async fn forward_down(
+    link: &Link,
+    mut recv: Box<dyn super::RecvHalf>,
+    down_tx: &mpsc::Sender<Result<Option<Bytes>>>,
+) -> Result<()> {
+    let mut line = DelayLine::new();
+    let mut eof = false;
+    loop {
+        let due = line.due(link.held_until());
+        tokio::select! {
+            res = recv.recv(), if !eof && !line.is_full() => match res? {
+                Some(data) => {
+                    link.carry(data.len())?;
+                    if !link.plan.is_lost() {
+                        line.push(Some(data), &link.plan);
+                    }
+                }
+                None => {
+                    eof = true;
+                    line.push(None, &link.plan);
+                }
+            },
+            permit = async { sleep_until(due).await; down_tx.reserve().await } => {
+                let Ok(permit) = permit else { return Ok(()) };
+                let data = line.pop();
+                let end = data.is_none();
+                permit.send(Ok(data));
+                if end {
+                    return Ok(());
+                }
+            },
+            _ = down_tx.closed() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::{TcpOptions, TcpTransport};
+    use crate::{Connection, Transport};
+
+    #[tokio::test]
+    async fn reset_reaches_the_peer_as_a_reset() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = FaultTransport::new(TcpTransport::new(), FaultPlan::new().with_reset_after(10));
+        let mut conn = transport.connect(&Endpoint::Inet(addr)).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        conn.send(Bytes::from_static(b"more than ten bytes")).await.unwrap();
+        let err = conn.recv().await.unwrap_err();
+        assert_eq!(err.downcast_ref::<std::io::Error>().unwrap().kind(), ErrorKind::ConnectionReset);
+
+        use tokio::io::AsyncReadExt;
+        let mut buf = [0u8; 64];
+        let err = peer.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn bulk_both_ways_does_not_stall() {
+        let tcp = TcpTransport::new().with_options(TcpOptions::new().with_send_buffer(16 * 1024).with_recv_buffer(16 * 1024));
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let probe = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let endpoint = Endpoint::Inet(probe.local_addr().unwrap());
+        drop(probe);
+        // Faults on both ends, so both pumps may want to send at once
+        let transport = FaultTransport::new(tcp, FaultPlan::new());
+        let mut listener = transport.listen(&endpoint).await.unwrap();
+        let conn = transport.connect(&endpoint).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+
+        // Both ends send far more than the sockets and queues hold
+        const TOTAL: usize = 8 * 1024 * 1024;
+        async fn exchange(conn: Box<dyn Connection>) -> usize {
+            let (mut recv, mut send) = conn.split();
+            let sender = tokio::spawn(async move {
+                for _ in 0..TOTAL / 4096 {
+                    send.send(Bytes::from(vec![7u8; 4096])).await.unwrap();
+                }
+            });
+            let mut received = 0;
+            while received < TOTAL {
+                received += recv.recv().await.unwrap().unwrap().len();
+            }
+            sender.await.unwrap();
+            received
+        }
+        let both = async { tokio::join!(exchange(conn), exchange(peer)) };
+        let received = tokio::time::timeout(Duration::from_secs(30), both).await.expect("stalled");
+        assert_eq!(received, (TOTAL, TOTAL));
+    }
+}
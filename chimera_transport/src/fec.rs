@@ -0,0 +1,641 @@
+//! Forward error correction for datagram transports: datagrams go out in
+//! groups followed by Reed-Solomon parity, so a receiver can rebuild lost
+//! ones without waiting a round trip for a retransmission.
+//!
+//! Packet layout (after whatever routing header the transport puts first):
+//! `[Kind: 1] [Seq: 4] [Loss: 1] [Group: 4] [Index: 1]`, then for parity
+//! `[Data: 1] [Parity: 1]`, then the payload. A data packet carries one
+//! datagram; a parity packet one parity shard over the group's datagrams,
+//! each length-prefixed and zero-padded to the longest. `Loss` is the loss
+//! the sender measures on what it receives, 0 to 254 for 0 to 100%, or
+//! `UNMEASURED` before it has a sample.
+
+use anyhow::{Result, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chimera_ai::Router;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Packet kinds; transports pick them out next to their own packet types.
+pub const KIND_DATA: u8 = 0x10;
+pub const KIND_PARITY: u8 = 0x11;
+
+/// Loss byte of a sender that has not measured anything yet.
+const UNMEASURED: u8 = 255;
+
+/// Length of the header in front of a data packet's datagram.
+pub const HEADER_LEN: usize = 11;
+const PARITY_HEADER_LEN: usize = HEADER_LEN + 2;
+/// Groups a receiver holds on to for late shards.
+const GROUP_WINDOW: u32 = 32;
+/// Packets per loss measurement.
+const LOSS_WINDOW: u32 = 128;
+/// How often the measured loss is passed on to the `Router`.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Share of groups allowed to lose more than their parity rebuilds.
+const UNRECOVERABLE_TARGET: f64 = 0.01;
+
+/// Whether a packet whose kind byte is `kind` belongs to this layer.
+pub fn is_fec(kind: u8) -> bool {
+    kind == KIND_DATA || kind == KIND_PARITY
+}
+
+/// How much parity to send.
+///
+/// Each group of up to `group_size` datagrams gets enough parity that at
+/// the loss rate, fewer than 1 in 100 groups lose more than it can rebuild,
+/// within the redundancy bounds. The loss rate is what the session's peer
+/// last measured, or until it has, the one the `Router` has for the path
+/// when given one.
+///
+/// The textual form is a comma-separated list, e.g. `group=10,min=0.1,max=0.5`.
+#[derive(Clone)]
+pub struct FecConfig {
+    group_size: usize,
+    min_redundancy: f32,
+    max_redundancy: f32,
+    router: Option<(Arc<Router>, String)>,
+    stats: Arc<FecStats>,
+}
+
+impl FecConfig {
+    pub fn new() -> Self {
+        Self {
+            group_size: 10,
+            min_redundancy: 0.0,
+            max_redundancy: 0.5,
+            router: None,
+            stats: Arc::new(FecStats::default()),
+        }
+    }
+
+    /// Datagrams per group (at most 128). Larger groups cost less parity
+    /// for the same protection, but hold up recovery longer.
+    pub fn with_group_size(mut self, size: usize) -> Self {
+        self.group_size = size.clamp(1, 128);
+        self
+    }
+
+    /// Parity per datagram, e.g. 0.2 for 2 parity shards per 10 datagrams.
+    /// A minimum above zero protects even while no loss was measured.
+    pub fn with_redundancy(mut self, min: f32, max: f32) -> Self {
+        self.max_redundancy = max.clamp(0.0, 1.0);
+        self.min_redundancy = min.clamp(0.0, self.max_redundancy);
+        self
+    }
+
+    /// Reports the loss measured on the link to `router`'s path `name` (the
+    /// worst any live session's peer sees), which new sessions size their
+    /// parity from until their own peer has measured.
+    pub fn with_router(mut self, router: Arc<Router>, name: &str) -> Self {
+        self.router = Some((router, name.to_string()));
+        self
+    }
+
+    /// Counters shared by every session using this config.
+    pub fn stats(&self) -> Arc<FecStats> {
+        self.stats.clone()
+    }
+
+    /// Parity shards for a group of `data` datagrams at `loss`.
+    fn parity(&self, data: usize, loss: f32) -> usize {
+        let min = (data as f32 * self.min_redundancy).ceil() as usize;
+        let max = (data as f32 * self.max_redundancy).ceil() as usize;
+        parity_needed(data, loss.min(1.0), max).clamp(min, max).min(255 - data)
+    }
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for FecConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = FecConfig::new();
+        let (mut min, mut max) = (config.min_redundancy, config.max_redundancy);
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            match key {
+                "group" => config = config.with_group_size(value.parse()?),
+                "min" => min = value.parse()?,
+                "max" => max = value.parse()?,
+                _ => return Err(anyhow!("Unknown FEC option '{}'", key)),
+            }
+        }
+        Ok(config.with_redundancy(min, max))
+    }
+}
+
+/// What FEC sent and saved, for tuning the redundancy.
+#[derive(Debug, Default)]
+pub struct FecStats {
+    data_sent: AtomicU64,
+    parity_sent: AtomicU64,
+    data_received: AtomicU64,
+    parity_received: AtomicU64,
+    recovered: AtomicU64,
+    unrecoverable: AtomicU64,
+    loss: AtomicU32,
+    redundancy: AtomicU32,
+    // What the peer of each live session last measured, by session
+    sessions: AtomicU64,
+    peer_losses: Mutex<HashMap<u64, f32>>,
+}
+
+impl FecStats {
+    pub fn data_sent(&self) -> u64 {
+        self.data_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn parity_sent(&self) -> u64 {
+        self.parity_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn data_received(&self) -> u64 {
+        self.data_received.load(Ordering::Relaxed)
+    }
+
+    pub fn parity_received(&self) -> u64 {
+        self.parity_received.load(Ordering::Relaxed)
+    }
+
+    /// Lost datagrams rebuilt from parity.
+    pub fn recovered(&self) -> u64 {
+        self.recovered.load(Ordering::Relaxed)
+    }
+
+    /// Lost datagrams there was too little parity for, left to
+    /// retransmission.
+    pub fn unrecoverable(&self) -> u64 {
+        self.unrecoverable.load(Ordering::Relaxed)
+    }
+
+    /// Loss last measured on incoming packets by any of the sessions
+    /// sharing these stats (0.0 to 1.0), for display.
+    pub fn loss(&self) -> f32 {
+        f32::from_bits(self.loss.load(Ordering::Relaxed))
+    }
+
+    /// Redundancy of the last group sent.
+    pub fn redundancy(&self) -> f32 {
+        f32::from_bits(self.redundancy.load(Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for FecStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} data + {} parity (redundancy {:.0}%), received {} data + {} parity (loss {:.1}%), recovered {}, unrecoverable {}",
+            self.data_sent(),
+            self.parity_sent(),
+            self.redundancy() * 100.0,
+            self.data_received(),
+            self.parity_received(),
+            self.loss() * 100.0,
+            self.recovered(),
+            self.unrecoverable(),
+        )
+    }
+}
+
+/// One session's FEC: wraps its outgoing datagrams and rebuilds incoming
+/// ones.
+pub struct Fec {
+    config: FecConfig,
+    id: u64,
+    header: Bytes,
+    codecs: HashMap<(usize, usize), ReedSolomon>,
+    // Sending
+    seq: u32,
+    group: u32,
+    shards: Vec<Vec<u8>>,
+    // Receiving
+    groups: BTreeMap<u32, Group>,
+    newest: Option<u32>,
+    meter: LossMeter,
+    peer_loss: Option<f32>,
+    last_report: Option<Instant>,
+}
+
+/// Shards of one received group, by index, until it is settled.
+#[derive(Default)]
+struct Group {
+    shards: HashMap<usize, Vec<u8>>,
+    shape: Option<(usize, usize)>,
+    settled: bool,
+}
+
+impl Fec {
+    /// Every packet it sends starts with `header` (e.g. a session id the
+    /// transport routes by); `decode` expects it stripped.
+    pub fn new(config: FecConfig, header: Bytes) -> Self {
+        let id = config.stats.sessions.fetch_add(1, Ordering::Relaxed);
+        Self {
+            config,
+            id,
+            header,
+            codecs: HashMap::new(),
+            seq: 0,
+            group: 0,
+            shards: Vec::new(),
+            groups: BTreeMap::new(),
+            newest: None,
+            meter: LossMeter::default(),
+            peer_loss: None,
+            last_report: None,
+        }
+    }
+
+    /// Wraps `datagram` for sending, followed by the group's parity once
+    /// it is full.
+    pub fn encode(&mut self, datagram: &[u8]) -> Vec<Bytes> {
+        let mut packet = self.packet(KIND_DATA, self.shards.len(), datagram.len());
+        packet.put_slice(datagram);
+        self.config.stats.data_sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut shard = Vec::with_capacity(2 + datagram.len());
+        shard.put_u16(datagram.len() as u16);
+        shard.put_slice(datagram);
+        self.shards.push(shard);
+
+        let mut packets = vec![packet.freeze()];
+        if self.shards.len() >= self.config.group_size {
+            packets.extend(self.flush());
+        }
+        packets
+    }
+
+    /// Closes the current group early with its parity, so datagrams sent
+    /// before a lull are protected too.
+    pub fn flush(&mut self) -> Vec<Bytes> {
+        if self.shards.is_empty() {
+            return Vec::new();
+        }
+        let data = self.shards.len();
+        let parity = self.config.parity(data, self.send_loss());
+        let redundancy = parity as f32 / data as f32;
+        self.config.stats.redundancy.store(redundancy.to_bits(), Ordering::Relaxed);
+
+        let mut packets = Vec::with_capacity(parity);
+        if parity > 0 {
+            let len = self.shards.iter().map(Vec::len).max().unwrap_or(0);
+            for shard in &mut self.shards {
+                shard.resize(len, 0);
+            }
+            let mut parity_shards = vec![vec![0u8; len]; parity];
+            if let Ok(codec) = codec(&mut self.codecs, data, parity) {
+                if codec.encode_sep(&self.shards, &mut parity_shards).is_ok() {
+                    for (i, shard) in parity_shards.iter().enumerate() {
+                        let mut packet = self.packet(KIND_PARITY, data + i, 2 + shard.len());
+                        packet.put_u8(data as u8);
+                        packet.put_u8(parity as u8);
+                        packet.put_slice(shard);
+                        packets.push(packet.freeze());
+                    }
+                    self.config.stats.parity_sent.fetch_add(parity as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        self.shards.clear();
+        self.group = self.group.wrapping_add(1);
+        packets
+    }
+
+    /// Unwraps a received packet: its datagram, plus any lost ones it
+    /// let the group rebuild. Malformed packets yield nothing.
+    pub fn decode(&mut self, mut packet: &[u8]) -> Vec<Bytes> {
+        if packet.len() < HEADER_LEN {
+            return Vec::new();
+        }
+        let kind = packet.get_u8();
+        let seq = packet.get_u32();
+        let peer_loss = packet.get_u8();
+        let group = packet.get_u32();
+        let index = packet.get_u8() as usize;
+
+        if let Some(loss) = self.meter.record(seq) {
+            self.config.stats.loss.store(loss.to_bits(), Ordering::Relaxed);
+        }
+        if peer_loss != UNMEASURED {
+            self.peer_loss = Some(peer_loss as f32 / 254.0);
+            self.report_loss();
+        }
+        if !self.track(group) {
+            // Too old to help any more, but still worth delivering
+            return match kind {
+                KIND_DATA => vec![Bytes::copy_from_slice(packet)],
+                _ => Vec::new(),
+            };
+        }
+
+        let mut delivered = Vec::new();
+        let entry = self.groups.entry(group).or_default();
+        match kind {
+            KIND_DATA => {
+                self.config.stats.data_received.fetch_add(1, Ordering::Relaxed);
+                delivered.push(Bytes::copy_from_slice(packet));
+                if !entry.settled {
+                    let mut shard = Vec::with_capacity(2 + packet.len());
+                    shard.put_u16(packet.len() as u16);
+                    shard.put_slice(packet);
+                    entry.shards.insert(index, shard);
+                }
+            }
+            KIND_PARITY if packet.len() >= PARITY_HEADER_LEN - HEADER_LEN => {
+                self.config.stats.parity_received.fetch_add(1, Ordering::Relaxed);
+                let data = packet.get_u8() as usize;
+                let parity = packet.get_u8() as usize;
+                if !entry.settled && data > 0 && index >= data && index < data + parity {
+                    entry.shape = Some((data, parity));
+                    entry.shards.insert(index, packet.to_vec());
+                }
+            }
+            _ => return Vec::new(),
+        }
+        delivered.extend(self.recover(group));
+        delivered
+    }
+
+    /// Loss the parity sent should cover.
+    fn send_loss(&self) -> f32 {
+        self.peer_loss
+            .or_else(|| {
+                let (router, name) = self.config.router.as_ref()?;
+                router.get_stats(name).map(|stats| stats.packet_loss)
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Passes the loss the peer sees on our packets on to the `Router`,
+    /// now and then, as the worst of the sessions on the path.
+    fn report_loss(&mut self) {
+        let (Some((router, name)), Some(peer_loss)) = (&self.config.router, self.peer_loss) else {
+            return;
+        };
+        if self.last_report.is_some_and(|last| last.elapsed() < REPORT_INTERVAL) {
+            return;
+        }
+        let worst = {
+            let mut peer_losses = self.config.stats.peer_losses.lock().unwrap();
+            peer_losses.insert(self.id, peer_loss);
+            peer_losses.values().copied().fold(0.0, f32::max)
+        };
+        router.report_loss(name, worst);
+        self.last_report = Some(Instant::now());
+    }
+
+    /// Notes `group` as seen, settling those that fell out of the window.
+    /// False when `group` itself is out of it.
+    fn track(&mut self, group: u32) -> bool {
+        let newest = match self.newest {
+            Some(newest) if seq_before(group, newest) => {
+                return newest.wrapping_sub(group) < GROUP_WINDOW;
+            }
+            _ => group,
+        };
+        self.newest = Some(newest);
+        let expired: Vec<u32> = self.groups.keys()
+            .copied()
+            .filter(|&old| newest.wrapping_sub(old) >= GROUP_WINDOW)
+            .collect();
+        for old in expired {
+            if let Some(entry) = self.groups.remove(&old) {
+                if let (false, Some((data, _))) = (entry.settled, entry.shape) {
+                    let missing = (0..data).filter(|i| !entry.shards.contains_key(i)).count();
+                    self.config.stats.unrecoverable.fetch_add(missing as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        true
+    }
+
+    /// Rebuilds the missing datagrams of `group` once enough shards are in.
+    fn recover(&mut self, group: u32) -> Vec<Bytes> {
+        let Some(entry) = self.groups.get_mut(&group) else {
+            return Vec::new();
+        };
+        let Some((data, parity)) = entry.shape.filter(|_| !entry.settled) else {
+            return Vec::new();
+        };
+        let missing: Vec<usize> = (0..data).filter(|i| !entry.shards.contains_key(i)).collect();
+        if missing.is_empty() || entry.shards.len() < data {
+            entry.settled = missing.is_empty();
+            if entry.settled {
+                entry.shards.clear();
+            }
+            return Vec::new();
+        }
+
+        let len = entry.shards.values().map(Vec::len).max().unwrap_or(0);
+        let mut shards: Vec<Option<Vec<u8>>> = (0..data + parity)
+            .map(|i| entry.shards.get(&i).map(|shard| {
+                let mut shard = shard.clone();
+                shard.resize(len, 0);
+                shard
+            }))
+            .collect();
+        entry.settled = true;
+        entry.shards.clear();
+        let rebuilt = codec(&mut self.codecs, data, parity)
+            .and_then(|codec| Ok(codec.reconstruct_data(&mut shards)?));
+        if rebuilt.is_err() {
+            self.config.stats.unrecoverable.fetch_add(missing.len() as u64, Ordering::Relaxed);
+            return Vec::new();
+        }
+
+        let mut recovered = Vec::with_capacity(missing.len());
+        for i in missing {
+            let Some(mut shard) = shards[i].as_deref() else { continue };
+            if shard.len() < 2 {
+                continue;
+            }
+            let datagram_len = shard.get_u16() as usize;
+            if datagram_len <= shard.len() {
+                recovered.push(Bytes::copy_from_slice(&shard[..datagram_len]));
+            }
+        }
+        self.config.stats.recovered.fetch_add(recovered.len() as u64, Ordering::Relaxed);
+        recovered
+    }
+
+    /// A packet of `kind` with room for `payload` bytes, header filled in.
+    fn packet(&mut self, kind: u8, index: usize, payload: usize) -> BytesMut {
+        let mut packet = BytesMut::with_capacity(self.header.len() + PARITY_HEADER_LEN + payload);
+        packet.put_slice(&self.header);
+        packet.put_u8(kind);
+        packet.put_u32(self.seq);
+        packet.put_u8(self.meter.loss.map_or(UNMEASURED, |loss| (loss * 254.0).round() as u8));
+        packet.put_u32(self.group);
+        packet.put_u8(index as u8);
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+}
+
+impl Drop for Fec {
+    fn drop(&mut self) {
+        self.config.stats.peer_losses.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Fewest parity shards (up to `max`) for which a group of `data` datagrams
+/// plus parity, each lost with probability `loss`, loses more than the
+/// parity at most `UNRECOVERABLE_TARGET` of the time.
+fn parity_needed(data: usize, loss: f32, max: usize) -> usize {
+    if loss <= 0.0 {
+        return 0;
+    }
+    let p = loss as f64;
+    (0..max).find(|&parity| {
+        // Binomial tail: more than `parity` of `n` packets lost
+        let n = data + parity;
+        let mut pmf = (1.0 - p).powi(n as i32);
+        let mut within = pmf;
+        for lost in 0..parity {
+            pmf *= (n - lost) as f64 / (lost + 1) as f64 * p / (1.0 - p);
+            within += pmf;
+        }
+        1.0 - within <= UNRECOVERABLE_TARGET
+    }).unwrap_or(max)
+}
+
+/// The codec for `data` + `parity` shards, built on first use.
+fn codec(codecs: &mut HashMap<(usize, usize), ReedSolomon>, data: usize, parity: usize) -> Result<&ReedSolomon> {
+    Ok(match codecs.entry((data, parity)) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(ReedSolomon::new(data, parity)?),
+    })
+}
+
+/// Loss of incoming packets, from the gaps in their sequence numbers.
+///
+/// Packets are counted in windows of `LOSS_WINDOW`; a window is measured
+/// once the one after it is complete too, so reordered packets still count.
+#[derive(Default)]
+struct LossMeter {
+    base: Option<u32>,
+    counts: [u32; 2],
+    loss: Option<f32>,
+}
+
+impl LossMeter {
+    /// Counts packet `seq`, returning the smoothed loss when a window is
+    /// measured.
+    fn record(&mut self, seq: u32) -> Option<f32> {
+        let base = *self.base.get_or_insert(seq);
+        if seq_before(seq, base) {
+            return None;
+        }
+        let mut offset = seq.wrapping_sub(base);
+        if offset >= 8 * LOSS_WINDOW {
+            // After an outage, start over rather than measure each window missed
+            self.base = Some(seq);
+            self.counts = [0, 0];
+            offset = 0;
+        }
+        let mut measured = None;
+        while offset >= 2 * LOSS_WINDOW {
+            let sample = 1.0 - (self.counts[0] as f32 / LOSS_WINDOW as f32).min(1.0);
+            let loss = match self.loss {
+                Some(loss) => loss * 0.8 + sample * 0.2,
+                None => sample,
+            };
+            self.loss = Some(loss);
+            measured = Some(loss);
+            self.counts = [self.counts[1], 0];
+            self.base = self.base.map(|base| base.wrapping_add(LOSS_WINDOW));
+            offset -= LOSS_WINDOW;
+        }
+        self.counts[(offset / LOSS_WINDOW) as usize] += 1;
+        measured
+    }
+}
+
+/// `a` comes before `b` in wrapping sequence space.
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The loss a packet tells its receiver it sees.
+    fn advertised_loss(packet: &[u8]) -> u8 {
+        packet[5]
+    }
+
+    #[test]
+    fn sessions_advertise_their_own_loss() {
+        let config = FecConfig::new();
+        let (mut lossy_peer, mut lossy) = (Fec::new(config.clone(), Bytes::new()), Fec::new(config.clone(), Bytes::new()));
+        let (mut clean_peer, mut clean) = (Fec::new(config.clone(), Bytes::new()), Fec::new(config.clone(), Bytes::new()));
+        for i in 0..8 * LOSS_WINDOW {
+            let packets = lossy_peer.encode(b"datagram");
+            // Half the lossy session's data never arrives
+            if i % 2 == 0 {
+                lossy.decode(&packets[0]);
+            }
+            for packet in clean_peer.encode(b"datagram") {
+                clean.decode(&packet);
+            }
+        }
+
+        assert!(advertised_loss(&lossy.encode(b"reply")[0]) > 64);
+        assert_eq!(advertised_loss(&clean.encode(b"reply")[0]), 0);
+    }
+
+    /// Sends `windows` loss windows of datagrams from `from` to `to`,
+    /// dropping every other one when `lossy`.
+    fn exchange(from: &mut Fec, to: &mut Fec, windows: u32, lossy: bool) {
+        for i in 0..windows * LOSS_WINDOW {
+            let packets = from.encode(b"datagram");
+            if !lossy || i % 2 == 0 {
+                to.decode(&packets[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn unmeasured_loss_is_not_reported() {
+        let router = Arc::new(Router::new());
+        router.register_path("KCP");
+        router.report_failure("KCP");
+        let mut session = Fec::new(FecConfig::new().with_router(router.clone(), "KCP"), Bytes::new());
+        let mut peer = Fec::new(FecConfig::new(), Bytes::new());
+
+        // The peer has not received enough to measure anything yet
+        assert_eq!(advertised_loss(&peer.encode(b"hello")[0]), UNMEASURED);
+        exchange(&mut peer, &mut session, 1, false);
+        assert_eq!(router.get_stats("KCP").unwrap().packet_loss, 1.0);
+    }
+
+    #[test]
+    fn router_gets_the_worst_session_and_each_sizes_its_own_parity() {
+        let router = Arc::new(Router::new());
+        router.register_path("KCP");
+        let config = FecConfig::new().with_router(router.clone(), "KCP");
+        let (mut lossy, mut lossy_peer) = (Fec::new(config.clone(), Bytes::new()), Fec::new(FecConfig::new(), Bytes::new()));
+        let (mut clean, mut clean_peer) = (Fec::new(config.clone(), Bytes::new()), Fec::new(FecConfig::new(), Bytes::new()));
+        exchange(&mut lossy, &mut lossy_peer, 8, true);
+        exchange(&mut clean, &mut clean_peer, 8, false);
+
+        // The peers' replies carry what they measured; the clean one comes last
+        exchange(&mut lossy_peer, &mut lossy, 1, false);
+        exchange(&mut clean_peer, &mut clean, 1, false);
+        assert!(router.get_stats("KCP").unwrap().packet_loss > 0.25);
+        assert!(lossy.send_loss() > 0.25);
+        assert_eq!(clean.send_loss(), 0.0);
+    }
+}
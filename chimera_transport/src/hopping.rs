@@ -0,0 +1,225 @@
+//! Port hopping: both sides derive the port in use from a shared secret
+//! and the time, so it changes every interval without any signalling and
+//! an observer cannot tell which port of the range comes next.
+
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use ring::hmac;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::Endpoint;
+
+type Accepted = Result<(Box<dyn super::Connection>, Endpoint)>;
+
+/// Which port of a range is in use when.
+///
+/// Time is cut into intervals numbered from the Unix epoch; the port of
+/// each is picked from the range by an HMAC of its number under the
+/// secret. Clocks need to agree to within an interval.
+#[derive(Clone)]
+pub struct HopSchedule {
+    key: hmac::Key,
+    first: u16,
+    last: u16,
+    interval: Duration,
+}
+
+impl HopSchedule {
+    pub fn new(secret: &[u8], ports: RangeInclusive<u16>) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            first: *ports.start(),
+            last: (*ports.end()).max(*ports.start()),
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    /// The transport that hops (`SERVER_HOP_TRANSPORT`, TCP by default) and
+    /// the schedule of `SERVER_HOP_PORTS`, `SERVER_HOP_SECRET` and
+    /// `SERVER_HOP_INTERVAL_SECS`; None without `SERVER_HOP_PORTS`.
+    pub fn from_env() -> Result<Option<(String, Self)>> {
+        let Ok(ports) = std::env::var("SERVER_HOP_PORTS") else {
+            return Ok(None);
+        };
+        let secret = std::env::var("SERVER_HOP_SECRET")
+            .map_err(|_| anyhow!("SERVER_HOP_PORTS needs SERVER_HOP_SECRET"))?;
+        let mut schedule = Self::new(secret.as_bytes(), parse_port_range(&ports)?);
+        if let Ok(secs) = std::env::var("SERVER_HOP_INTERVAL_SECS") {
+            schedule = schedule.with_interval(Duration::from_secs(secs.parse()?));
+        }
+        let transport = std::env::var("SERVER_HOP_TRANSPORT").unwrap_or_else(|_| "TCP".to_string());
+        Ok(Some((transport, schedule)))
+    }
+
+    /// How long each port is used for (default a minute).
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Number of the current interval.
+    pub fn epoch(&self) -> u64 {
+        since_unix_epoch().as_secs() / self.interval.as_secs()
+    }
+
+    /// The port in use during interval `epoch`.
+    pub fn port(&self, epoch: u64) -> u16 {
+        let tag = hmac::sign(&self.key, &epoch.to_be_bytes());
+        let value = u64::from_be_bytes(tag.as_ref()[..8].try_into().unwrap());
+        let count = (self.last - self.first) as u64 + 1;
+        self.first + (value % count) as u16
+    }
+
+    /// Time left until the next interval starts.
+    pub fn until_next(&self) -> Duration {
+        let interval = self.interval.as_secs();
+        let now = since_unix_epoch();
+        let next = Duration::from_secs((now.as_secs() / interval + 1) * interval);
+        next.saturating_sub(now)
+    }
+
+    /// Ports a server keeps open during interval `epoch`: its own and the
+    /// neighbours', for clocks that are a little off.
+    fn open_ports(&self, epoch: u64) -> Vec<u16> {
+        let mut ports: Vec<u16> = (epoch.saturating_sub(1)..=epoch + 1).map(|e| self.port(e)).collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+}
+
+fn since_unix_epoch() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Parses a port range such as `40000-40999` (or a single port).
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let first = u16::from_str(first.trim())?;
+    let last = u16::from_str(last.trim())?;
+    if first > last {
+        return Err(anyhow!("Invalid port range '{}'", s));
+    }
+    Ok(first..=last)
+}
+
+/// Wraps another transport so it connects to, and listens on, the port of
+/// a `HopSchedule` instead of the endpoint's.
+///
+/// Connects go to the current port; listeners follow the schedule, keeping
+/// the ports of the previous, current and next interval open. Connections
+/// outlive their port, so moving existing traffic over is up to the caller.
+pub struct HoppingTransport<T> {
+    inner: Arc<T>,
+    schedule: HopSchedule,
+}
+
+impl<T: super::Transport + 'static> HoppingTransport<T> {
+    pub fn new(inner: T, schedule: HopSchedule) -> Self {
+        Self { inner: Arc::new(inner), schedule }
+    }
+}
+
+#[async_trait]
+impl<T: super::Transport + 'static> super::Transport for HoppingTransport<T> {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let port = self.schedule.port(self.schedule.epoch());
+        self.inner.connect(&SocketAddr::new(addr.ip(), port).into()).await
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Listener>> {
+        let ip = endpoint.inet()?.ip();
+        let (tx, rx) = mpsc::channel(100);
+        let mut hops = Hops { inner: self.inner.clone(), ip, tx, listeners: HashMap::new() };
+        // The first ports must bind, later ones are retried on every hop
+        for port in self.schedule.open_ports(self.schedule.epoch()) {
+            hops.open(port).await?;
+        }
+        tokio::spawn(hops.run(self.schedule.clone()));
+        Ok(Box::new(HoppingListener { rx }))
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn wants_mimic(&self) -> bool {
+        self.inner.wants_mimic()
+    }
+}
+
+/// The inner listeners of a `HoppingListener`, by port.
+struct Hops<T> {
+    inner: Arc<T>,
+    ip: IpAddr,
+    tx: mpsc::Sender<Accepted>,
+    listeners: HashMap<u16, JoinHandle<()>>,
+}
+
+impl<T: super::Transport + 'static> Hops<T> {
+    async fn open(&mut self, port: u16) -> Result<()> {
+        let endpoint: Endpoint = SocketAddr::new(self.ip, port).into();
+        let listener = self.inner.listen(&endpoint).await?;
+        self.listeners.insert(port, tokio::spawn(accept_loop(listener, self.tx.clone())));
+        Ok(())
+    }
+
+    /// Opens and closes ports as the schedule moves on, until the listener
+    /// is dropped.
+    async fn run(mut self, schedule: HopSchedule) {
+        loop {
+            tokio::time::sleep(schedule.until_next()).await;
+            if self.tx.is_closed() {
+                break;
+            }
+            let open = schedule.open_ports(schedule.epoch());
+            self.listeners.retain(|port, task| {
+                let keep = open.contains(port);
+                if !keep {
+                    task.abort();
+                }
+                keep
+            });
+            for port in open {
+                if self.listeners.contains_key(&port) {
+                    continue;
+                }
+                if let Err(e) = self.open(port).await {
+                    // Reported like a failed accept, the listener stays up
+                    let _ = self.tx.send(Err(anyhow!("Cannot hop to port {}: {}", port, e))).await;
+                }
+            }
+        }
+        for task in self.listeners.values() {
+            task.abort();
+        }
+    }
+}
+
+async fn accept_loop(mut listener: Box<dyn super::Listener>, tx: mpsc::Sender<Accepted>) {
+    loop {
+        let accepted = listener.accept().await;
+        if tx.send(accepted).await.is_err() {
+            return;
+        }
+    }
+}
+
+struct HoppingListener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for HoppingListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, Endpoint)> {
+        self.rx.recv().await.ok_or_else(|| anyhow!("Hopping listener closed"))?
+    }
+}
@@ -0,0 +1,391 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::TlsAcceptor;
+use h2::client::SendRequest;
+use h2::{RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::tcp::{TcpOptions, TcpTransport};
+use crate::upstream::UpstreamProxy;
+use crate::tls::TlsTransport;
+
+/// TLS and HTTP/2 handshakes that take longer than this are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONTENT_TYPE: &str = "application/grpc";
+
+const USER_AGENT: &str = "grpc-go/1.64.0";
+
+/// Flow-control windows. The protocol defaults (64 KiB) throttle bulk
+/// tunnels; these are in line with what gRPC implementations use.
+const STREAM_WINDOW: u32 = 1024 * 1024;
+const CONNECTION_WINDOW: u32 = 4 * 1024 * 1024;
+
+/// How long a dropped stream lingers to flush data still queued.
+const LINGER: Duration = Duration::from_secs(10);
+
+/// Tunnels over HTTP/2, gRPC-style: each Chimera tunnel is one long-lived
+/// POST whose request and response bodies carry the two directions.
+///
+/// Clients keep one HTTP/2 connection per server address and open every
+/// new tunnel as another stream on it, so share a single transport
+/// instance (e.g. behind an `Arc`) to get multiplexing. Without TLS the
+/// transport speaks cleartext HTTP/2 (h2c), which is what most fronting
+/// proxies use towards their upstreams.
+pub struct Http2Transport {
+    tcp: TcpTransport,
+    path: String,
+    authority: Option<String>,
+    tls: Option<TlsTransport>,
+    sessions: Mutex<HashMap<SocketAddr, SendRequest<Bytes>>>,
+}
+
+impl Http2Transport {
+    pub fn new() -> Self {
+        Self {
+            tcp: TcpTransport::new(),
+            path: "/chimera.Tunnel/Stream".to_string(),
+            authority: None,
+            tls: None,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Request path of the tunnel streams; the server rejects any other path.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// `:authority` sent by the client (e.g. the fronted domain).
+    /// Defaults to the address being dialed.
+    pub fn with_authority(mut self, authority: &str) -> Self {
+        self.authority = Some(authority.to_string());
+        self
+    }
+
+    /// Runs HTTP/2 over TLS. The ALPN is forced to `h2`; everything else
+    /// (SNI, pin, certificate) is taken from `tls`.
+    pub fn with_tls(mut self, tls: TlsTransport) -> Self {
+        self.tls = Some(tls.with_alpn(&["h2"]));
+        self
+    }
+
+    /// Socket settings for plain HTTP/2; over TLS those of the
+    /// `TlsTransport` apply.
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Dials plain HTTP/2 through `proxy`; over TLS that of the
+    /// `TlsTransport` applies.
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.tcp = self.tcp.with_upstream_proxy(proxy);
+        self
+    }
+
+    /// Expects a PROXY protocol header (ahead of TLS, if any) from `trusted`
+    /// relays (see `TcpTransport::with_proxy_protocol`).
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.tcp = self.tcp.with_proxy_protocol(trusted);
+        self
+    }
+
+    /// Returns a ready handle to the pooled connection for `addr`,
+    /// dialing a new one if there is none or it has died.
+    async fn session(&self, addr: SocketAddr) -> Result<SendRequest<Bytes>> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get(&addr) {
+            if let Ok(session) = session.clone().ready().await {
+                return Ok(session);
+            }
+            sessions.remove(&addr);
+        }
+
+        let session = match &self.tls {
+            Some(tls) => client_handshake(tls.connect_stream(addr).await?).await?,
+            None => client_handshake(self.tcp.connect_stream(addr).await?).await?,
+        };
+        sessions.insert(addr, session.clone());
+        Ok(session.ready().await?)
+    }
+}
+
+impl Default for Http2Transport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for Http2Transport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let authority = self.authority.clone().unwrap_or_else(|| addr.to_string());
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}://{}{}", scheme, authority, self.path))
+            .header("content-type", CONTENT_TYPE)
+            .header("te", "trailers")
+            .header("user-agent", USER_AGENT)
+            .body(())?;
+
+        let mut session = self.session(addr).await?;
+        let (response, send) = session.send_request(request, false)?;
+        let response = response.await?;
+        if response.status() != StatusCode::OK {
+            return Err(anyhow!("HTTP/2 tunnel rejected with status {}", response.status()));
+        }
+        Ok(Box::new(Http2Connection::new(send, response.into_body(), false)))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let (listener, acceptor) = match &self.tls {
+            Some(tls) => {
+                let (listener, acceptor) = tls.bind(addr).await?;
+                (listener, Some(acceptor))
+            }
+            None => (self.tcp.bind(addr).await?, None),
+        };
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(accept_loop(listener, acceptor, self.tcp.proxy_protocol().cloned(), self.path.clone(), tx));
+        Ok(Box::new(Http2Listener { rx }))
+    }
+
+    fn name(&self) -> &str {
+        "HTTP2"
+    }
+
+    fn wants_mimic(&self) -> bool {
+        false
+    }
+}
+
+/// Performs the client side of the HTTP/2 handshake and drives the
+/// connection in the background for as long as any stream uses it.
+async fn client_handshake<S>(io: S) -> Result<SendRequest<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (session, connection) = h2::client::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake(io)
+        .await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(session)
+}
+
+struct Http2Connection {
+    send: SendStream<Bytes>,
+    // Only taken on drop, to keep the stream alive while it drains
+    recv: Option<RecvStream>,
+    // The server ends its half with gRPC trailers rather than an empty DATA frame
+    server: bool,
+    finished: bool,
+}
+
+impl Http2Connection {
+    fn new(send: SendStream<Bytes>, recv: RecvStream, server: bool) -> Self {
+        Self { send, recv: Some(recv), server, finished: false }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.server {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            self.send.send_trailers(trailers)?;
+        } else {
+            self.send.send_data(Bytes::new(), true)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl super::Connection for Http2Connection {
+    async fn send(&mut self, mut data: Bytes) -> Result<()> {
+        // Respect flow control instead of letting h2 buffer without bound
+        while !data.is_empty() {
+            self.send.reserve_capacity(data.len());
+            let capacity = match std::future::poll_fn(|cx| self.send.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Err(anyhow!("HTTP/2 stream closed")),
+            };
+            if capacity == 0 {
+                continue;
+            }
+            let chunk = data.split_to(capacity.min(data.len()));
+            self.send.send_data(chunk, false)?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        let Some(recv) = self.recv.as_mut() else {
+            return Ok(None);
+        };
+        match recv.data().await {
+            Some(chunk) => {
+                let chunk = chunk?;
+                // Reopen the window so the peer can keep sending
+                recv.flow_control().release_capacity(chunk.len())?;
+                Ok(Some(chunk))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl Drop for Http2Connection {
+    fn drop(&mut self) {
+        // h2 resets a stream once every handle to it is gone, discarding data
+        // still queued. End our half and hold the other one open, draining it,
+        // until the peer finishes too.
+        let _ = self.finish();
+        let (Some(mut recv), Ok(runtime)) = (self.recv.take(), tokio::runtime::Handle::try_current()) else {
+            return;
+        };
+        runtime.spawn(async move {
+            let drain = async {
+                while let Some(Ok(chunk)) = recv.data().await {
+                    let _ = recv.flow_control().release_capacity(chunk.len());
+                }
+            };
+            let _ = tokio::time::timeout(LINGER, drain).await;
+        });
+    }
+}
+
+type Accepted = Result<(Http2Connection, SocketAddr)>;
+
+struct Http2Listener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for Http2Listener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("HTTP/2 listener closed"))??;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Accepts TCP connections and serves each one in the background;
+/// every tunnel stream a client opens becomes one accepted connection.
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    trusted: Option<TrustedProxies>,
+    path: String,
+    tx: mpsc::Sender<Accepted>,
+) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if tx.send(Err(e.into())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        let path = path.clone();
+        let acceptor = acceptor.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            let addr = match proxy_protocol::accept_header(&mut stream, addr, trusted.as_ref()).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let Some(acceptor) = acceptor else {
+                return serve(stream, addr, path, tx).await;
+            };
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls)) => serve(tls, addr, path, tx).await,
+                Ok(Err(e)) => {
+                    let _ = tx.send(Err(anyhow!("TLS handshake from {} failed: {}", addr, e))).await;
+                }
+                Err(_) => {
+                    let _ = tx.send(Err(anyhow!("TLS handshake from {} timed out", addr))).await;
+                }
+            }
+        });
+    }
+}
+
+/// Drives one server-side HTTP/2 connection until the client goes away.
+async fn serve<S>(io: S, addr: SocketAddr, path: String, tx: mpsc::Sender<Accepted>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake(io);
+    let mut connection = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            let _ = tx.send(Err(anyhow!("HTTP/2 handshake from {} failed: {}", addr, e))).await;
+            return;
+        }
+        Err(_) => {
+            let _ = tx.send(Err(anyhow!("HTTP/2 handshake from {} timed out", addr))).await;
+            return;
+        }
+    };
+
+    // Accepting also drives I/O for the streams already handed out,
+    // so keep going even after the listener is gone.
+    while let Some(res) = connection.accept().await {
+        let Ok((request, mut respond)) = res else {
+            break;
+        };
+        if request.method() != Method::POST || request.uri().path() != path {
+            let mut not_found = Response::new(());
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            let _ = respond.send_response(not_found, true);
+            continue;
+        }
+
+        let mut response = Response::new(());
+        response.headers_mut().insert("content-type", HeaderValue::from_static(CONTENT_TYPE));
+        let Ok(send) = respond.send_response(response, false) else {
+            continue;
+        };
+        let tunnel = Http2Connection::new(send, request.into_body(), true);
+        if tx.send(Ok((tunnel, addr))).await.is_err() {
+            // Listener dropped: tell the client to stop opening streams here
+            connection.graceful_shutdown();
+        }
+    }
+}
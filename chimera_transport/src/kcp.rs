@@ -0,0 +1,907 @@
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+use crate::fec::{self, Fec, FecConfig};
+use crate::{ChannelRecvHalf, ChannelSendHalf};
+
+// Segment commands
+const CMD_PUSH: u8 = 1;
+const CMD_ACK: u8 = 2;
+const CMD_FIN: u8 = 3;
+const CMD_PING: u8 = 4;
+
+/// Segment header: [Conv: 4] [Cmd: 1] [Wnd: 2] [Ts: 4] [SN: 4] [UNA: 4] [Len: 2]
+const HEADER_LEN: usize = 21;
+/// Smallest MTU accepted: a header plus some payload.
+const MIN_MTU: usize = 64;
+/// Largest MTU accepted: the most a UDP datagram can carry over IPv4.
+const MAX_MTU: usize = 65_507;
+
+/// A segment retransmitted this many times declares the link dead.
+const DEAD_LINK: u32 = 20;
+/// Fast resend stops after this many transmissions; timeouts take over.
+const FAST_RESEND_LIMIT: u32 = 5;
+const RTO_INITIAL: Duration = Duration::from_millis(200);
+const RTO_MAX: Duration = Duration::from_secs(60);
+/// Silence from the peer for this long declares the link dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// An idle session sends a ping this often so the peer does not time out.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a finished session keeps acking retransmitted FINs.
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Tuning knobs for the reliable-UDP session.
+/// Smaller intervals and RTOs recover from loss faster at the cost of more
+/// redundant traffic.
+#[derive(Debug, Clone)]
+pub struct KcpConfig {
+    /// Max segments in flight, and max segments buffered out of order.
+    pub window_size: u16,
+    /// Internal clock: how often acks are flushed and retransmissions checked.
+    pub interval: Duration,
+    /// Lower bound of the retransmission timeout (the resend interval).
+    pub min_rto: Duration,
+    /// Resend a segment once this many later segments were acked (0 disables).
+    pub fast_resend: u32,
+    /// Limit the send rate with a TCP-like congestion window.
+    /// When disabled only the peer's receive window applies.
+    pub congestion_control: bool,
+    /// Max datagram size, headers included. Set through `with_mtu`.
+    mtu: usize,
+}
+
+impl KcpConfig {
+    /// Conservative, TCP-friendly settings.
+    pub fn normal() -> Self {
+        Self {
+            window_size: 128,
+            interval: Duration::from_millis(40),
+            min_rto: Duration::from_millis(100),
+            fast_resend: 0,
+            congestion_control: true,
+            mtu: 1350,
+        }
+    }
+
+    /// Faster loss recovery, still congestion controlled.
+    pub fn fast() -> Self {
+        Self {
+            window_size: 256,
+            interval: Duration::from_millis(20),
+            min_rto: Duration::from_millis(50),
+            fast_resend: 2,
+            congestion_control: true,
+            mtu: 1350,
+        }
+    }
+
+    /// Ignores congestion and resends eagerly. For links with heavy random loss.
+    pub fn turbo() -> Self {
+        Self {
+            window_size: 1024,
+            interval: Duration::from_millis(10),
+            min_rto: Duration::from_millis(30),
+            fast_resend: 2,
+            congestion_control: false,
+            mtu: 1350,
+        }
+    }
+
+    /// Sets the max datagram size, headers included, clamped to what a
+    /// segment header and a UDP datagram allow.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu.clamp(MIN_MTU, MAX_MTU);
+        self
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn mss(&self) -> usize {
+        self.mtu - HEADER_LEN
+    }
+}
+
+impl Default for KcpConfig {
+    fn default() -> Self {
+        Self::normal()
+    }
+}
+
+/// Reliable, ordered byte stream over UDP with its own retransmission
+/// and congestion control (modelled after KCP).
+pub struct KcpTransport {
+    config: KcpConfig,
+    fec: Option<FecConfig>,
+}
+
+impl KcpTransport {
+    pub fn new() -> Self {
+        Self::with_config(KcpConfig::default())
+    }
+
+    pub fn with_config(config: KcpConfig) -> Self {
+        Self { config, fec: None }
+    }
+
+    /// Sends parity along with the segments, so lost ones are rebuilt
+    /// instead of waiting out a retransmission. Sessions always decode
+    /// FEC and answer a peer that uses it in kind; this turns it on from
+    /// the start (and sets the redundancy).
+    pub fn with_fec(mut self, fec: FecConfig) -> Self {
+        self.fec = Some(fec);
+        self
+    }
+}
+
+impl Default for KcpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for KcpTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let bind_addr: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
+        tokio::spawn(client_reader(socket.clone(), addr, datagram_tx));
+
+        let conv = rand::random::<u32>();
+        let kcp = Kcp::new(conv, self.config.clone());
+        let fec = SessionFec::new(conv, self.fec.as_ref());
+        Ok(Box::new(spawn_session(kcp, fec, Route::direct(socket, addr), datagram_rx)))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let (accept_tx, accept_rx) = mpsc::channel(100);
+        tokio::spawn(server_demux(socket, self.config.clone(), self.fec.clone(), accept_tx));
+        Ok(Box::new(KcpListener { rx: accept_rx }))
+    }
+
+    fn name(&self) -> &str {
+        "KCP"
+    }
+}
+
+struct KcpConnection {
+    // Dropping the sender tells the session to send FIN once the queue drains
+    tx: Option<mpsc::Sender<Bytes>>,
+    rx: mpsc::Receiver<Result<Option<Bytes>>>,
+}
+
+#[async_trait]
+impl super::Connection for KcpConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| anyhow!("KCP connection closed"))?;
+        tx.send(data).await.map_err(|_| anyhow!("KCP session terminated"))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        // A finished session without an explicit error is a clean EOF
+        Ok(self.rx.recv().await.transpose()?.flatten())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        (Box::new(ChannelRecvHalf::new(self.rx)), Box::new(ChannelSendHalf::new(self.tx)))
+    }
+}
+
+struct KcpListener {
+    rx: mpsc::Receiver<(KcpConnection, SocketAddr)>,
+}
+
+#[async_trait]
+impl super::Listener for KcpListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await.ok_or_else(|| anyhow!("KCP socket closed"))?;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Forwards datagrams from the server to the client session.
+async fn client_reader(socket: Arc<UdpSocket>, peer: SocketAddr, tx: mpsc::Sender<Bytes>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok((n, from)) if from == peer => {
+                    // Drop on overflow, like the network would
+                    let _ = tx.try_send(Bytes::copy_from_slice(&buf[..n]));
+                }
+                Ok(_) => {} // Stray datagram
+                Err(_) => break,
+            },
+            _ = tx.closed() => break,
+        }
+    }
+}
+
+type SessionMap = Arc<Mutex<HashMap<(SocketAddr, u32), mpsc::Sender<Bytes>>>>;
+
+/// Routes datagrams on the shared server socket to sessions by (peer, conv),
+/// creating a session when a peer sends its first segment.
+async fn server_demux(
+    socket: Arc<UdpSocket>,
+    config: KcpConfig,
+    fec: Option<FecConfig>,
+    accept_tx: mpsc::Sender<(KcpConnection, SocketAddr)>,
+) {
+    let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, peer) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(received) => received,
+                Err(_) => continue, // e.g. ICMP errors surfaced on the socket
+            },
+            _ = accept_tx.closed() => break,
+        };
+        let datagram = &buf[..n];
+        if datagram.len() < HEADER_LEN {
+            continue;
+        }
+        let conv = (&datagram[..4]).get_u32();
+        let key = (peer, conv);
+
+        let existing = sessions.lock().unwrap().get(&key).cloned();
+        if let Some(tx) = existing {
+            let _ = tx.try_send(Bytes::copy_from_slice(datagram));
+            continue;
+        }
+        if !opens_session(datagram) {
+            continue;
+        }
+
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
+        let _ = datagram_tx.try_send(Bytes::copy_from_slice(datagram));
+        sessions.lock().unwrap().insert(key, datagram_tx);
+
+        let session_fec = SessionFec::new(conv, fec.as_ref());
+        let route = Route::direct(socket.clone(), peer);
+        let (connection, driver) = session(Kcp::new(conv, config.clone()), session_fec, route, datagram_rx);
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            driver.await;
+            sessions.lock().unwrap().remove(&key);
+        });
+        if accept_tx.send((connection, peer)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Only the first data segment of a conversation may create a session,
+/// so stray acks or late retransmissions do not spawn ghosts.
+pub(crate) fn opens_session(mut datagram: &[u8]) -> bool {
+    if datagram[4] == fec::KIND_DATA {
+        // The segments it carries start after the FEC header
+        return datagram.len() >= 4 + fec::HEADER_LEN + HEADER_LEN && opens_session(&datagram[4 + fec::HEADER_LEN..]);
+    }
+    datagram.advance(4);
+    let cmd = datagram.get_u8();
+    datagram.advance(2 + 4);
+    let sn = datagram.get_u32();
+    cmd == CMD_PUSH && sn == 0
+}
+
+/// Where a session's datagrams go: straight to the peer, or behind a
+/// header to a relay that forwards them there.
+pub(crate) struct Route {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    header: Option<Bytes>,
+}
+
+impl Route {
+    pub(crate) fn direct(socket: Arc<UdpSocket>, peer: SocketAddr) -> Self {
+        Self { socket, peer, header: None }
+    }
+
+    pub(crate) fn relayed(socket: Arc<UdpSocket>, relay: SocketAddr, header: Bytes) -> Self {
+        Self { socket, peer: relay, header: Some(header) }
+    }
+
+    async fn send(&self, datagram: &[u8]) {
+        let _ = match &self.header {
+            Some(header) => self.socket.send_to(&[&header[..], datagram].concat(), self.peer).await,
+            None => self.socket.send_to(datagram, self.peer).await,
+        };
+    }
+}
+
+/// Runs a session of conversation `conv` over a path set up elsewhere
+/// (e.g. a punched hole), fed the datagrams that arrive on it.
+pub(crate) fn open_session(
+    conv: u32,
+    config: KcpConfig,
+    fec: Option<&FecConfig>,
+    route: Route,
+    incoming: mpsc::Receiver<Bytes>,
+) -> Box<dyn super::Connection> {
+    Box::new(spawn_session(Kcp::new(conv, config), SessionFec::new(conv, fec), route, incoming))
+}
+
+fn spawn_session(
+    kcp: Kcp,
+    fec: SessionFec,
+    route: Route,
+    incoming: mpsc::Receiver<Bytes>,
+) -> KcpConnection {
+    let (connection, driver) = session(kcp, fec, route, incoming);
+    tokio::spawn(driver);
+    connection
+}
+
+fn session(
+    kcp: Kcp,
+    fec: SessionFec,
+    route: Route,
+    incoming: mpsc::Receiver<Bytes>,
+) -> (KcpConnection, impl std::future::Future<Output = ()>) {
+    let (app_tx, app_rx) = mpsc::channel(64);
+    let (deliver_tx, deliver_rx) = mpsc::channel(64);
+    let connection = KcpConnection { tx: Some(app_tx), rx: deliver_rx };
+    (connection, drive(kcp, fec, route, incoming, app_rx, deliver_tx))
+}
+
+/// Runs one session: feeds datagrams and application data into the state
+/// machine and puts whatever it produces on the wire.
+async fn drive(
+    mut kcp: Kcp,
+    mut fec: SessionFec,
+    route: Route,
+    mut incoming: mpsc::Receiver<Bytes>,
+    mut app_rx: mpsc::Receiver<Bytes>,
+    deliver_tx: mpsc::Sender<Result<Option<Bytes>>>,
+) {
+    let mut ticker = tokio::time::interval(kcp.config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut app_open = true;
+    let send_limit = kcp.config.window_size as usize * 2;
+    let mut ticked = false;
+
+    loop {
+        tokio::select! {
+            Some(datagram) = incoming.recv() => fec.input(&mut kcp, &datagram),
+            data = app_rx.recv(), if app_open && kcp.snd_queue.len() < send_limit => match data {
+                Some(data) => kcp.send(data),
+                None => {
+                    app_open = false;
+                    kcp.send_fin();
+                }
+            },
+            permit = deliver_tx.reserve(), if !kcp.rcv_queue.is_empty() => match permit {
+                Ok(permit) => permit.send(Ok(kcp.rcv_queue.pop_front().unwrap())),
+                // Reader is gone; keep acking but stop buffering
+                Err(_) => kcp.rcv_queue.clear(),
+            },
+            _ = ticker.tick() => ticked = true,
+        }
+
+        let now = Instant::now();
+        let mut datagrams = fec.output(kcp.flush(now));
+        if std::mem::take(&mut ticked) {
+            datagrams.extend(fec.flush());
+        }
+        for datagram in datagrams {
+            route.send(&datagram).await;
+        }
+
+        if kcp.dead || now.duration_since(kcp.last_recv) > IDLE_TIMEOUT {
+            let _ = deliver_tx.try_send(Err(anyhow!("KCP link to {} is dead", route.peer)));
+            return;
+        }
+        let local_done = !app_open && kcp.snd_queue.is_empty() && kcp.snd_buf.is_empty();
+        let remote_done = (kcp.fin_received && kcp.rcv_queue.is_empty()) || deliver_tx.is_closed();
+        if local_done && remote_done {
+            break;
+        }
+    }
+
+    // Keep acking for a moment in case our ack of the peer's FIN was lost
+    let linger = tokio::time::sleep(LINGER);
+    tokio::pin!(linger);
+    loop {
+        tokio::select! {
+            Some(datagram) = incoming.recv() => {
+                fec.input(&mut kcp, &datagram);
+                let mut datagrams = fec.output(kcp.flush(Instant::now()));
+                datagrams.extend(fec.flush());
+                for datagram in datagrams {
+                    route.send(&datagram).await;
+                }
+            }
+            _ = &mut linger => break,
+        }
+    }
+}
+
+/// A session's FEC: always ready to decode, sending once configured to
+/// or once the peer does.
+struct SessionFec {
+    fec: Fec,
+    sending: bool,
+}
+
+impl SessionFec {
+    fn new(conv: u32, config: Option<&FecConfig>) -> Self {
+        // Packets lead with the conversation, like segments, for the demux
+        let header = Bytes::copy_from_slice(&conv.to_be_bytes());
+        Self {
+            fec: Fec::new(config.cloned().unwrap_or_default(), header),
+            sending: config.is_some(),
+        }
+    }
+
+    /// Feeds a datagram into `kcp`, unwrapping it first if it came with FEC.
+    fn input(&mut self, kcp: &mut Kcp, datagram: &[u8]) {
+        if datagram.len() > 4 && fec::is_fec(datagram[4]) {
+            self.sending = true;
+            for datagram in self.fec.decode(&datagram[4..]) {
+                // Malformed or foreign datagrams are dropped
+                let _ = kcp.input(&datagram);
+            }
+        } else {
+            let _ = kcp.input(datagram);
+        }
+    }
+
+    /// Wraps outgoing datagrams, with parity for every group they fill.
+    fn output(&mut self, datagrams: Vec<Bytes>) -> Vec<Bytes> {
+        if !self.sending {
+            return datagrams;
+        }
+        datagrams.iter().flat_map(|datagram| self.fec.encode(datagram)).collect()
+    }
+
+    /// Parity for the datagrams of a group that did not fill up.
+    fn flush(&mut self) -> Vec<Bytes> {
+        if !self.sending {
+            return Vec::new();
+        }
+        self.fec.flush()
+    }
+}
+
+/// `a` comes before `b` in wrapping sequence space.
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+struct Segment {
+    cmd: u8,
+    sn: u32,
+    data: Bytes,
+    xmit: u32,
+    resend_at: Instant,
+    rto: Duration,
+    fastack: u32,
+}
+
+/// Protocol state of one session. It does no I/O: the driver feeds it
+/// datagrams and clock ticks and sends out the datagrams it produces.
+struct Kcp {
+    conv: u32,
+    config: KcpConfig,
+    epoch: Instant,
+
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    snd_queue: VecDeque<(u8, Bytes)>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: HashMap<u32, (u8, Bytes)>,
+    // Ordered data waiting for the reader; `None` marks the peer's FIN
+    rcv_queue: VecDeque<Option<Bytes>>,
+    fin_received: bool,
+    acklist: Vec<(u32, u32)>,
+
+    rmt_wnd: u16,
+    cwnd: u32,
+    ssthresh: u32,
+    cwnd_acc: u32,
+
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+
+    last_recv: Instant,
+    last_send: Instant,
+    dead: bool,
+}
+
+impl Kcp {
+    fn new(conv: u32, config: KcpConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            conv,
+            rmt_wnd: config.window_size,
+            config,
+            epoch: now,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            snd_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: HashMap::new(),
+            rcv_queue: VecDeque::new(),
+            fin_received: false,
+            acklist: Vec::new(),
+            cwnd: 2,
+            ssthresh: 32,
+            cwnd_acc: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: RTO_INITIAL,
+            last_recv: now,
+            last_send: now,
+            dead: false,
+        }
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_millis() as u32
+    }
+
+    /// Queues application data, split into MSS-sized segments.
+    fn send(&mut self, mut data: Bytes) {
+        let mss = self.config.mss();
+        while !data.is_empty() {
+            let chunk = data.split_to(mss.min(data.len()));
+            self.snd_queue.push_back((CMD_PUSH, chunk));
+        }
+    }
+
+    fn send_fin(&mut self) {
+        self.snd_queue.push_back((CMD_FIN, Bytes::new()));
+    }
+
+    /// Processes one datagram (possibly several segments) from the peer.
+    fn input(&mut self, mut data: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        let prev_una = self.snd_una;
+        let mut max_ack: Option<u32> = None;
+
+        while data.len() >= HEADER_LEN {
+            let conv = data.get_u32();
+            let cmd = data.get_u8();
+            let wnd = data.get_u16();
+            let ts = data.get_u32();
+            let sn = data.get_u32();
+            let una = data.get_u32();
+            let len = data.get_u16() as usize;
+            if conv != self.conv || data.len() < len {
+                return Err(anyhow!("Malformed KCP segment"));
+            }
+            let payload = Bytes::copy_from_slice(&data[..len]);
+            data.advance(len);
+
+            self.last_recv = now;
+            self.rmt_wnd = wnd;
+            while self.snd_buf.front().is_some_and(|seg| seq_before(seg.sn, una)) {
+                self.snd_buf.pop_front();
+            }
+
+            match cmd {
+                CMD_ACK => {
+                    let rtt = self.timestamp(now).wrapping_sub(ts);
+                    if (rtt as i32) >= 0 {
+                        self.update_rtt(Duration::from_millis(rtt as u64));
+                    }
+                    if let Some(pos) = self.snd_buf.iter().position(|seg| seg.sn == sn) {
+                        self.snd_buf.remove(pos);
+                    }
+                    if max_ack.is_none_or(|max| seq_before(max, sn)) {
+                        max_ack = Some(sn);
+                    }
+                }
+                CMD_PUSH | CMD_FIN => {
+                    self.acklist.push((sn, ts));
+                    if sn.wrapping_sub(self.rcv_nxt) < self.config.window_size as u32 {
+                        self.rcv_buf.entry(sn).or_insert((cmd, payload));
+                    }
+                }
+                CMD_PING => {}
+                _ => return Err(anyhow!("Unknown KCP command: {}", cmd)),
+            }
+        }
+
+        // Segments skipped over by later acks are candidates for fast resend
+        if let Some(max_ack) = max_ack {
+            for seg in self.snd_buf.iter_mut().filter(|seg| seq_before(seg.sn, max_ack)) {
+                seg.fastack += 1;
+            }
+        }
+
+        self.snd_una = self.snd_buf.front().map_or(self.snd_nxt, |seg| seg.sn);
+        let acked = self.snd_una.wrapping_sub(prev_una);
+        if self.config.congestion_control {
+            for _ in 0..acked {
+                self.grow_cwnd();
+            }
+        }
+
+        while let Some((cmd, payload)) = self.rcv_buf.remove(&self.rcv_nxt) {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            if cmd == CMD_FIN {
+                self.fin_received = true;
+                self.rcv_queue.push_back(None);
+                break;
+            }
+            self.rcv_queue.push_back(Some(payload));
+        }
+        Ok(())
+    }
+
+    /// Slow start below `ssthresh`, additive increase above it.
+    fn grow_cwnd(&mut self) {
+        if self.cwnd >= self.rmt_wnd as u32 {
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1;
+        } else {
+            self.cwnd_acc += 1;
+            if self.cwnd_acc >= self.cwnd {
+                self.cwnd_acc = 0;
+                self.cwnd += 1;
+            }
+        }
+    }
+
+    /// RFC 6298 smoothing.
+    fn update_rtt(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt);
+        let rto = srtt + self.config.interval.max(self.rttvar * 4);
+        self.rto = rto.clamp(self.config.min_rto, RTO_MAX);
+    }
+
+    /// Emits pending acks, new segments within the window and due retransmissions.
+    fn flush(&mut self, now: Instant) -> Vec<Bytes> {
+        let ts = self.timestamp(now);
+        let buffered = self.rcv_buf.len() + self.rcv_queue.len();
+        let wnd = (self.config.window_size as usize).saturating_sub(buffered) as u16;
+        let mut packer = Packer::new(self.config.mtu);
+
+        for (sn, echo_ts) in self.acklist.drain(..) {
+            packer.push(self.conv, CMD_ACK, wnd, echo_ts, sn, self.rcv_nxt, &[]);
+        }
+
+        let mut limit = (self.config.window_size as u32).min((self.rmt_wnd as u32).max(1));
+        if self.config.congestion_control {
+            limit = limit.min(self.cwnd);
+        }
+        while self.snd_nxt.wrapping_sub(self.snd_una) < limit {
+            let Some((cmd, data)) = self.snd_queue.pop_front() else { break };
+            self.snd_buf.push_back(Segment {
+                cmd,
+                sn: self.snd_nxt,
+                data,
+                xmit: 0,
+                resend_at: now,
+                rto: self.rto,
+                fastack: 0,
+            });
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        }
+
+        let mut lost = false;
+        let mut fast_resent = false;
+        for seg in self.snd_buf.iter_mut() {
+            let due = if seg.xmit == 0 {
+                seg.rto = self.rto;
+                true
+            } else if now >= seg.resend_at {
+                seg.rto = (seg.rto * 2).min(RTO_MAX);
+                lost = true;
+                true
+            } else if self.config.fast_resend > 0
+                && seg.fastack >= self.config.fast_resend
+                && seg.xmit < FAST_RESEND_LIMIT
+            {
+                fast_resent = true;
+                true
+            } else {
+                false
+            };
+            if !due {
+                continue;
+            }
+            seg.xmit += 1;
+            seg.fastack = 0;
+            seg.resend_at = now + seg.rto;
+            if seg.xmit > DEAD_LINK {
+                self.dead = true;
+            }
+            packer.push(self.conv, seg.cmd, wnd, ts, seg.sn, self.rcv_nxt, &seg.data);
+        }
+
+        if self.config.congestion_control {
+            if fast_resent {
+                let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+                self.ssthresh = (inflight / 2).max(2);
+                self.cwnd = self.ssthresh + self.config.fast_resend;
+            }
+            if lost {
+                self.ssthresh = (self.cwnd / 2).max(2);
+                self.cwnd = 1;
+            }
+        }
+
+        if packer.is_empty() && now.duration_since(self.last_send) >= PING_INTERVAL {
+            packer.push(self.conv, CMD_PING, wnd, ts, 0, self.rcv_nxt, &[]);
+        }
+        let datagrams = packer.finish();
+        if !datagrams.is_empty() {
+            self.last_send = now;
+        }
+        datagrams
+    }
+}
+
+/// Packs consecutive segments into datagrams of at most `mtu` bytes.
+struct Packer {
+    mtu: usize,
+    current: BytesMut,
+    done: Vec<Bytes>,
+}
+
+impl Packer {
+    fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            current: BytesMut::with_capacity(mtu),
+            done: Vec::new(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push(&mut self, conv: u32, cmd: u8, wnd: u16, ts: u32, sn: u32, una: u32, data: &[u8]) {
+        if !self.current.is_empty() && self.current.len() + HEADER_LEN + data.len() > self.mtu {
+            self.done.push(self.current.split().freeze());
+        }
+        self.current.put_u32(conv);
+        self.current.put_u8(cmd);
+        self.current.put_u16(wnd);
+        self.current.put_u32(ts);
+        self.current.put_u32(sn);
+        self.current.put_u32(una);
+        self.current.put_u16(data.len() as u16);
+        self.current.put_slice(data);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.current.is_empty() && self.done.is_empty()
+    }
+
+    fn finish(mut self) -> Vec<Bytes> {
+        if !self.current.is_empty() {
+            self.done.push(self.current.freeze());
+        }
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// One direction of a lossy datagram path: drops a share of what it is
+    /// given and holds the rest back for up to a few ticks, so datagrams
+    /// arrive out of order.
+    struct LossyLink {
+        rng: StdRng,
+        loss: f64,
+        mtu: usize,
+        queue: Vec<(u32, Bytes)>,
+    }
+
+    impl LossyLink {
+        fn new(seed: u64, loss: f64, mtu: usize) -> Self {
+            Self { rng: StdRng::seed_from_u64(seed), loss, mtu, queue: Vec::new() }
+        }
+
+        fn carry(&mut self, tick: u32, datagrams: Vec<Bytes>, to: &mut Kcp) {
+            for datagram in datagrams {
+                assert!(datagram.len() <= self.mtu, "{}-byte datagram over a {}-byte MTU", datagram.len(), self.mtu);
+                if !self.rng.gen_bool(self.loss) {
+                    let due = tick + self.rng.gen_range(0..4);
+                    self.queue.push((due, datagram));
+                }
+            }
+            let (due, later) = self.queue.drain(..).partition::<Vec<_>, _>(|&(due, _)| due <= tick);
+            self.queue = later;
+            for (_, datagram) in due.into_iter().rev() {
+                to.input(&datagram).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn delivers_in_order_through_loss_and_reordering() {
+        let data: Bytes = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        for config in [KcpConfig::normal(), KcpConfig::fast(), KcpConfig::turbo()] {
+            let config = config.with_mtu(200);
+            let mut sender = Kcp::new(7, config.clone());
+            let mut receiver = Kcp::new(7, config.clone());
+            sender.send(data.clone());
+            sender.send_fin();
+            let mut forward = LossyLink::new(1, 0.2, config.mtu());
+            let mut back = LossyLink::new(2, 0.2, config.mtu());
+
+            // Ticks of the sessions' own clock, run as fast as they go
+            let start = Instant::now();
+            let mut received = BytesMut::new();
+            let mut finished = false;
+            for tick in 0..20_000 {
+                let now = start + config.interval * tick;
+                forward.carry(tick, sender.flush(now), &mut receiver);
+                back.carry(tick, receiver.flush(now), &mut sender);
+                while let Some(record) = receiver.rcv_queue.pop_front() {
+                    match record {
+                        Some(record) => received.extend_from_slice(&record),
+                        None => finished = true,
+                    }
+                }
+                if finished {
+                    break;
+                }
+            }
+            assert!(finished, "no FIN with {:?}; {} of {} bytes arrived", config, received.len(), data.len());
+            assert_eq!(received, data);
+            assert!(!sender.dead && !receiver.dead);
+        }
+    }
+
+    #[test]
+    fn mtu_is_clamped() {
+        assert_eq!(KcpConfig::normal().with_mtu(0).mtu(), MIN_MTU);
+        assert_eq!(KcpConfig::normal().with_mtu(HEADER_LEN).mtu(), MIN_MTU);
+        assert_eq!(KcpConfig::normal().with_mtu(500).mtu(), 500);
+        assert_eq!(KcpConfig::normal().with_mtu(usize::MAX).mtu(), MAX_MTU);
+
+        // Segments split to fit even the smallest
+        let config = KcpConfig::normal().with_mtu(0);
+        let mut kcp = Kcp::new(1, config.clone());
+        kcp.send(Bytes::from(vec![0u8; 1000]));
+        let datagrams = kcp.flush(Instant::now());
+        assert!(!datagrams.is_empty());
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MIN_MTU));
+    }
+}
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use anyhow::Result;
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 /// The core trait that all transport mechanisms must implement.
 /// This allows the protocol to switch between TCP, UDP, Websockets, etc.
@@ -9,26 +9,176 @@ use std::net::SocketAddr;
 #[async_trait]
 pub trait Transport: Send + Sync {
     /// Connect to a remote endpoint.
-    async fn connect(&self, addr: SocketAddr) -> Result<Box<dyn Connection>>;
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn Connection>>;
 
     /// Listen for incoming connections.
-    async fn listen(&self, addr: SocketAddr) -> Result<Box<dyn Listener>>;
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn Listener>>;
 
     /// valid traffic mimicry type (e.g. "TLS", "HTTP", "Random")
     fn name(&self) -> &str;
+
+    /// Whether the handshake on top should be disguised with a mimic.
+    /// Transports whose framing already looks like ordinary traffic
+    /// (e.g. HTTP/2) opt out, since nesting HTTP/1.1 inside them is a tell.
+    fn wants_mimic(&self) -> bool {
+        true
+    }
+}
+
+/// Lets one transport instance (and its pooled state) be shared.
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn Connection>> {
+        (**self).connect(endpoint).await
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn Listener>> {
+        (**self).listen(endpoint).await
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn wants_mimic(&self) -> bool {
+        (**self).wants_mimic()
+    }
 }
 
+/// Lets boxed transports be wrapped (e.g. by `FaultTransport`).
 #[async_trait]
-pub trait Connection: Send + Sync {
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn Connection>> {
+        (**self).connect(endpoint).await
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn Listener>> {
+        (**self).listen(endpoint).await
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn wants_mimic(&self) -> bool {
+        (**self).wants_mimic()
+    }
+}
+
+#[async_trait]
+pub trait Connection: Send + Sync + IntoConnection {
     async fn send(&mut self, data: Bytes) -> Result<()>;
+
+    /// Sends several chunks back to back, in one write where the transport
+    /// can gather them. By default each goes out as its own `send`.
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        for chunk in data {
+            self.send(chunk.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>>;
+    async fn close(&mut self) -> Result<()>;
+
+    /// Lets the connection be reset rather than closed, for transports
+    /// that can tell the two apart on the wire. Taken before `split`, the
+    /// handle still works on the halves.
+    fn reset_handle(&self) -> Option<ResetHandle> {
+        None
+    }
+
+    /// Splits the connection into halves that can be used from separate
+    /// tasks, so a slow send does not hold up reading. Connections that
+    /// cannot be split themselves get a task that serves both halves.
+    fn split(self: Box<Self>) -> (Box<dyn RecvHalf>, Box<dyn SendHalf>) {
+        split::serve(self.into_connection())
+    }
+}
+
+/// Makes a connection end with a reset (a TCP RST) instead of an orderly
+/// close, see `Connection::reset_handle`.
+pub struct ResetHandle {
+    // Another descriptor for the connection's socket
+    socket: socket2::Socket,
+}
+
+impl ResetHandle {
+    pub(crate) fn new(socket: socket2::Socket) -> Self {
+        Self { socket }
+    }
+
+    /// Takes effect once the connection (or both of its halves) is dropped.
+    pub fn reset(self) {
+        // Closing with a zero linger time discards unsent data and sends RST
+        let _ = self.socket.set_linger(Some(std::time::Duration::ZERO));
+    }
+}
+
+/// Receiving half of a split `Connection`.
+#[async_trait]
+pub trait RecvHalf: Send + Sync {
     async fn recv(&mut self) -> Result<Option<Bytes>>;
+}
+
+/// Sending half of a split `Connection`. Closing it closes the sending
+/// direction; the other one stays open until the peer closes it.
+#[async_trait]
+pub trait SendHalf: Send + Sync {
+    async fn send(&mut self, data: Bytes) -> Result<()>;
+
+    /// As `Connection::send_vectored`.
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        for chunk in data {
+            self.send(chunk.clone()).await?;
+        }
+        Ok(())
+    }
+
     async fn close(&mut self) -> Result<()>;
 }
 
+/// Turns a connection back into a trait object, which the default
+/// `Connection::split` needs. Every connection has it.
+pub trait IntoConnection {
+    fn into_connection(self: Box<Self>) -> Box<dyn Connection>;
+}
+
+impl<T: Connection + 'static> IntoConnection for T {
+    fn into_connection(self: Box<Self>) -> Box<dyn Connection> {
+        self
+    }
+}
+
 #[async_trait]
 pub trait Listener: Send + Sync {
-    async fn accept(&mut self) -> Result<(Box<dyn Connection>, SocketAddr)>;
+    /// Returns the next connection and the peer it came from.
+    async fn accept(&mut self) -> Result<(Box<dyn Connection>, Endpoint)>;
 }
 
+pub use endpoint::Endpoint;
+pub use split::{ChannelRecvHalf, ChannelSendHalf};
+
+pub mod endpoint;
 pub mod tcp;
-pub mod blocked;
+pub mod quic;
+pub mod kcp;
+pub mod fec;
+pub mod punch;
+pub mod websocket;
+pub mod tls;
+pub mod ssh;
+pub mod shadowsocks;
+pub mod http2;
+pub mod meek;
+pub mod dns;
+pub mod memory;
+pub mod fault;
+pub mod hopping;
+pub mod upstream;
+pub mod proxy_protocol;
+pub mod registry;
+#[cfg(unix)]
+pub mod unix;
+mod polling;
+mod split;
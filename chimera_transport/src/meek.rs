@@ -0,0 +1,511 @@
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::tcp::{TcpOptions, TcpTransport};
+use crate::upstream::UpstreamProxy;
+use crate::polling::{self, PollConfig, Reply, RoundTrip, SessionTable, SESSION_TIMEOUT};
+
+const SESSION_HEADER: &str = "x-session-id";
+const SEQ_HEADER: &str = "x-request-seq";
+const CLOSE_HEADER: &str = "x-session-close";
+
+/// A round trip that takes longer than this counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A failed round trip is retried this many times (with the same sequence
+/// number, so the server can replay its answer) before the session dies.
+const MAX_RETRIES: u32 = 3;
+/// How long the server holds a request open waiting for downstream data.
+const RESPONSE_HOLD: Duration = Duration::from_millis(100);
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Tunnels over a sequence of short HTTP/1.1 POSTs, for networks that only
+/// let request/response exchanges through (meek-style).
+///
+/// Each request carries pending upstream bytes and each response whatever
+/// the server has queued downstream (see `polling`). A session ID header
+/// ties the requests of one tunnel together.
+pub struct MeekTransport {
+    path: String,
+    host: Option<String>,
+    forward_proxy: Option<SocketAddr>,
+    min_poll: Duration,
+    max_poll: Duration,
+    max_body: usize,
+    proxy_protocol: Option<TrustedProxies>,
+    forwarded_for: Option<TrustedProxies>,
+    tcp: TcpTransport,
+}
+
+impl MeekTransport {
+    pub fn new() -> Self {
+        Self {
+            path: "/".to_string(),
+            host: None,
+            forward_proxy: None,
+            min_poll: Duration::from_millis(50),
+            max_poll: Duration::from_secs(5),
+            max_body: 64 * 1024,
+            proxy_protocol: None,
+            forwarded_for: None,
+            tcp: TcpTransport::new(),
+        }
+    }
+
+    /// Request path; the server answers 404 to anything else.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Host header sent by the client (e.g. the fronted domain).
+    /// Defaults to the address being dialed.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Sends every request through this HTTP forwarding proxy
+    /// (absolute-form request target) instead of dialing the server.
+    pub fn with_forward_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.forward_proxy = Some(proxy);
+        self
+    }
+
+    /// Socket settings of the client's connections (see `TcpOptions`).
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Dials through `proxy` (see `TcpTransport::with_upstream_proxy`),
+    /// to the forwarding proxy if there is one.
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.tcp = self.tcp.with_upstream_proxy(proxy);
+        self
+    }
+
+    /// Bounds of the adaptive polling interval of an idle client.
+    pub fn with_poll_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.min_poll = min;
+        self.max_poll = max.max(min);
+        self
+    }
+
+    /// Largest body put in a single request or response.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body.clamp(1, MAX_BODY);
+        self
+    }
+
+    /// Expects a PROXY protocol header from `trusted` relays (see
+    /// `TcpTransport::with_proxy_protocol`).
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.proxy_protocol = Some(trusted);
+        self
+    }
+
+    /// Takes the client from `X-Forwarded-For` on requests that come
+    /// through `trusted` reverse proxies (e.g. a CDN).
+    pub fn with_forwarded_for(mut self, trusted: TrustedProxies) -> Self {
+        self.forwarded_for = Some(trusted);
+        self
+    }
+}
+
+impl Default for MeekTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for MeekTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let host = self.host.clone().unwrap_or_else(|| addr.to_string());
+        let target = match self.forward_proxy {
+            Some(_) => format!("http://{}{}", host, self.path),
+            None => self.path.clone(),
+        };
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let session = ClientSession {
+            http: HttpClient::new(self.tcp.clone(), self.forward_proxy.unwrap_or(addr)),
+            target,
+            host,
+            id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+            seq: 0,
+        };
+        let config = PollConfig {
+            min_poll: self.min_poll,
+            max_poll: self.max_poll,
+            max_body: self.max_body,
+        };
+        Ok(Box::new(polling::connect(session, config, "Meek").await?))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let listener = TcpListener::bind(addr).await?;
+        let (sessions, polling_listener) = SessionTable::new(RESPONSE_HOLD, "Meek");
+        let server = Arc::new(MeekServer {
+            path: self.path.clone(),
+            max_body: self.max_body,
+            sessions,
+            proxy_protocol: self.proxy_protocol.clone(),
+            forwarded_for: self.forwarded_for.clone(),
+        });
+        tokio::spawn(accept_loop(listener, server));
+        Ok(Box::new(polling_listener))
+    }
+
+    fn name(&self) -> &str {
+        "Meek"
+    }
+}
+
+/// Client side of one session: numbers the requests and retries failed ones.
+struct ClientSession {
+    http: HttpClient,
+    target: String,
+    host: String,
+    id: String,
+    seq: u64,
+}
+
+#[async_trait]
+impl RoundTrip for ClientSession {
+    async fn round_trip(&mut self, body: Bytes, close: bool) -> Result<Reply> {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nX-Session-Id: {}\r\nX-Request-Seq: {}\r\n",
+            self.target, self.host, body.len(), self.id, self.seq,
+        );
+        if close {
+            request.push_str("X-Session-Close: 1\r\n");
+        }
+        request.push_str("\r\n");
+        let mut request = BytesMut::from(request.as_bytes());
+        request.extend_from_slice(&body);
+
+        let mut last_error = anyhow!("Meek request failed");
+        for attempt in 0..=MAX_RETRIES {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_millis(250) * attempt).await;
+            }
+            match tokio::time::timeout(REQUEST_TIMEOUT, self.http.round_trip(&request)).await {
+                Ok(Ok(response)) => {
+                    if response.status != 200 {
+                        return Err(anyhow!("Meek server answered {}", response.status));
+                    }
+                    self.seq += 1;
+                    let closed = response.headers.contains_key(CLOSE_HEADER);
+                    return Ok(Reply { body: response.body, closed });
+                }
+                Ok(Err(e)) => last_error = e,
+                Err(_) => last_error = anyhow!("Meek request timed out"),
+            }
+            self.http.reset();
+        }
+        Err(last_error)
+    }
+}
+
+/// Minimal keep-alive HTTP/1.1 client; reconnects after any failure.
+struct HttpClient {
+    tcp: TcpTransport,
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    buf: BytesMut,
+}
+
+impl HttpClient {
+    fn new(tcp: TcpTransport, addr: SocketAddr) -> Self {
+        Self { tcp, addr, stream: None, buf: BytesMut::new() }
+    }
+
+    fn reset(&mut self) {
+        self.stream = None;
+        self.buf.clear();
+    }
+
+    async fn round_trip(&mut self, request: &[u8]) -> Result<Response> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.tcp.connect_stream(self.addr).await?),
+        };
+        stream.write_all(request).await?;
+        let response = read_response(stream, &mut self.buf).await?;
+        if response.headers.get("connection").is_some_and(|c| c.eq_ignore_ascii_case("close")) {
+            self.reset();
+        }
+        Ok(response)
+    }
+}
+
+struct MeekServer {
+    path: String,
+    max_body: usize,
+    sessions: SessionTable<String>,
+    proxy_protocol: Option<TrustedProxies>,
+    forwarded_for: Option<TrustedProxies>,
+}
+
+impl MeekServer {
+    async fn handle(&self, request: Request, addr: SocketAddr) -> Option<Reply> {
+        if request.method != "POST" || origin_path(&request.path) != self.path {
+            return None;
+        }
+        let id = request.headers.get(SESSION_HEADER).filter(|id| valid_session_id(id))?;
+        let seq = request.headers.get(SEQ_HEADER)?.parse::<u64>().ok()?;
+        let close = request.headers.contains_key(CLOSE_HEADER);
+        self.sessions.exchange(id.clone(), seq, request.body, close, self.max_body, addr).await
+    }
+}
+
+/// Path of an origin- or absolute-form request target.
+fn origin_path(target: &str) -> &str {
+    match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => target,
+    }
+}
+
+/// Session IDs are chosen by the client; keep them short and printable.
+fn valid_session_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+async fn accept_loop(listener: TcpListener, server: Arc<MeekServer>) {
+    let mut expiry = tokio::time::interval(SESSION_TIMEOUT / 4);
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                // Accept errors are transient (e.g. out of file descriptors)
+                if let Ok((stream, addr)) = res {
+                    tokio::spawn(serve(stream, addr, server.clone()));
+                }
+            }
+            _ = expiry.tick() => server.sessions.expire(),
+            _ = server.sessions.closed() => break,
+        }
+    }
+}
+
+/// Serves keep-alive requests on one TCP connection.
+async fn serve(mut stream: TcpStream, addr: SocketAddr, server: Arc<MeekServer>) {
+    let Ok(addr) = proxy_protocol::accept_header(&mut stream, addr, server.proxy_protocol.as_ref()).await else {
+        return;
+    };
+    let mut buf = BytesMut::new();
+    loop {
+        let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream, &mut buf)).await {
+            Ok(Ok(Some(request))) => request,
+            _ => return,
+        };
+        let keep_alive = !request.headers.get("connection").is_some_and(|c| c.eq_ignore_ascii_case("close"));
+
+        // A CDN may carry requests of several clients on one connection
+        let forwarded = request.headers.get("x-forwarded-for").map(String::as_str);
+        let client = proxy_protocol::forwarded_for(forwarded, addr, server.forwarded_for.as_ref());
+
+        // Anything that is not a valid tunnel request looks like a missing page
+        let reply = server.handle(request, client).await;
+        let (status, reason) = match reply {
+            Some(_) => (200, "OK"),
+            None => (404, "Not Found"),
+        };
+        let body = reply.as_ref().map(|r| r.body.clone()).unwrap_or_default();
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-store\r\n",
+            status, reason, body.len(),
+        );
+        if reply.is_some_and(|r| r.closed) {
+            response.push_str("X-Session-Close: 1\r\n");
+        }
+        if !keep_alive {
+            response.push_str("Connection: close\r\n");
+        }
+        response.push_str("\r\n");
+        let mut response = BytesMut::from(response.as_bytes());
+        response.extend_from_slice(&body);
+        if stream.write_all(&response).await.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Bytes,
+}
+
+struct Response {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Bytes,
+}
+
+/// Reads one request; `None` on a clean EOF between requests.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<Option<Request>> {
+    if !read_head(stream, buf).await? {
+        return Ok(None);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let httparse::Status::Complete(head_len) = parsed.parse(buf)? else {
+        return Err(anyhow!("Incomplete HTTP request head"));
+    };
+    let method = parsed.method.unwrap_or_default().to_string();
+    let path = parsed.path.unwrap_or_default().to_string();
+    let headers = collect_headers(parsed.headers);
+    buf.advance(head_len);
+
+    let body = read_body(stream, buf, &headers).await?;
+    Ok(Some(Request { method, path, headers, body }))
+}
+
+async fn read_response<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<Response> {
+    if !read_head(stream, buf).await? {
+        return Err(anyhow!("Connection closed before HTTP response"));
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(head_len) = parsed.parse(buf)? else {
+        return Err(anyhow!("Incomplete HTTP response head"));
+    };
+    let status = parsed.code.unwrap_or_default();
+    let headers = collect_headers(parsed.headers);
+    buf.advance(head_len);
+
+    let body = read_body(stream, buf, &headers).await?;
+    Ok(Response { status, headers, body })
+}
+
+/// Headers by lowercased name. A repeated header is read as one
+/// comma-separated list, in order (e.g. `X-Forwarded-For` lines added by
+/// successive relays).
+fn collect_headers(headers: &[httparse::Header]) -> HashMap<String, String> {
+    let mut collected: HashMap<String, String> = HashMap::new();
+    for header in headers {
+        let value = String::from_utf8_lossy(header.value);
+        collected.entry(header.name.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    collected
+}
+
+/// Buffers a complete message head. Returns false on EOF before any byte.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<bool> {
+    loop {
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(true);
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(anyhow!("HTTP head too large"));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            return Err(anyhow!("Connection closed inside HTTP head"));
+        }
+    }
+}
+
+/// Reads a Content-Length or chunked body; forwarding proxies may use either.
+async fn read_body<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut, headers: &HashMap<String, String>) -> Result<Bytes> {
+    let chunked = headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().trim_end().ends_with("chunked"));
+    if !chunked {
+        let len: usize = match headers.get("content-length") {
+            Some(len) => len.trim().parse()?,
+            None => 0,
+        };
+        if len > MAX_BODY {
+            return Err(anyhow!("HTTP body too large"));
+        }
+        fill(stream, buf, len).await?;
+        return Ok(buf.split_to(len).freeze());
+    }
+
+    let mut body = BytesMut::new();
+    loop {
+        let line = read_line(stream, buf).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| anyhow!("Bad chunk size"))?;
+        if size == 0 {
+            // Skip trailers
+            while !read_line(stream, buf).await?.is_empty() {}
+            return Ok(body.freeze());
+        }
+        if body.len() + size > MAX_BODY {
+            return Err(anyhow!("HTTP body too large"));
+        }
+        fill(stream, buf, size + 2).await?;
+        body.extend_from_slice(&buf[..size]);
+        buf.advance(size + 2);
+    }
+}
+
+async fn read_line<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<String> {
+    loop {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(pos);
+            buf.advance(2);
+            return Ok(String::from_utf8_lossy(&line).into_owned());
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(anyhow!("HTTP line too long"));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(anyhow!("Connection closed inside HTTP body"));
+        }
+    }
+}
+
+async fn fill<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut, len: usize) -> Result<()> {
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(anyhow!("Connection closed inside HTTP body"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_headers_are_read_in_order() {
+        let head = b"POST / HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 6.6.6.6\r\nx-forwarded-for: 203.0.113.7\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 8];
+        let mut request = httparse::Request::new(&mut headers);
+        request.parse(head).unwrap();
+        let headers = collect_headers(request.headers);
+        assert_eq!(headers["x-forwarded-for"], "6.6.6.6, 203.0.113.7");
+
+        // The relay's line, the last, is the one that counts
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let client = proxy_protocol::forwarded_for(Some(&headers["x-forwarded-for"]), "10.0.0.1:1".parse().unwrap(), Some(&trusted));
+        assert_eq!(client, "203.0.113.7:0".parse().unwrap());
+    }
+}
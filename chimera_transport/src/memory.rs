@@ -0,0 +1,174 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+use super::Endpoint;
+
+/// Listening in-process endpoints, by name.
+static LISTENERS: LazyLock<Mutex<HashMap<String, mpsc::Sender<DuplexStream>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Connects endpoints within one process through in-memory pipes, for
+/// deterministic tests and for embedding client and server in one program.
+///
+/// Endpoints are `Endpoint::Memory` names, shared by the whole process:
+/// `connect` reaches whichever transport instance `listen`s on the name.
+/// The buffer size bounds how much can be written before the other side
+/// reads (set by the connecting side); the chunk size bounds what a single
+/// `recv` returns, to reproduce the short reads of real sockets.
+pub struct MemoryTransport {
+    buffer_size: usize,
+    chunk_size: usize,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self {
+            buffer_size: 64 * 1024,
+            chunk_size: 64 * 1024,
+        }
+    }
+
+    /// Bytes a pipe holds in each direction before writers wait.
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Largest number of bytes handed out by one `recv`.
+    pub fn with_chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size.max(1);
+        self
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn memory_name(endpoint: &Endpoint) -> Result<&str> {
+    match endpoint {
+        Endpoint::Memory(name) => Ok(name),
+        other => Err(anyhow!("{} is not an in-process endpoint", other)),
+    }
+}
+
+#[async_trait]
+impl super::Transport for MemoryTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Connection>> {
+        let name = memory_name(endpoint)?;
+        let listener = LISTENERS.lock().unwrap().get(name).cloned();
+        let listener = listener.ok_or_else(|| anyhow!("Connection refused: nothing listens on {}", endpoint))?;
+
+        let (local, remote) = tokio::io::duplex(self.buffer_size);
+        listener.send(remote).await
+            .map_err(|_| anyhow!("Connection refused: nothing listens on {}", endpoint))?;
+        Ok(Box::new(MemoryConnection { stream: local, chunk_size: self.chunk_size }))
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Listener>> {
+        let name = memory_name(endpoint)?;
+        let (tx, rx) = mpsc::channel(100);
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners.get(name).is_some_and(|existing| !existing.is_closed()) {
+            return Err(anyhow!("{} is already in use", endpoint));
+        }
+        listeners.insert(name.to_string(), tx.clone());
+        Ok(Box::new(MemoryListener {
+            name: name.to_string(),
+            tx,
+            rx,
+            chunk_size: self.chunk_size,
+        }))
+    }
+
+    fn name(&self) -> &str {
+        "Memory"
+    }
+}
+
+struct MemoryConnection<S = DuplexStream> {
+    stream: S,
+    chunk_size: usize,
+}
+
+#[async_trait]
+impl super::Connection for MemoryConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        super::SendHalf::send(self, data).await
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        super::RecvHalf::recv(self).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        super::SendHalf::close(self).await
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        let (reader, writer) = tokio::io::split(self.stream);
+        let chunk_size = self.chunk_size;
+        (Box::new(MemoryConnection { stream: reader, chunk_size }), Box::new(MemoryConnection { stream: writer, chunk_size }))
+    }
+}
+
+/// A split `MemoryConnection` is two of them, one over each half of the pipe.
+#[async_trait]
+impl<S: AsyncRead + Unpin + Send + Sync> super::RecvHalf for MemoryConnection<S> {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        let mut buf = vec![0u8; self.chunk_size];
+        let n = self.stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok(Some(Bytes::from(buf)))
+    }
+}
+
+#[async_trait]
+impl<S: AsyncWrite + Unpin + Send + Sync> super::SendHalf for MemoryConnection<S> {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.stream.write_all(&data).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+struct MemoryListener {
+    name: String,
+    // Kept to recognise our own registration when unregistering
+    tx: mpsc::Sender<DuplexStream>,
+    rx: mpsc::Receiver<DuplexStream>,
+    chunk_size: usize,
+}
+
+#[async_trait]
+impl super::Listener for MemoryListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, Endpoint)> {
+        let stream = self.rx.recv().await
+            .ok_or_else(|| anyhow!("Memory listener closed"))?;
+        let connection = MemoryConnection { stream, chunk_size: self.chunk_size };
+        Ok((Box::new(connection), Endpoint::Memory(self.name.clone())))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners.get(&self.name).is_some_and(|tx| tx.same_channel(&self.tx)) {
+            listeners.remove(&self.name);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Plumbing shared by the request/response transports (meek, DNS), which
+//! can only move data when the client asks.
+//!
+//! The client numbers its requests and sends them strictly one at a time;
+//! each carries pending upstream bytes, each reply whatever the server has
+//! queued downstream. A request whose reply got lost is retried with the
+//! same number and the server replays its cached reply, so nothing is
+//! duplicated or dropped. An idle client polls with a growing interval and
+//! any traffic snaps it back to the minimum.
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::{ChannelRecvHalf, ChannelSendHalf};
+
+/// Server sessions that see no request for this long are dropped.
+pub(crate) const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What one round trip brought back.
+#[derive(Clone)]
+pub(crate) struct Reply {
+    pub(crate) body: Bytes,
+    pub(crate) closed: bool,
+}
+
+/// One numbered request/response exchange with the server, including
+/// retries. Implementations advance their sequence number on success.
+#[async_trait]
+pub(crate) trait RoundTrip: Send + 'static {
+    async fn round_trip(&mut self, body: Bytes, close: bool) -> Result<Reply>;
+}
+
+pub(crate) struct PollConfig {
+    pub(crate) min_poll: Duration,
+    pub(crate) max_poll: Duration,
+    /// Largest upstream body put in a single request.
+    pub(crate) max_body: usize,
+}
+
+/// Opens a client session: the first (empty) round trip runs before this
+/// returns, so connecting fails right away when the server is unreachable.
+pub(crate) async fn connect<R: RoundTrip>(mut session: R, config: PollConfig, name: &'static str) -> Result<PollingConnection> {
+    let first = session.round_trip(Bytes::new(), false).await?;
+
+    let (up_tx, up_rx) = mpsc::channel(100);
+    let (down_tx, down_rx) = mpsc::channel(100);
+    if !first.body.is_empty() {
+        let _ = down_tx.try_send(Ok(Some(first.body)));
+    }
+    if first.closed {
+        let _ = down_tx.try_send(Ok(None));
+        return Ok(PollingConnection { tx: None, rx: down_rx, name });
+    }
+    tokio::spawn(poll(session, config, up_rx, down_tx));
+    Ok(PollingConnection { tx: Some(up_tx), rx: down_rx, name })
+}
+
+/// Moves data between the client connection and the server, one request
+/// at a time.
+async fn poll<R: RoundTrip>(
+    mut session: R,
+    config: PollConfig,
+    mut up_rx: mpsc::Receiver<Bytes>,
+    down_tx: mpsc::Sender<Result<Option<Bytes>>>,
+) {
+    let mut interval = config.min_poll;
+    let mut pending = BytesMut::new();
+    let mut closing = false;
+
+    loop {
+        // Sleep until the next poll is due, or send right away if there is data
+        if pending.is_empty() && !closing {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                data = up_rx.recv() => match data {
+                    Some(data) => pending.extend_from_slice(&data),
+                    None => closing = true,
+                },
+            }
+        }
+        while !closing && pending.len() < config.max_body {
+            match up_rx.try_recv() {
+                Ok(data) => pending.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => closing = true,
+            }
+        }
+
+        let body = pending.split_to(pending.len().min(config.max_body)).freeze();
+        let close = closing && pending.is_empty();
+        let sent = !body.is_empty();
+        let reply = match session.round_trip(body, close).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                let _ = down_tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let received = !reply.body.is_empty();
+        if received && down_tx.send(Ok(Some(reply.body))).await.is_err() {
+            // Connection dropped: the next request closes the session
+            closing = true;
+        }
+        if reply.closed {
+            let _ = down_tx.send(Ok(None)).await;
+            return;
+        }
+        if close {
+            return;
+        }
+        interval = if sent || received {
+            config.min_poll
+        } else {
+            (interval * 2).min(config.max_poll)
+        };
+    }
+}
+
+pub(crate) struct PollingConnection {
+    // Dropping the sender makes the session send its closing request
+    tx: Option<mpsc::Sender<Bytes>>,
+    rx: mpsc::Receiver<Result<Option<Bytes>>>,
+    // Transport name, for error messages
+    name: &'static str,
+}
+
+#[async_trait]
+impl super::Connection for PollingConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| anyhow!("{} connection closed", self.name))?;
+        tx.send(data).await.map_err(|_| anyhow!("{} session terminated", self.name))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        // A finished session without an explicit error is a clean EOF
+        Ok(self.rx.recv().await.transpose()?.flatten())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        (Box::new(ChannelRecvHalf::new(self.rx)), Box::new(ChannelSendHalf::new(self.tx)))
+    }
+}
+
+pub(crate) struct PollingListener {
+    rx: mpsc::Receiver<(PollingConnection, SocketAddr)>,
+    name: &'static str,
+}
+
+#[async_trait]
+impl super::Listener for PollingListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("{} listener closed", self.name))?;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Server side: routes requests to sessions by ID and hands new sessions
+/// to the listener.
+pub(crate) struct SessionTable<K> {
+    sessions: Mutex<HashMap<K, Arc<ServerSession>>>,
+    tx: mpsc::Sender<(PollingConnection, SocketAddr)>,
+    /// How long a request is held open waiting for downstream data.
+    hold: Duration,
+    name: &'static str,
+}
+
+impl<K: Hash + Eq + Clone> SessionTable<K> {
+    pub(crate) fn new(hold: Duration, name: &'static str) -> (Self, PollingListener) {
+        let (tx, rx) = mpsc::channel(100);
+        let table = Self { sessions: Mutex::new(HashMap::new()), tx, hold, name };
+        (table, PollingListener { rx, name })
+    }
+
+    /// Applies request `seq` of session `id` and returns its reply, with at
+    /// most `max_body` bytes of downstream data. Request 0 of an unknown
+    /// session opens it. `None` means the request must be rejected.
+    pub(crate) async fn exchange(
+        &self,
+        id: K,
+        seq: u64,
+        body: Bytes,
+        close: bool,
+        max_body: usize,
+        addr: SocketAddr,
+    ) -> Option<Reply> {
+        let existing = self.sessions.lock().unwrap().get(&id).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None if seq == 0 => self.open(id.clone(), addr).await?,
+            None => return None,
+        };
+
+        let reply = session.exchange(seq, body, close, max_body, self.hold).await?;
+        if reply.closed {
+            self.sessions.lock().unwrap().remove(&id);
+        }
+        Some(reply)
+    }
+
+    async fn open(&self, id: K, addr: SocketAddr) -> Option<Arc<ServerSession>> {
+        let (up_tx, up_rx) = mpsc::channel(100);
+        let (down_tx, down_rx) = mpsc::channel(100);
+        let session = Arc::new(ServerSession {
+            upstream: up_tx,
+            last_seen: Mutex::new(Instant::now()),
+            state: tokio::sync::Mutex::new(SessionState {
+                downstream: down_rx,
+                pending: BytesMut::new(),
+                next_seq: 0,
+                last_reply: None,
+            }),
+        });
+        self.sessions.lock().unwrap().insert(id.clone(), session.clone());
+
+        let connection = PollingConnection { tx: Some(down_tx), rx: up_rx, name: self.name };
+        if self.tx.send((connection, addr)).await.is_err() {
+            self.sessions.lock().unwrap().remove(&id);
+            return None;
+        }
+        Some(session)
+    }
+
+    /// Drops sessions whose client vanished without closing them.
+    pub(crate) fn expire(&self) {
+        self.sessions.lock().unwrap().retain(|_, session| {
+            let alive = session.last_seen.lock().unwrap().elapsed() < SESSION_TIMEOUT;
+            if !alive {
+                let _ = session.upstream.try_send(Err(anyhow!("{} session timed out", self.name)));
+            }
+            alive
+        });
+    }
+
+    /// Resolves once the listener has been dropped.
+    pub(crate) async fn closed(&self) {
+        self.tx.closed().await
+    }
+}
+
+struct ServerSession {
+    upstream: mpsc::Sender<Result<Option<Bytes>>>,
+    last_seen: Mutex<Instant>,
+    // Requests of one session are handled strictly one after another
+    state: tokio::sync::Mutex<SessionState>,
+}
+
+struct SessionState {
+    downstream: mpsc::Receiver<Bytes>,
+    pending: BytesMut,
+    next_seq: u64,
+    // Replayed if the client retries the last request
+    last_reply: Option<Reply>,
+}
+
+impl ServerSession {
+    /// Applies one request and produces its reply, or `None` if the
+    /// request is out of sequence.
+    async fn exchange(&self, seq: u64, body: Bytes, close: bool, max_body: usize, hold: Duration) -> Option<Reply> {
+        *self.last_seen.lock().unwrap() = Instant::now();
+        let mut state = self.state.lock().await;
+        if seq + 1 == state.next_seq {
+            return state.last_reply.clone();
+        }
+        if seq != state.next_seq {
+            return None;
+        }
+
+        // Only empty polls wait for data; a client that is uploading should
+        // get its next request out as soon as possible
+        let hold = if close || !body.is_empty() { Duration::ZERO } else { hold };
+
+        // If the connection is gone already, the downstream side reports it
+        if !body.is_empty() {
+            let _ = self.upstream.send(Ok(Some(body))).await;
+        }
+        if close {
+            let _ = self.upstream.send(Ok(None)).await;
+        }
+
+        let mut finished = false;
+        if state.pending.is_empty() {
+            match tokio::time::timeout(hold, state.downstream.recv()).await {
+                Ok(Some(data)) => state.pending.extend_from_slice(&data),
+                Ok(None) => finished = true,
+                Err(_) => {}
+            }
+        }
+        while !finished && state.pending.len() < max_body {
+            match state.downstream.try_recv() {
+                Ok(data) => state.pending.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => finished = true,
+            }
+        }
+
+        let len = state.pending.len().min(max_body);
+        let body = state.pending.split_to(len).freeze();
+        let closed = close || (finished && state.pending.is_empty());
+        let reply = Reply { body, closed };
+        state.next_seq += 1;
+        state.last_reply = Some(reply.clone());
+        Some(reply)
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{Result, anyhow};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Relays send their header right after connecting; anything slower is
+/// not one of them.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest v1 header, CRLF included (from the spec).
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Relays (load balancers, reverse proxies) allowed to say which client
+/// they are relaying, by PROXY protocol header or `X-Forwarded-For`.
+///
+/// The textual form is a comma-separated list of addresses and CIDR
+/// ranges, e.g. `10.0.0.0/8,192.168.1.5`.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_net(mut self, net: IpNet) -> Self {
+        self.nets.push(net);
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut trusted = Self::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let net = match item.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => item.parse::<IpAddr>()
+                    .map(IpNet::from)
+                    .map_err(|_| anyhow!("Invalid trusted proxy '{}' (expected an address or CIDR range)", item))?,
+            };
+            trusted = trusted.with_net(net);
+        }
+        Ok(trusted)
+    }
+}
+
+/// The client a connection from `peer` stands for. A trusted peer must
+/// open with a PROXY protocol header (v1 or v2), which is consumed; other
+/// peers are taken as they are and never read from here.
+pub(crate) async fn accept_header<S>(stream: &mut S, peer: SocketAddr, trusted: Option<&TrustedProxies>) -> Result<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    match trusted {
+        Some(trusted) if trusted.contains(peer.ip()) => {
+            tokio::time::timeout(HEADER_TIMEOUT, read_header(stream)).await
+                .map_err(|_| anyhow!("PROXY header from {} timed out", peer))?
+                .map_err(|e| anyhow!("Bad PROXY header from {}: {}", peer, e))
+                // LOCAL (health checks) and unknown families speak for themselves
+                .map(|source| source.unwrap_or(peer))
+        }
+        _ => Ok(peer),
+    }
+}
+
+/// Reads a v1 or v2 header, and nothing past it. Returns the source
+/// address it carries, if any.
+async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions are at least this long ("PROXY UNKNOWN\r\n" is 15)
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(anyhow!("missing"));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&line[..line.len() - 2])?)
+}
+
+/// `PROXY TCP4|TCP6 <src> <dst> <sport> <dport>` or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse()?;
+            let port: u16 = source_port.parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("malformed v1 header")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // [version/command] [family/protocol] [length: 2]
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, ..] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("unsupported version {}", version_command >> 4));
+    }
+    let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+    // Addresses, then TLVs we have no use for
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0F {
+        // LOCAL: the relay's own connection (e.g. a health check)
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => return Err(anyhow!("unknown command {}", command)),
+    }
+    let source = match family >> 4 {
+        // INET: [src: 4] [dst: 4] [src port: 2] [dst port: 2]
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))
+        }
+        // INET6: [src: 16] [dst: 16] [src port: 2] [dst port: 2]
+        0x2 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into()?;
+            SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([body[32], body[33]]))
+        }
+        0x1 | 0x2 => return Err(anyhow!("truncated v2 addresses")),
+        // UNSPEC or UNIX: nothing we can report
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// The client behind `X-Forwarded-For`, read right to left: each trusted
+/// hop vouches for the entry before it, the first untrusted one is the
+/// client. Its port is not known and reported as 0.
+pub(crate) fn forwarded_for(header: Option<&str>, peer: SocketAddr, trusted: Option<&TrustedProxies>) -> SocketAddr {
+    let (Some(header), Some(trusted)) = (header, trusted) else {
+        return peer;
+    };
+    if !trusted.contains(peer.ip()) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in header.rsplit(',').map(str::trim) {
+        let Some(ip) = parse_hop(hop) else {
+            // Garbage can only have come from the client's side
+            break;
+        };
+        client = SocketAddr::new(ip, 0);
+        if !trusted.contains(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// An address as proxies write it: bare, `[v6]`, or with a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>().ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// A v2 header: `command` (0 LOCAL, 1 PROXY), `family` byte, then `body`
+    /// whatever its length field says.
+    fn v2(command: u8, family: u8, len: u16, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&len.to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn headers() {
+        let relay = addr("10.0.0.1:40000");
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let inet = [[203, 0, 113, 7], [10, 0, 0, 2]].concat();
+        let inet = [&inet[..], &1234u16.to_be_bytes(), &443u16.to_be_bytes()].concat();
+        let inet6 = [&[0x20, 0x01, 0x0d, 0xb8][..], &[0; 11], &[1], &[0; 16], &1234u16.to_be_bytes(), &443u16.to_be_bytes()].concat();
+        let with_tlv = [&inet[..], &[0x04, 0x00, 0x01, 0xff]].concat();
+
+        let cases: Vec<(&str, Vec<u8>, Option<SocketAddr>)> = vec![
+            ("v1 TCP4", b"PROXY TCP4 203.0.113.7 10.0.0.2 1234 443\r\n".to_vec(), Some(addr("203.0.113.7:1234"))),
+            ("v1 TCP6", b"PROXY TCP6 2001:db8::1 2001:db8::2 1234 443\r\n".to_vec(), Some(addr("[2001:db8::1]:1234"))),
+            ("v1 UNKNOWN", b"PROXY UNKNOWN\r\n".to_vec(), Some(relay)),
+            ("v1 truncated", b"PROXY TCP4 203.0.113.7 10.0".to_vec(), None),
+            ("v1 oversized", [&b"PROXY TCP4 "[..], &[b'1'; 200], b"\r\n"].concat(), None),
+            ("v1 malformed", b"PROXY TCP4 203.0.113.7 1234\r\n".to_vec(), None),
+            ("v1 bad address", b"PROXY TCP4 example.com 10.0.0.2 1234 443\r\n".to_vec(), None),
+            ("missing", b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".to_vec(), None),
+            ("v2 INET", v2(1, 0x11, inet.len() as u16, &inet), Some(addr("203.0.113.7:1234"))),
+            ("v2 INET with TLVs", v2(1, 0x11, with_tlv.len() as u16, &with_tlv), Some(addr("203.0.113.7:1234"))),
+            ("v2 INET6", v2(1, 0x21, inet6.len() as u16, &inet6), Some(addr("[2001:db8::1]:1234"))),
+            ("v2 LOCAL", v2(0, 0x00, 0, &[]), Some(relay)),
+            ("v2 UNSPEC", v2(1, 0x00, 0, &[]), Some(relay)),
+            ("v2 truncated body", v2(1, 0x11, 12, &inet[..5]), None),
+            ("v2 short addresses", v2(1, 0x11, 4, &inet[..4]), None),
+            ("v2 bad version", [&V2_SIGNATURE[..], &[0x11, 0x11, 0, 0]].concat(), None),
+            ("v2 bad command", v2(5, 0x11, inet.len() as u16, &inet), None),
+        ];
+        for (name, header, expected) in cases {
+            let input = [&header[..], b"tunnel"].concat();
+            let mut stream = &input[..];
+            let accepted = accept_header(&mut stream, relay, Some(&trusted)).await;
+            match expected {
+                Some(expected) => {
+                    assert_eq!(accepted.unwrap(), expected, "{}", name);
+                    // Nothing past the header is consumed
+                    assert_eq!(stream, b"tunnel", "{}", name);
+                }
+                None => assert!(accepted.is_err(), "{}", name),
+            }
+
+            // Untrusted peers are not read from at all
+            let stranger = addr("198.51.100.1:5000");
+            let mut stream = &input[..];
+            assert_eq!(accept_header(&mut stream, stranger, Some(&trusted)).await.unwrap(), stranger, "{}", name);
+            assert_eq!(stream.len(), input.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn forwarded_for_hops() {
+        let relay = addr("10.0.0.1:40000");
+        let trusted: TrustedProxies = "10.0.0.0/8, 2001:db8:ffff::/48".parse().unwrap();
+        let cases: Vec<(&str, Option<&str>, SocketAddr, SocketAddr)> = vec![
+            ("no header", None, relay, relay),
+            ("untrusted peer", Some("203.0.113.7"), addr("198.51.100.1:5000"), addr("198.51.100.1:5000")),
+            ("one hop", Some("203.0.113.7"), relay, addr("203.0.113.7:0")),
+            ("spoofed by the client", Some("6.6.6.6, 203.0.113.7"), relay, addr("203.0.113.7:0")),
+            ("through trusted hops", Some("6.6.6.6, 203.0.113.7, 10.0.0.2, 10.0.0.3"), relay, addr("203.0.113.7:0")),
+            ("every hop trusted", Some("10.0.0.3, 10.0.0.2"), relay, addr("10.0.0.3:0")),
+            ("garbage left of the client", Some("<script>, 203.0.113.7"), relay, addr("203.0.113.7:0")),
+            ("garbage from the client's side", Some("203.0.113.7, unknown"), relay, relay),
+            ("garbage past a trusted hop", Some("nonsense, 10.0.0.2"), relay, addr("10.0.0.2:0")),
+            ("empty", Some(""), relay, relay),
+            ("with a port", Some("203.0.113.7:5678"), relay, addr("203.0.113.7:0")),
+            ("bracketed v6", Some("[2001:db8::1]"), relay, addr("[2001:db8::1]:0")),
+            ("bracketed v6 with a port", Some("[2001:db8::1]:443, 2001:db8:ffff::2"), relay, addr("[2001:db8::1]:0")),
+            ("mapped v4 relay", Some("203.0.113.7"), addr("[::ffff:10.0.0.1]:40000"), addr("203.0.113.7:0")),
+        ];
+        for (name, header, peer, expected) in cases {
+            assert_eq!(forwarded_for(header, peer, Some(&trusted)), expected, "{}", name);
+        }
+        assert_eq!(forwarded_for(Some("203.0.113.7"), relay, None), relay);
+    }
+}
@@ -0,0 +1,750 @@
+//! UDP hole punching: peers behind NAT reach each other with the help of a
+//! rendezvous server both of them can reach.
+//!
+//! A node registers an id with the rendezvous from a socket it keeps open.
+//! A peer dialling that id asks the rendezvous from a fresh socket; the
+//! node is told, answers from a fresh socket of its own, and the rendezvous
+//! gives each side the address it saw the other's request come from. Both
+//! probe that address until one probe is answered, which opens the way
+//! through both NATs, and a KCP session runs over the hole. When no answer
+//! comes (e.g. NATs that map every destination to a port of its own), the
+//! dialler sends its datagrams through the rendezvous instead, which
+//! forwards them between the two addresses it saw, and the node answers
+//! the way they came.
+//!
+//! Registering, dialling and answering take the id's secret, an HMAC of the
+//! id under a key the rendezvous shares with the nodes and their peers.
+//! Requests carry a fresh HMAC under it rather than the secret itself.
+
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use ring::hmac;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::fec::FecConfig;
+use crate::kcp::{self, KcpConfig, Route};
+use super::Endpoint;
+
+/// Leads every message between peers and the rendezvous.
+const MAGIC: &[u8; 4] = b"CHRV";
+
+/// Message header: [Magic: 4] [Type: 1] [Token: 8]
+const HEADER_LEN: usize = 13;
+/// Leads the body of REGISTER, CONNECT and ANSWER, ahead of the id:
+/// [Time: 8] [HMAC-SHA256 of header, time and id under the id's secret: 32]
+const PROOF_LEN: usize = 8 + 32;
+/// How far the time in a proof may be off the rendezvous's clock. A proof
+/// replayed within it finds the id registered, or the session started,
+/// from another address.
+const PROOF_WINDOW: Duration = Duration::from_secs(30);
+
+// Message types
+const MSG_REGISTER: u8 = 1;
+const MSG_REGISTERED: u8 = 2;
+const MSG_CONNECT: u8 = 3;
+const MSG_INCOMING: u8 = 4;
+const MSG_ANSWER: u8 = 5;
+const MSG_PEER: u8 = 6;
+const MSG_PROBE: u8 = 7;
+const MSG_PROBE_ACK: u8 = 8;
+const MSG_RELAY: u8 = 9;
+const MSG_ERROR: u8 = 10;
+
+const MAX_ID_LEN: usize = 64;
+/// Sessions the rendezvous keeps track of at once.
+const MAX_SESSIONS: usize = 4096;
+
+/// How often a node renews its registration, which also keeps its NAT
+/// mapping to the rendezvous open.
+const REGISTER_INTERVAL: Duration = Duration::from_secs(20);
+/// A registration not renewed for this long is dropped, and its id free
+/// to be taken from another address.
+const REGISTRATION_TTL: Duration = Duration::from_secs(60);
+/// A session neither set up nor relaying for this long is forgotten.
+const SESSION_TTL: Duration = Duration::from_secs(60);
+/// Requests to the rendezvous are repeated this often until answered.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the rendezvous may take to put the two peers in touch.
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// Probes unanswered for this long make the dialler relay instead.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a node waits for the session after answering.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Reaches nodes behind NAT through a rendezvous: `connect` dials the node
+/// registered under the transport's id, `listen` registers as it and takes
+/// the peers that dial in. Both are given the rendezvous's endpoint.
+pub struct PunchTransport {
+    id: String,
+    secret: hmac::Key,
+    config: KcpConfig,
+    fec: Option<FecConfig>,
+}
+
+impl PunchTransport {
+    /// `key` is the one the rendezvous is run with.
+    pub fn new(id: &str, key: &[u8]) -> Self {
+        Self {
+            id: id.to_string(),
+            secret: id_secret(key, id),
+            config: KcpConfig::default(),
+            fec: None,
+        }
+    }
+
+    /// Tuning of the KCP sessions run over the hole.
+    pub fn with_config(mut self, config: KcpConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Forward error correction for those sessions, as on `KcpTransport`.
+    pub fn with_fec(mut self, fec: FecConfig) -> Self {
+        self.fec = Some(fec);
+        self
+    }
+}
+
+#[async_trait]
+impl super::Transport for PunchTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Connection>> {
+        let rendezvous = endpoint.inet()?;
+        let socket = Arc::new(UdpSocket::bind(unspecified(rendezvous)).await?);
+        let token = rand::random::<u64>();
+        let request = signed(MSG_CONNECT, token, &self.id, &self.secret);
+        let peer = ask(&socket, rendezvous, &request, token).await
+            .map_err(|e| anyhow!("Rendezvous for '{}': {}", self.id, e))?;
+
+        let path = match punch(&socket, peer, token).await {
+            Some(peer) => Path::Direct(peer),
+            None => Path::Relayed(rendezvous),
+        };
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
+        tokio::spawn(session_reader(socket.clone(), path, token, datagram_tx));
+        let route = path.route(socket, token);
+        Ok(kcp::open_session(rand::random(), self.config.clone(), self.fec.as_ref(), route, datagram_rx))
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Listener>> {
+        let rendezvous = endpoint.inet()?;
+        let socket = UdpSocket::bind(unspecified(rendezvous)).await?;
+        let (accept_tx, accept_rx) = mpsc::channel(100);
+        let node = Node {
+            rendezvous,
+            id: self.id.clone(),
+            secret: self.secret.clone(),
+            config: self.config.clone(),
+            fec: self.fec.clone(),
+        };
+        tokio::spawn(node.serve(socket, accept_tx));
+        Ok(Box::new(PunchListener { rx: accept_rx }))
+    }
+
+    fn name(&self) -> &str {
+        "Punch"
+    }
+}
+
+type Accepted = Result<(Box<dyn super::Connection>, SocketAddr)>;
+
+struct PunchListener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for PunchListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, Endpoint)> {
+        let (connection, peer) = self.rx.recv().await.ok_or_else(|| anyhow!("Punch listener stopped"))??;
+        Ok((connection, peer.into()))
+    }
+}
+
+/// The way a session's datagrams travel: to the peer at the address its
+/// probes came from, or through the rendezvous at this address.
+#[derive(Clone, Copy)]
+enum Path {
+    Direct(SocketAddr),
+    Relayed(SocketAddr),
+}
+
+impl Path {
+    fn route(self, socket: Arc<UdpSocket>, token: u64) -> Route {
+        match self {
+            Path::Direct(peer) => Route::direct(socket, peer),
+            Path::Relayed(rendezvous) => Route::relayed(socket, rendezvous, message(MSG_RELAY, token).freeze()),
+        }
+    }
+}
+
+/// The listening side: keeps its id registered and answers the peers the
+/// rendezvous announces.
+struct Node {
+    rendezvous: SocketAddr,
+    id: String,
+    secret: hmac::Key,
+    config: KcpConfig,
+    fec: Option<FecConfig>,
+}
+
+impl Node {
+    async fn serve(self, socket: UdpSocket, accept_tx: mpsc::Sender<Accepted>) {
+        let node = Arc::new(self);
+        let mut registered = false;
+        let mut next_register = Instant::now();
+        // Announcements are repeated while the dialler waits; each is answered once
+        let mut answered: HashMap<u64, Instant> = HashMap::new();
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (n, from) = tokio::select! {
+                _ = tokio::time::sleep_until(next_register) => {
+                    let register = signed(MSG_REGISTER, 0, &node.id, &node.secret);
+                    let _ = socket.send_to(&register, node.rendezvous).await;
+                    // Retried quickly until it is through, then only renewed
+                    next_register = Instant::now() + if registered { REGISTER_INTERVAL } else { RETRY_INTERVAL };
+                    registered = false;
+                    continue;
+                }
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(_) => continue, // e.g. ICMP errors surfaced on the socket
+                },
+                _ = accept_tx.closed() => break,
+            };
+            if from != node.rendezvous {
+                continue;
+            }
+            match parse(&buf[..n]) {
+                Some((MSG_REGISTERED, _, _)) => {
+                    if !registered {
+                        next_register = Instant::now() + REGISTER_INTERVAL;
+                    }
+                    registered = true;
+                }
+                Some((MSG_ERROR, 0, text)) => {
+                    let error = anyhow!("Rendezvous refused '{}': {}", node.id, String::from_utf8_lossy(text));
+                    let _ = accept_tx.send(Err(error)).await;
+                    next_register = Instant::now() + REGISTER_INTERVAL;
+                }
+                Some((MSG_INCOMING, token, _)) => {
+                    let now = Instant::now();
+                    answered.retain(|_, at| now.duration_since(*at) < SESSION_TIMEOUT);
+                    if answered.insert(token, now).is_some() {
+                        continue;
+                    }
+                    let node = node.clone();
+                    let accept_tx = accept_tx.clone();
+                    tokio::spawn(async move {
+                        let _ = accept_tx.send(node.answer(token).await).await;
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Answers a peer from a socket of its own, probes towards it, and
+    /// starts the session on whichever path the peer's first datagram takes.
+    async fn answer(&self, token: u64) -> Accepted {
+        let socket = Arc::new(UdpSocket::bind(unspecified(self.rendezvous)).await?);
+        let answer = signed(MSG_ANSWER, token, &self.id, &self.secret);
+        let peer = ask(&socket, self.rendezvous, &answer, token).await
+            .map_err(|e| anyhow!("Rendezvous for a peer of '{}': {}", self.id, e))?;
+        let (path, first) = tokio::time::timeout(SESSION_TIMEOUT, await_session(&socket, peer, self.rendezvous, token)).await
+            .map_err(|_| anyhow!("Peer {} never started its session", peer))?;
+
+        // Segments (and FEC packets) lead with the conversation
+        let conv = (&first[..4]).get_u32();
+        let (datagram_tx, datagram_rx) = mpsc::channel(1024);
+        let _ = datagram_tx.try_send(first);
+        tokio::spawn(session_reader(socket.clone(), path, token, datagram_tx));
+        let route = path.route(socket, token);
+        Ok((kcp::open_session(conv, self.config.clone(), self.fec.as_ref(), route, datagram_rx), peer))
+    }
+}
+
+/// Sends `request` to the rendezvous until it answers with the peer's
+/// address, or with an error.
+async fn ask(socket: &UdpSocket, rendezvous: SocketAddr, request: &[u8], token: u64) -> Result<SocketAddr> {
+    let mut retry = tokio::time::interval(RETRY_INTERVAL);
+    let deadline = tokio::time::sleep(RENDEZVOUS_TIMEOUT);
+    tokio::pin!(deadline);
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            _ = retry.tick() => {
+                socket.send_to(request, rendezvous).await?;
+            }
+            res = socket.recv_from(&mut buf) => {
+                let Ok((n, from)) = res else { continue };
+                if from != rendezvous {
+                    continue;
+                }
+                match parse(&buf[..n]) {
+                    Some((MSG_PEER, t, body)) if t == token => {
+                        return get_addr(body).ok_or_else(|| anyhow!("Malformed answer"));
+                    }
+                    Some((MSG_ERROR, t, text)) if t == token => {
+                        return Err(anyhow!("{}", String::from_utf8_lossy(text)));
+                    }
+                    _ => {}
+                }
+            }
+            _ = &mut deadline => return Err(anyhow!("No answer from {}", rendezvous)),
+        }
+    }
+}
+
+/// Probes `peer` until one probe is answered, and returns the address the
+/// answer came from; `None` if none is within `PUNCH_TIMEOUT`.
+async fn punch(socket: &UdpSocket, peer: SocketAddr, token: u64) -> Option<SocketAddr> {
+    let probe = message(MSG_PROBE, token);
+    let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+    let deadline = tokio::time::sleep(PUNCH_TIMEOUT);
+    tokio::pin!(deadline);
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let _ = socket.send_to(&probe, peer).await;
+            }
+            res = socket.recv_from(&mut buf) => {
+                let Ok((n, from)) = res else { continue };
+                match parse(&buf[..n]) {
+                    Some((MSG_PROBE, t, _)) if t == token => {
+                        let _ = socket.send_to(&message(MSG_PROBE_ACK, token), from).await;
+                    }
+                    Some((MSG_PROBE_ACK, t, _)) if t == token => return Some(from),
+                    _ => {}
+                }
+            }
+            _ = &mut deadline => return None,
+        }
+    }
+}
+
+/// Probes `peer` (for `PUNCH_TIMEOUT`) while waiting for the first datagram
+/// of its session, straight from it or relayed by the rendezvous.
+async fn await_session(socket: &UdpSocket, peer: SocketAddr, rendezvous: SocketAddr, token: u64) -> (Path, Bytes) {
+    let probe = message(MSG_PROBE, token);
+    let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+    let probe_until = Instant::now() + PUNCH_TIMEOUT;
+    // Where the peer's probes came from, which its NAT may have changed
+    let mut known = vec![peer];
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, from) = tokio::select! {
+            _ = ticker.tick() => {
+                if Instant::now() < probe_until {
+                    let _ = socket.send_to(&probe, peer).await;
+                }
+                continue;
+            }
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(received) => received,
+                Err(_) => continue,
+            },
+        };
+        let datagram = &buf[..n];
+        match parse(datagram) {
+            Some((MSG_PROBE, t, _)) if t == token => {
+                let _ = socket.send_to(&message(MSG_PROBE_ACK, token), from).await;
+                if !known.contains(&from) {
+                    known.push(from);
+                }
+            }
+            Some((MSG_PROBE_ACK, t, _)) if t == token && !known.contains(&from) => known.push(from),
+            Some((MSG_RELAY, t, payload)) if t == token && from == rendezvous && kcp::opens_session(payload) => {
+                return (Path::Relayed(rendezvous), Bytes::copy_from_slice(payload));
+            }
+            None if known.contains(&from) && kcp::opens_session(datagram) => {
+                return (Path::Direct(from), Bytes::copy_from_slice(datagram));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Feeds a session the datagrams that come its way, and answers the
+/// peer's late probes (it may not have heard back yet).
+async fn session_reader(socket: Arc<UdpSocket>, path: Path, token: u64, tx: mpsc::Sender<Bytes>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, from) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(received) => received,
+                Err(_) => continue,
+            },
+            _ = tx.closed() => break,
+        };
+        let datagram = &buf[..n];
+        // Drop on overflow, like the network would
+        match (parse(datagram), path) {
+            (Some((MSG_PROBE, t, _)), _) if t == token => {
+                let _ = socket.send_to(&message(MSG_PROBE_ACK, token), from).await;
+            }
+            (Some((MSG_RELAY, t, payload)), Path::Relayed(rendezvous)) if t == token && from == rendezvous => {
+                let _ = tx.try_send(Bytes::copy_from_slice(payload));
+            }
+            (None, Path::Direct(peer)) if from == peer => {
+                let _ = tx.try_send(Bytes::copy_from_slice(datagram));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Puts nodes behind NAT in touch with the peers dialling them, and relays
+/// between the two when their NATs cannot be punched through.
+///
+/// Only requests proving the id's secret (derived from `key`) are heeded;
+/// anything else is dropped unanswered, so a stranger can neither take an
+/// id nor have a node probe an address of their choosing. The rendezvous
+/// itself can derive every secret and carries relayed sessions, and the
+/// handshake run over them does not authenticate the ends, so it has to be
+/// trusted as much as the nodes are.
+pub struct RendezvousServer {
+    socket: UdpSocket,
+    key: Vec<u8>,
+}
+
+impl RendezvousServer {
+    pub async fn bind(endpoint: &Endpoint, key: &[u8]) -> Result<Self> {
+        Ok(Self { socket: UdpSocket::bind(endpoint.inet()?).await?, key: key.to_vec() })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Serves registrations, introductions and relaying until dropped.
+    pub async fn run(self) {
+        let mut state = Rendezvous::new(&self.key);
+        let mut sweep = tokio::time::interval(Duration::from_secs(10));
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, from) = tokio::select! {
+                res = self.socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+                _ = sweep.tick() => {
+                    state.expire(Instant::now());
+                    continue;
+                }
+            };
+            for (reply, to) in state.handle(&buf[..n], from, Instant::now()) {
+                let _ = self.socket.send_to(&reply, to).await;
+            }
+        }
+    }
+}
+
+struct Registration {
+    addr: SocketAddr,
+    renewed: Instant,
+}
+
+/// A dialler, the node it asked for, and the address the node answered
+/// from once it has.
+struct Session {
+    id: String,
+    dialler: SocketAddr,
+    node: SocketAddr,
+    answer: Option<SocketAddr>,
+    active: Instant,
+}
+
+/// What the rendezvous knows. It does no I/O: the server feeds it
+/// datagrams and sends out what it answers.
+struct Rendezvous {
+    key: Vec<u8>,
+    registrations: HashMap<String, Registration>,
+    sessions: HashMap<u64, Session>,
+}
+
+impl Rendezvous {
+    fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec(), registrations: HashMap::new(), sessions: HashMap::new() }
+    }
+
+    /// The id a REGISTER, CONNECT or ANSWER is for, if it proves the id's
+    /// secret and is recent.
+    fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a str> {
+        let body = datagram.get(HEADER_LEN..)?;
+        let id = parse_id(body.get(PROOF_LEN..)?)?;
+        let time = u64::from_be_bytes(body[..8].try_into().unwrap());
+        if time.abs_diff(unix_time()) > PROOF_WINDOW.as_secs() {
+            return None;
+        }
+        let mut proven = BytesMut::from(&datagram[..HEADER_LEN + 8]);
+        proven.put_slice(id.as_bytes());
+        hmac::verify(&id_secret(&self.key, id), &proven, &body[8..PROOF_LEN]).ok()?;
+        Some(id)
+    }
+
+    /// The datagrams to send, and where, in answer to one from `from`.
+    fn handle(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) -> Vec<(Bytes, SocketAddr)> {
+        let Some((kind, token, _)) = parse(datagram) else {
+            return Vec::new();
+        };
+        match kind {
+            MSG_REGISTER => {
+                let Some(id) = self.verify(datagram) else {
+                    return Vec::new();
+                };
+                if let Some(existing) = self.registrations.get(id) {
+                    if existing.addr != from && now.duration_since(existing.renewed) < REGISTRATION_TTL {
+                        return vec![(error(token, &format!("'{}' is registered from another address", id)), from)];
+                    }
+                }
+                self.registrations.insert(id.to_string(), Registration { addr: from, renewed: now });
+                let mut reply = message(MSG_REGISTERED, token);
+                put_addr(&mut reply, from);
+                vec![(reply.freeze(), from)]
+            }
+            MSG_CONNECT => {
+                let Some(id) = self.verify(datagram) else {
+                    return Vec::new();
+                };
+                let node = self.registrations.get(id)
+                    .filter(|registration| now.duration_since(registration.renewed) < REGISTRATION_TTL)
+                    .map(|registration| registration.addr);
+                let Some(node) = node else {
+                    return vec![(error(token, &format!("No node is registered as '{}'", id)), from)];
+                };
+                if self.sessions.len() >= MAX_SESSIONS && !self.sessions.contains_key(&token) {
+                    return vec![(error(token, "Too many sessions"), from)];
+                }
+                let session = self.sessions.entry(token)
+                    .or_insert(Session { id: id.to_string(), dialler: from, node, answer: None, active: now });
+                if session.dialler != from {
+                    return Vec::new();
+                }
+                session.active = now;
+                match session.answer {
+                    // The dialler missed the introduction
+                    Some(answer) => vec![(peer(token, answer), from)],
+                    None => vec![(message(MSG_INCOMING, token).freeze(), session.node)],
+                }
+            }
+            MSG_ANSWER => {
+                let Some(id) = self.verify(datagram) else {
+                    return Vec::new();
+                };
+                let Some(session) = self.sessions.get_mut(&token).filter(|session| session.id == id) else {
+                    return Vec::new();
+                };
+                if *session.answer.get_or_insert(from) != from {
+                    return Vec::new();
+                }
+                session.active = now;
+                vec![(peer(token, from), session.dialler), (peer(token, session.dialler), from)]
+            }
+            MSG_RELAY => {
+                let Some(session) = self.sessions.get_mut(&token) else {
+                    return Vec::new();
+                };
+                let Some(answer) = session.answer else {
+                    return Vec::new();
+                };
+                let to = match from {
+                    from if from == session.dialler => answer,
+                    from if from == answer => session.dialler,
+                    _ => return Vec::new(),
+                };
+                session.active = now;
+                vec![(Bytes::copy_from_slice(datagram), to)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.registrations.retain(|_, registration| now.duration_since(registration.renewed) < REGISTRATION_TTL);
+        self.sessions.retain(|_, session| now.duration_since(session.active) < SESSION_TTL);
+    }
+}
+
+fn message(kind: u8, token: u64) -> BytesMut {
+    let mut msg = BytesMut::with_capacity(64);
+    msg.put_slice(MAGIC);
+    msg.put_u8(kind);
+    msg.put_u64(token);
+    msg
+}
+
+/// A REGISTER, CONNECT or ANSWER for `id`, with the proof of its secret.
+fn signed(kind: u8, token: u64, id: &str, secret: &hmac::Key) -> Bytes {
+    let time = unix_time();
+    let mut proven = message(kind, token);
+    proven.put_u64(time);
+    proven.put_slice(id.as_bytes());
+    let tag = hmac::sign(secret, &proven);
+
+    let mut msg = message(kind, token);
+    msg.put_u64(time);
+    msg.put_slice(tag.as_ref());
+    msg.put_slice(id.as_bytes());
+    msg.freeze()
+}
+
+/// What registering and dialling `id` takes: an HMAC of it under the key
+/// shared with the rendezvous.
+fn id_secret(key: &[u8], id: &str) -> hmac::Key {
+    let secret = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), id.as_bytes());
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_ref())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Introduces the peer at `addr`.
+fn peer(token: u64, addr: SocketAddr) -> Bytes {
+    let mut msg = message(MSG_PEER, token);
+    put_addr(&mut msg, addr);
+    msg.freeze()
+}
+
+fn error(token: u64, text: &str) -> Bytes {
+    let mut msg = message(MSG_ERROR, token);
+    msg.put_slice(text.as_bytes());
+    msg.freeze()
+}
+
+/// A message's type, token and body; `None` for anything else (e.g. the
+/// session's own datagrams).
+fn parse(datagram: &[u8]) -> Option<(u8, u64, &[u8])> {
+    if datagram.len() < HEADER_LEN || &datagram[..4] != MAGIC {
+        return None;
+    }
+    let token = u64::from_be_bytes(datagram[5..HEADER_LEN].try_into().unwrap());
+    Some((datagram[4], token, &datagram[HEADER_LEN..]))
+}
+
+fn parse_id(body: &[u8]) -> Option<&str> {
+    std::str::from_utf8(body).ok().filter(|id| !id.is_empty() && id.len() <= MAX_ID_LEN)
+}
+
+/// [Family: 1 (4 or 6)] [IP: 4 or 16] [Port: 2]
+fn put_addr(buf: &mut BytesMut, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+    buf.put_u16(addr.port());
+}
+
+fn get_addr(mut body: &[u8]) -> Option<SocketAddr> {
+    let ip: IpAddr = match body.first()? {
+        4 if body.len() >= 1 + 4 + 2 => {
+            let octets: [u8; 4] = body[1..5].try_into().unwrap();
+            body.advance(5);
+            Ipv4Addr::from(octets).into()
+        }
+        6 if body.len() >= 1 + 16 + 2 => {
+            let octets: [u8; 16] = body[1..17].try_into().unwrap();
+            body.advance(17);
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, body.get_u16()))
+}
+
+/// Any local address of `addr`'s family.
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transport;
+
+    const KEY: &[u8] = b"rendezvous key";
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn requests_without_the_secret_are_dropped() {
+        let mut rendezvous = Rendezvous::new(KEY);
+        let now = Instant::now();
+        let node = addr("198.51.100.1:4000");
+        let dialler = addr("203.0.113.9:5000");
+        let secret = id_secret(KEY, "home");
+        let forged = id_secret(b"another key", "home");
+
+        assert!(rendezvous.handle(&signed(MSG_REGISTER, 0, "home", &forged), node, now).is_empty());
+        let registered = rendezvous.handle(&signed(MSG_REGISTER, 0, "home", &secret), node, now);
+        assert!(matches!(parse(&registered[0].0), Some((MSG_REGISTERED, _, _))));
+
+        // A spoofed CONNECT would have the node probe the address it names
+        assert!(rendezvous.handle(&signed(MSG_CONNECT, 7, "home", &forged), dialler, now).is_empty());
+        let mut unsigned = message(MSG_CONNECT, 7);
+        unsigned.put_slice(b"home");
+        assert!(rendezvous.handle(&unsigned, dialler, now).is_empty());
+        // Nor does a valid proof carry over to another id
+        let mut swapped = BytesMut::from(&signed(MSG_CONNECT, 7, "home", &secret)[..HEADER_LEN + PROOF_LEN]);
+        swapped.put_slice(b"away");
+        assert!(rendezvous.handle(&swapped, dialler, now).is_empty());
+
+        let incoming = rendezvous.handle(&signed(MSG_CONNECT, 7, "home", &secret), dialler, now);
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].1, node);
+        assert!(matches!(parse(&incoming[0].0), Some((MSG_INCOMING, 7, _))));
+
+        assert!(rendezvous.handle(&signed(MSG_ANSWER, 7, "home", &forged), addr("198.51.100.66:1"), now).is_empty());
+        let answer = addr("198.51.100.1:4001");
+        let introduced = rendezvous.handle(&signed(MSG_ANSWER, 7, "home", &secret), answer, now);
+        assert_eq!(introduced.iter().map(|(_, to)| *to).collect::<Vec<_>>(), vec![dialler, answer]);
+    }
+
+    #[tokio::test]
+    async fn punches_through_on_localhost() {
+        let server = RendezvousServer::bind(&addr("127.0.0.1:0").into(), KEY).await.unwrap();
+        let rendezvous: Endpoint = server.local_addr().unwrap().into();
+        tokio::spawn(server.run());
+
+        let mut listener = PunchTransport::new("home", KEY).listen(&rendezvous).await.unwrap();
+        let dialler = PunchTransport::new("home", KEY);
+        let mut client = None;
+        for _ in 0..20 {
+            match dialler.connect(&rendezvous).await {
+                Ok(connection) => {
+                    client = Some(connection);
+                    break;
+                }
+                // Not registered yet
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        let mut client = client.expect("never got through the rendezvous");
+        client.send(Bytes::from_static(b"hello")).await.unwrap();
+        let (mut node, _) = tokio::time::timeout(Duration::from_secs(10), listener.accept()).await.unwrap().unwrap();
+        assert_eq!(&node.recv().await.unwrap().unwrap()[..], b"hello");
+
+        // The wrong key gets no answer at all
+        let stranger = PunchTransport::new("home", b"guessed");
+        assert!(stranger.connect(&rendezvous).await.is_err());
+    }
+}
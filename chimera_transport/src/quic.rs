@@ -0,0 +1,297 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, RecvStream, SendStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+use crate::tls::{crypto_provider, self_signed};
+use super::{RecvHalf, SendHalf};
+
+/// ALPN advertised by both sides, so the handshake looks like ordinary HTTP/3.
+const ALPN_H3: &[u8] = b"h3";
+
+/// Largest chunk handed out by a single `recv` call.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// How long a dropped connection lingers to flush unacknowledged data.
+const LINGER: Duration = Duration::from_secs(10);
+
+/// QUIC transport built on `quinn`.
+/// Each Chimera tunnel is one QUIC connection carrying a single bidirectional stream.
+/// The outer TLS layer is camouflage only: the server uses a throwaway self-signed
+/// certificate and the client does not verify it, since the Chimera handshake
+/// running inside the stream provides the actual key exchange.
+pub struct QuicTransport {
+    server_name: String,
+}
+
+impl QuicTransport {
+    pub fn new() -> Self {
+        Self {
+            server_name: "localhost".to_string(),
+        }
+    }
+
+    /// Sets the SNI sent by the client and the name put in the server certificate.
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = server_name.to_string();
+        self
+    }
+}
+
+impl Default for QuicTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for QuicTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let bind_addr: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let mut endpoint = Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(client_config()?);
+
+        let connection = endpoint.connect(addr, &self.server_name)?.await?;
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Box::new(QuicConnection::new(Some(endpoint), connection, send, recv)))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let endpoint = Endpoint::server(server_config(&self.server_name)?, addr)?;
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(accept_loop(endpoint, tx));
+        Ok(Box::new(QuicListener { rx }))
+    }
+
+    fn name(&self) -> &str {
+        "QUIC"
+    }
+}
+
+struct QuicConnection {
+    send: QuicSendHalf,
+    recv: QuicRecvHalf,
+}
+
+impl QuicConnection {
+    fn new(endpoint: Option<Endpoint>, connection: quinn::Connection, send: SendStream, recv: RecvStream) -> Self {
+        let linger = Arc::new(Linger { endpoint, connection });
+        Self {
+            send: QuicSendHalf { stream: send, _linger: linger.clone() },
+            recv: QuicRecvHalf { stream: recv, _linger: linger },
+        }
+    }
+}
+
+#[async_trait]
+impl super::Connection for QuicConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.send.send(data).await
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        self.send.send_vectored(data).await
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        self.recv.recv().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.send.close().await
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn RecvHalf>, Box<dyn SendHalf>) {
+        (Box::new(self.recv), Box::new(self.send))
+    }
+}
+
+struct QuicSendHalf {
+    stream: SendStream,
+    _linger: Arc<Linger>,
+}
+
+#[async_trait]
+impl SendHalf for QuicSendHalf {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.stream.write_all(&data).await?;
+        Ok(())
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        self.stream.write_all_chunks(&mut data.to_vec()).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.stream.finish()?;
+        // Wait until the peer has read everything we sent
+        let _ = self.stream.stopped().await;
+        Ok(())
+    }
+}
+
+impl Drop for QuicSendHalf {
+    fn drop(&mut self) {
+        let _ = self.stream.finish();
+    }
+}
+
+struct QuicRecvHalf {
+    stream: RecvStream,
+    _linger: Arc<Linger>,
+}
+
+#[async_trait]
+impl RecvHalf for QuicRecvHalf {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        let chunk = self.stream.read_chunk(MAX_CHUNK, true).await?;
+        Ok(chunk.map(|c| c.bytes))
+    }
+}
+
+/// Shared by a connection's streams; the last one gone leaves the
+/// connection lingering.
+struct Linger {
+    // Client connections own their endpoint; it must outlive the streams.
+    endpoint: Option<Endpoint>,
+    connection: quinn::Connection,
+}
+
+impl Drop for Linger {
+    fn drop(&mut self) {
+        // Unlike a TCP socket, a QUIC connection is torn down immediately once
+        // every handle is gone, discarding data still in flight. The stream is
+        // finished by now; keep the connection alive until the peer closes it.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let connection = self.connection.clone();
+        let endpoint = self.endpoint.take();
+        runtime.spawn(async move {
+            let _ = tokio::time::timeout(LINGER, connection.closed()).await;
+            drop(endpoint);
+        });
+    }
+}
+
+type Accepted = Result<(QuicConnection, SocketAddr)>;
+
+struct QuicListener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for QuicListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("QUIC endpoint closed"))??;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Completes QUIC handshakes in the background so a slow client
+/// cannot stall `accept()` for everyone else.
+async fn accept_loop(endpoint: Endpoint, tx: mpsc::Sender<Accepted>) {
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            // Listener dropped: stop accepting, but leave live connections alone
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let remote_addr = incoming.remote_address();
+            let result = async {
+                let connection = incoming.await?;
+                // The client opens the stream and speaks first (mimic handshake)
+                let (send, recv) = connection.accept_bi().await?;
+                Ok((QuicConnection::new(None, connection, send, recv), remote_addr))
+            }.await;
+            let _ = tx.send(result).await;
+        });
+    }
+    endpoint.set_server_config(None);
+}
+
+fn client_config() -> Result<quinn::ClientConfig> {
+    let provider = crypto_provider();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+    Ok(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?)))
+}
+
+fn server_config(server_name: &str) -> Result<quinn::ServerConfig> {
+    let (cert, key) = self_signed(vec![server_name.to_string()])?;
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+    crypto.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?)))
+}
+
+/// Accepts any server certificate but still checks handshake signatures,
+/// so the TLS exchange itself stays well-formed.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,653 @@
+use anyhow::{Result, anyhow};
+use chimera_ai::Router;
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::dns::{DnsRecordType, DnsTransport};
+use crate::fault::{FaultPlan, FaultTransport};
+use crate::http2::Http2Transport;
+use crate::fec::{FecConfig, FecStats};
+use crate::kcp::KcpTransport;
+use crate::meek::MeekTransport;
+use crate::proxy_protocol::TrustedProxies;
+use crate::punch::PunchTransport;
+use crate::quic::QuicTransport;
+use crate::ssh::{self, SshKey, SshTransport};
+use crate::tcp::{Desync, TcpOptions, TcpTransport};
+use crate::tls::TlsTransport;
+use crate::upstream::UpstreamProxy;
+use crate::websocket::WebSocketTransport;
+use super::{Endpoint, Transport};
+
+/// Which end of the tunnel transports are built for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// What the registered transports are built from.
+///
+/// `addr` is the server's address on the client and the main bind address
+/// on the server; transports with a port of their own use its IP with that
+/// port unless given an endpoint (keyed like `TransportSpec::key`).
+///
+/// Everything else is a string setting named like the environment variable
+/// the binaries take it from (e.g. `SERVER_WS_PATH`), which each transport's
+/// factory looks up and parses itself.
+#[derive(Clone)]
+pub struct TransportParams {
+    side: Side,
+    addr: SocketAddr,
+    endpoints: HashMap<String, Endpoint>,
+    settings: HashMap<String, String>,
+    router: Option<Arc<Router>>,
+    notices: Option<Arc<Notices>>,
+    // Built once and handed to every transport that asks, by key
+    shared: Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>,
+}
+
+type Notices = dyn Fn(&str) + Send + Sync;
+
+impl TransportParams {
+    pub fn new(side: Side, addr: SocketAddr) -> Self {
+        Self {
+            side,
+            addr,
+            endpoints: HashMap::new(),
+            settings: HashMap::new(),
+            router: None,
+            notices: None,
+            shared: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Endpoint for the transports registered under `key`, instead of
+    /// their default port.
+    pub fn with_endpoint(mut self, key: &str, endpoint: Endpoint) -> Self {
+        self.endpoints.insert(key.to_string(), endpoint);
+        self
+    }
+
+    /// Sets the setting `name` (e.g. `SERVER_WS_PATH`).
+    pub fn with_setting(mut self, name: &str, value: &str) -> Self {
+        self.settings.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Takes every `SERVER_*` and `CLIENT_*` pair as a setting, e.g. from
+    /// `std::env::vars()`.
+    pub fn with_settings<I: IntoIterator<Item = (String, String)>>(mut self, settings: I) -> Self {
+        self.settings.extend(settings.into_iter()
+            .filter(|(name, _)| name.starts_with("SERVER_") || name.starts_with("CLIENT_")));
+        self
+    }
+
+    /// The client's `Router`, for transports that adapt to what it measures.
+    pub fn with_router(mut self, router: Arc<Router>) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Where transports report what the operator should know, such as the
+    /// fingerprint of a generated certificate.
+    pub fn with_notices<F: Fn(&str) + Send + Sync + 'static>(mut self, notices: F) -> Self {
+        self.notices = Some(Arc::new(notices));
+        self
+    }
+
+    /// The setting `name` (e.g. `SERVER_WS_PATH`), if given.
+    pub fn setting(&self, name: &str) -> Option<&str> {
+        self.settings.get(name).map(String::as_str)
+    }
+
+    /// The setting for this side: `CLIENT_<name>` on the client,
+    /// `SERVER_<name>` on the server.
+    pub fn side_setting(&self, name: &str) -> Option<&str> {
+        match self.side {
+            Side::Client => self.setting(&format!("CLIENT_{}", name)),
+            Side::Server => self.setting(&format!("SERVER_{}", name)),
+        }
+    }
+
+    /// Parses the setting `name`, if given.
+    pub fn parse_setting<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        self.setting(name)
+            .map(|value| value.parse().map_err(|e: T::Err| e.into().context(format!("Invalid {}", name))))
+            .transpose()
+    }
+
+    pub fn router(&self) -> Option<&Arc<Router>> {
+        self.router.as_ref()
+    }
+
+    pub fn notice(&self, message: &str) {
+        if let Some(notices) = &self.notices {
+            notices(message);
+        }
+    }
+
+    /// The value shared under `key`, built by `init` the first time, for
+    /// transports that must use the same one (e.g. TLS and HTTP/2 serving
+    /// one certificate).
+    pub fn shared<T, F>(&self, key: &str, init: F) -> Result<T>
+    where
+        T: Clone + Send + 'static,
+        F: FnOnce() -> Result<T>,
+    {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(value) = shared.get(key).and_then(|value| value.downcast_ref::<T>()) {
+            return Ok(value.clone());
+        }
+        let value = init()?;
+        shared.insert(key.to_string(), Box::new(value.clone()));
+        Ok(value)
+    }
+
+    /// Socket settings for the TCP-based transports (`CLIENT_TCP_OPTIONS`
+    /// or `SERVER_TCP_OPTIONS`).
+    pub fn tcp_options(&self) -> Result<TcpOptions> {
+        match self.side_setting("TCP_OPTIONS") {
+            Some(options) => options.parse().map_err(|e: anyhow::Error| e.context("Invalid TCP options")),
+            None => Ok(TcpOptions::new()),
+        }
+    }
+
+    /// Relays whose connections open with a PROXY protocol header, on the
+    /// server's TCP-based listeners (`SERVER_PROXY_PROTOCOL`).
+    pub fn proxy_protocol(&self) -> Result<Option<TrustedProxies>> {
+        match self.side {
+            Side::Server => self.parse_setting("SERVER_PROXY_PROTOCOL"),
+            Side::Client => Ok(None),
+        }
+    }
+
+    /// Reverse proxies whose `X-Forwarded-For` is believed, on the
+    /// server's HTTP-based listeners (`SERVER_TRUSTED_PROXIES`).
+    pub fn forwarded_for(&self) -> Result<Option<TrustedProxies>> {
+        match self.side {
+            Side::Server => self.parse_setting("SERVER_TRUSTED_PROXIES"),
+            Side::Client => Ok(None),
+        }
+    }
+
+    /// The proxy the client's TCP-based transports dial through
+    /// (`CLIENT_UPSTREAM_PROXY`, e.g. "socks5://127.0.0.1:9050"). It is
+    /// asked for the server by name when `SERVER_HOST` is one.
+    pub fn upstream_proxy(&self) -> Result<Option<UpstreamProxy>> {
+        let proxy = match self.side {
+            Side::Client => self.parse_setting::<UpstreamProxy>("CLIENT_UPSTREAM_PROXY")?,
+            Side::Server => None,
+        };
+        Ok(proxy.map(|proxy| match self.server_name() {
+            Some(name) => proxy.with_remote_name(self.addr.ip(), name),
+            None => proxy,
+        }))
+    }
+
+    /// The server's host name, when the client leaves it to the upstream
+    /// proxy to resolve: `addr` is then only a placeholder, and the name
+    /// stands in for it wherever a host is sent (SNI, `Host` headers).
+    pub fn server_name(&self) -> Option<&str> {
+        if self.side == Side::Server || self.setting("CLIENT_UPSTREAM_PROXY").is_none() {
+            return None;
+        }
+        self.setting("SERVER_HOST").filter(|host| host.parse::<IpAddr>().is_err())
+    }
+
+    /// TCP with the socket, upstream proxy and server-side relay settings
+    /// applied.
+    pub fn tcp(&self) -> Result<TcpTransport> {
+        let mut tcp = TcpTransport::new().with_options(self.tcp_options()?);
+        if let Some(proxy) = self.upstream_proxy()? {
+            tcp = tcp.with_upstream_proxy(proxy);
+        }
+        Ok(match self.proxy_protocol()? {
+            Some(trusted) => tcp.with_proxy_protocol(trusted),
+            None => tcp,
+        })
+    }
+
+    /// TLS as configured by the `SERVER_TLS_*` settings, with the socket,
+    /// upstream proxy and server-side relay settings applied. TLS and
+    /// HTTP/2 share it.
+    pub fn tls(&self) -> Result<TlsTransport> {
+        let mut tls = self.shared("TLS", || tls_settings(self))?.with_tcp_options(self.tcp_options()?);
+        if let Some(proxy) = self.upstream_proxy()? {
+            tls = tls.with_upstream_proxy(proxy);
+        }
+        Ok(match self.proxy_protocol()? {
+            Some(trusted) => tls.with_proxy_protocol(trusted),
+            None => tls,
+        })
+    }
+}
+
+/// Client: web PKI roots, or the self-signed certificate pinned with
+/// `SERVER_TLS_PIN`, checked for `SERVER_TLS_SNI`. Server: the certificate in
+/// `SERVER_TLS_CERT`/`SERVER_TLS_KEY`, or a self-signed one for
+/// `SERVER_TLS_NAME` whose pin is announced. `SERVER_TLS_ALPN` on both.
+fn tls_settings(params: &TransportParams) -> Result<TlsTransport> {
+    let mut tls = TlsTransport::new();
+    if let Some(alpn) = params.setting("SERVER_TLS_ALPN") {
+        tls = tls.with_alpn(&alpn.split(',').collect::<Vec<_>>());
+    }
+    match params.side {
+        Side::Client => {
+            if let Some(sni) = params.setting("SERVER_TLS_SNI").or(params.server_name()) {
+                tls = tls.with_server_name(sni);
+            }
+            if let Some(pin) = params.setting("SERVER_TLS_PIN") {
+                tls = tls.with_pinned_cert(pin)?;
+            }
+            Ok(tls)
+        }
+        Side::Server => match (params.setting("SERVER_TLS_CERT"), params.setting("SERVER_TLS_KEY")) {
+            (Some(cert), Some(key)) => tls.with_cert_files(Path::new(cert), Path::new(key)),
+            _ => {
+                let name = params.setting("SERVER_TLS_NAME").unwrap_or("localhost");
+                let tls = tls.with_self_signed(&[name])?;
+                if let Some(fingerprint) = tls.fingerprint() {
+                    params.notice(&format!("TLS: self-signed certificate, pin with SERVER_TLS_PIN={}", fingerprint));
+                }
+                Ok(tls)
+            }
+        },
+    }
+}
+
+/// Client: logs in as `CLIENT_SSH_USER` with the ed25519 key in
+/// `CLIENT_SSH_KEY`, pinning the host key to `SERVER_SSH_PIN` when set.
+/// Server: admits the keys in `SERVER_SSH_AUTHORIZED_KEYS`, with the host
+/// key in `SERVER_SSH_HOST_KEY` or a generated one whose pin is announced.
+fn ssh_settings(params: &TransportParams) -> Result<SshTransport> {
+    let ssh = SshTransport::new();
+    let ssh = match params.side {
+        Side::Client => match params.setting("CLIENT_SSH_KEY") {
+            Some(key) => {
+                let user = params.setting("CLIENT_SSH_USER").unwrap_or("chimera");
+                let ssh = ssh.with_login(user, SshKey::from_file(Path::new(key))?);
+                match params.setting("SERVER_SSH_PIN") {
+                    Some(pin) => ssh.with_pinned_host_key(pin),
+                    None => ssh,
+                }
+            }
+            None => ssh,
+        },
+        Side::Server => {
+            let authorized_keys = params.setting("SERVER_SSH_AUTHORIZED_KEYS")
+                .ok_or_else(|| anyhow!("SSH needs SERVER_SSH_AUTHORIZED_KEYS"))?;
+            let ssh = ssh.with_authorized_keys(ssh::read_authorized_keys(Path::new(authorized_keys))?);
+            match params.setting("SERVER_SSH_HOST_KEY") {
+                Some(path) => ssh.with_host_key(SshKey::from_file(Path::new(path))?),
+                None => {
+                    let ssh = ssh.with_host_key(SshKey::generate()?);
+                    if let Some(fingerprint) = ssh.host_key_fingerprint() {
+                        params.notice(&format!("SSH: generated host key, pin with SERVER_SSH_PIN={}", fingerprint));
+                    }
+                    ssh
+                }
+            }
+        }
+    };
+    let mut ssh = ssh.with_tcp_options(params.tcp_options()?);
+    if let Some(proxy) = params.upstream_proxy()? {
+        ssh = ssh.with_upstream_proxy(proxy);
+    }
+    Ok(match params.proxy_protocol()? {
+        Some(trusted) => ssh.with_proxy_protocol(trusted),
+        None => ssh,
+    })
+}
+
+/// Forward error correction for KCP and Punch, which share it, from
+/// `CLIENT_FEC` or `SERVER_FEC`. Each session sizes its parity from the
+/// loss its peer measures; the client reports the worst of them as the KCP
+/// path's loss, which new sessions start from.
+fn fec_settings(params: &TransportParams) -> Result<Option<FecConfig>> {
+    let Some(fec) = params.side_setting("FEC") else {
+        return Ok(None);
+    };
+    params.shared("FEC", || {
+        let mut fec: FecConfig = fec.parse()?;
+        if let (Side::Client, Some(router)) = (params.side, &params.router) {
+            fec = fec.with_router(router.clone(), "KCP");
+        }
+        if let (Some(notices), Ok(runtime)) = (params.notices.clone(), tokio::runtime::Handle::try_current()) {
+            runtime.spawn(report_fec_stats(fec.stats(), notices));
+        }
+        Ok(fec)
+    }).map(Some)
+}
+
+/// Reports what FEC sent and recovered every minute, while it is in use.
+async fn report_fec_stats(stats: Arc<FecStats>, notices: Arc<Notices>) {
+    let mut last = (0, 0);
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let current = (stats.data_sent(), stats.data_received());
+        if current != last {
+            notices(&format!("KCP FEC: {}", stats));
+            last = current;
+        }
+    }
+}
+
+/// Resolves a `host:port` setting to one address.
+fn resolve_setting(params: &TransportParams, name: &str) -> Result<Option<SocketAddr>> {
+    let Some(value) = params.setting(name) else {
+        return Ok(None);
+    };
+    let addr = value.to_socket_addrs()?.next()
+        .ok_or_else(|| anyhow!("Could not resolve {} ({})", name, value))?;
+    Ok(Some(addr))
+}
+
+/// A built transport under its registered name, with its endpoint.
+pub type NamedTransport = (String, Box<dyn Transport>, Endpoint);
+
+type Build = dyn Fn(&TransportParams) -> Result<Box<dyn Transport>> + Send + Sync;
+
+/// How to build one named transport (a `Router` path on the client).
+pub struct TransportSpec {
+    name: String,
+    key: Option<String>,
+    default_port: Option<u16>,
+    latency: Duration,
+    client: bool,
+    server: bool,
+    udp: bool,
+    build: Box<Build>,
+}
+
+impl TransportSpec {
+    pub fn new<F>(name: &str, build: F) -> Self
+    where
+        F: Fn(&TransportParams) -> Result<Box<dyn Transport>> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            key: None,
+            default_port: None,
+            latency: Duration::from_millis(100),
+            client: true,
+            server: true,
+            udp: false,
+            build: Box::new(build),
+        }
+    }
+
+    /// Runs on an endpoint of its own, looked up under `key` (e.g. "KCP",
+    /// which the binaries map to SERVER_KCP_PORT and SERVER_KCP_BIND).
+    /// Without a default port it is only available when given an endpoint.
+    pub fn with_endpoint_key(mut self, key: &str, default_port: Option<u16>) -> Self {
+        self.key = Some(key.to_string());
+        self.default_port = default_port;
+        self
+    }
+
+    /// Latency the `Router` assumes before it has measured anything, which
+    /// sets the order paths are first tried in.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn client_only(mut self) -> Self {
+        self.server = false;
+        self
+    }
+
+    /// Runs over UDP, which an upstream proxy does not carry: left out on a
+    /// client that has one, rather than going around it.
+    pub fn over_udp(mut self) -> Self {
+        self.udp = true;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Endpoint key; `None` for transports on the main address.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    fn endpoint(&self, params: &TransportParams) -> Option<Endpoint> {
+        let runs_here = match params.side {
+            Side::Client => self.client,
+            Side::Server => self.server,
+        };
+        if !runs_here || (self.udp && params.side == Side::Client && params.setting("CLIENT_UPSTREAM_PROXY").is_some()) {
+            return None;
+        }
+        match &self.key {
+            Some(key) => params.endpoints.get(key).cloned()
+                .or_else(|| self.default_port.map(|port| SocketAddr::new(params.addr.ip(), port).into())),
+            None => Some(params.addr.into()),
+        }
+    }
+}
+
+/// Transports by name, so the binaries build whatever is registered
+/// instead of naming each one.
+pub struct TransportRegistry {
+    specs: Vec<TransportSpec>,
+}
+
+impl TransportRegistry {
+    pub fn new() -> Self {
+        Self { specs: Vec::new() }
+    }
+
+    /// Every transport shipped in this crate, with its stock ports.
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        // Demo path the client tries first and fails over from
+        registry.register(TransportSpec::new("BlockedProtocol", |_| {
+            Ok(Box::new(FaultTransport::new(TcpTransport::new(), FaultPlan::new().blocked())))
+        }).with_latency(Duration::from_millis(10)).client_only());
+        // With CLIENT_UPSTREAM_PROXY (e.g. "socks5://127.0.0.1:9050") every
+        // TCP-based path reaches the server through it, and the UDP ones are off
+        registry.register(TransportSpec::new("TCP", |params| {
+            if let Some(proxy) = params.upstream_proxy()? {
+                params.notice(&format!("Dialling through upstream proxy {}, without the UDP paths", proxy));
+            }
+            Ok(Box::new(params.tcp()?))
+        }).with_latency(Duration::from_millis(100)));
+        // The same TCP with its first flight cut up, for when DPI picks out the
+        // handshake: each strategy is a path of its own, the Router keeps what works
+        registry.register(TransportSpec::new("TCP-Split", |params| {
+            Ok(Box::new(params.tcp()?.with_desync(Desync::new().with_split(16))))
+        }).with_latency(Duration::from_millis(110)).client_only());
+        registry.register(TransportSpec::new("TCP-Disorder", |params| {
+            Ok(Box::new(params.tcp()?.with_desync(Desync::new().with_split(16).with_disorder(1))))
+        }).with_latency(Duration::from_millis(115)).client_only());
+        registry.register(TransportSpec::new("TCP-Delay", |params| {
+            let desync = Desync::new().with_split(16).with_delay(Duration::from_millis(20));
+            Ok(Box::new(params.tcp()?.with_desync(desync)))
+        }).with_latency(Duration::from_millis(140)).client_only());
+        // TCP and QUIC (UDP) share the port number without conflict
+        registry.register(TransportSpec::new("QUIC", |_| Ok(Box::new(QuicTransport::new())))
+            .with_latency(Duration::from_millis(150))
+            .over_udp());
+        // Fallback for networks that throttle or reset long-lived TCP flows
+        registry.register(TransportSpec::new("KCP", |params| {
+            Ok(Box::new(match fec_settings(params)? {
+                Some(fec) => KcpTransport::new().with_fec(fec),
+                None => KcpTransport::new(),
+            }))
+        })
+            .with_endpoint_key("KCP", Some(8081))
+            .with_latency(Duration::from_millis(200))
+            .over_udp());
+        // A node behind NAT, reached through the rendezvous (its endpoint) that
+        // knows it by id (SERVER_PUNCH_ID), under the key it was given
+        // (SERVER_PUNCH_KEY). Only set up where nothing else reaches the node,
+        // so it goes first
+        registry.register(TransportSpec::new("Punch", |params| {
+            let id = params.setting("SERVER_PUNCH_ID")
+                .ok_or_else(|| anyhow!("Punch needs the id the node is registered as (SERVER_PUNCH_ID)"))?;
+            let key = params.setting("SERVER_PUNCH_KEY")
+                .ok_or_else(|| anyhow!("Punch needs the rendezvous's key (SERVER_PUNCH_KEY)"))?;
+            let punch = PunchTransport::new(id, key.as_bytes());
+            Ok(Box::new(match fec_settings(params)? {
+                Some(fec) => punch.with_fec(fec),
+                None => punch,
+            }))
+        })
+            .with_endpoint_key("PUNCH", None)
+            .with_latency(Duration::from_millis(50))
+            .over_udp());
+        // Possibly behind a reverse proxy or CDN: SERVER_WS_PATH, SERVER_WS_HOST
+        registry.register(TransportSpec::new("WebSocket", |params| {
+            let mut ws = WebSocketTransport::new()
+                .with_path(params.setting("SERVER_WS_PATH").unwrap_or("/ws"))
+                .with_tcp_options(params.tcp_options()?);
+            if let Some(host) = params.setting("SERVER_WS_HOST").or(params.server_name()) {
+                ws = ws.with_host(host);
+            }
+            if let Some(proxy) = params.upstream_proxy()? {
+                ws = ws.with_upstream_proxy(proxy);
+            }
+            if let Some(trusted) = params.proxy_protocol()? {
+                ws = ws.with_proxy_protocol(trusted);
+            }
+            if let Some(trusted) = params.forwarded_for()? {
+                ws = ws.with_forwarded_for(trusted);
+            }
+            Ok(Box::new(ws))
+        }).with_endpoint_key("WS", Some(8082)).with_latency(Duration::from_millis(180)));
+        registry.register(TransportSpec::new("TLS", |params| Ok(Box::new(params.tls()?)))
+            .with_endpoint_key("TLS", Some(8443))
+            .with_latency(Duration::from_millis(120)));
+        // Needs keys set up on both ends, so only runs where given an endpoint
+        registry.register(TransportSpec::new("SSH", |params| {
+            Ok(Box::new(params.shared("SSH", || ssh_settings(params))?))
+        })
+            .with_endpoint_key("SSH", None)
+            .with_latency(Duration::from_millis(125)));
+        // gRPC look-alike on the TLS certificate, under SERVER_H2_PATH
+        registry.register(TransportSpec::new("HTTP2", |params| {
+            let mut h2 = Http2Transport::new().with_tls(params.tls()?);
+            if let Some(trusted) = params.proxy_protocol()? {
+                h2 = h2.with_proxy_protocol(trusted);
+            }
+            if let Some(path) = params.setting("SERVER_H2_PATH") {
+                h2 = h2.with_path(path);
+            }
+            Ok(Box::new(h2))
+        }).with_endpoint_key("H2", Some(8444)).with_latency(Duration::from_millis(130)));
+        // Polling is slow; only worth it when nothing else gets through.
+        // SERVER_MEEK_PROXY routes the polls through an HTTP forwarding proxy
+        registry.register(TransportSpec::new("Meek", |params| {
+            let mut meek = MeekTransport::new()
+                .with_path(params.setting("SERVER_MEEK_PATH").unwrap_or("/"))
+                .with_tcp_options(params.tcp_options()?);
+            if let Some(host) = params.setting("SERVER_MEEK_HOST").or(params.server_name()) {
+                meek = meek.with_host(host);
+            }
+            if let Some(proxy) = params.upstream_proxy()? {
+                meek = meek.with_upstream_proxy(proxy);
+            }
+            if let Some(proxy) = resolve_setting(params, "SERVER_MEEK_PROXY")? {
+                meek = meek.with_forward_proxy(proxy);
+            }
+            if let Some(trusted) = params.proxy_protocol()? {
+                meek = meek.with_proxy_protocol(trusted);
+            }
+            if let Some(trusted) = params.forwarded_for()? {
+                meek = meek.with_forwarded_for(trusted);
+            }
+            Ok(Box::new(meek))
+        }).with_endpoint_key("MEEK", Some(8083)).with_latency(Duration::from_millis(400)));
+        // A trickle at best: the path of last resort. The server is authoritative
+        // for SERVER_DNS_DOMAIN; the client goes through SERVER_DNS_RESOLVER if set
+        registry.register(TransportSpec::new("DNS", |params| {
+            let domain = params.setting("SERVER_DNS_DOMAIN").unwrap_or("t.example.com");
+            let record_type = match params.setting("SERVER_DNS_RECORD") {
+                Some("NULL") => DnsRecordType::Null,
+                _ => DnsRecordType::Txt,
+            };
+            let mut dns = DnsTransport::new(domain).with_record_type(record_type);
+            if let Some(resolver) = resolve_setting(params, "SERVER_DNS_RESOLVER")? {
+                dns = dns.with_resolver(resolver);
+            }
+            Ok(Box::new(dns))
+        }).with_endpoint_key("DNS", Some(5353)).with_latency(Duration::from_millis(2000)).over_udp());
+        // Nothing beats a local socket when the server is next door
+        #[cfg(unix)]
+        registry.register(TransportSpec::new("Unix", |_| Ok(Box::new(crate::unix::UnixTransport)))
+            .with_endpoint_key("UNIX", None)
+            .with_latency(Duration::from_millis(5)));
+        registry
+    }
+
+    /// Adds a transport, replacing any registered under the same name.
+    pub fn register(&mut self, spec: TransportSpec) {
+        match self.specs.iter_mut().find(|existing| existing.name == spec.name) {
+            Some(existing) => *existing = spec,
+            None => self.specs.push(spec),
+        }
+    }
+
+    pub fn specs(&self) -> impl Iterator<Item = &TransportSpec> {
+        self.specs.iter()
+    }
+
+    /// Builds the transport registered as `name`, with the endpoint it
+    /// dials (client) or listens on (server).
+    pub fn build(&self, name: &str, params: &TransportParams) -> Result<(Box<dyn Transport>, Endpoint)> {
+        let spec = self.specs.iter().find(|spec| spec.name == name)
+            .ok_or_else(|| anyhow!("Unknown transport '{}'", name))?;
+        let endpoint = spec.endpoint(params)
+            .ok_or_else(|| anyhow!("Transport '{}' is not available here", name))?;
+        Ok(((spec.build)(params)?, endpoint))
+    }
+
+    /// Builds every transport available with `params`, in registration order.
+    pub fn build_all(&self, params: &TransportParams) -> Result<Vec<NamedTransport>> {
+        let mut transports = Vec::new();
+        for spec in &self.specs {
+            if let Some(endpoint) = spec.endpoint(params) {
+                transports.push((spec.name.clone(), (spec.build)(params)?, endpoint));
+            }
+        }
+        Ok(transports)
+    }
+
+    /// Registers every available transport as a `Router` path, with its
+    /// starting latency estimate.
+    pub fn register_paths(&self, router: &Router, params: &TransportParams) {
+        for spec in &self.specs {
+            if spec.endpoint(params).is_some() {
+                router.register_path(&spec.name);
+                router.update_latency(&spec.name, spec.latency);
+            }
+        }
+    }
+}
+
+impl Default for TransportRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
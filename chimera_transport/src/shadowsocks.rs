@@ -0,0 +1,761 @@
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use rand::Rng;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::tcp::{TcpOptions, TcpTransport};
+use crate::upstream::UpstreamProxy;
+
+/// blake3 context the session subkeys are derived under (SIP022).
+const SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+const TAG_LEN: usize = 16;
+const HEADER_CLIENT: u8 = 0;
+const HEADER_SERVER: u8 = 1;
+/// Request header: type, timestamp and the length of what follows.
+const REQUEST_HEADER_LEN: usize = 1 + 8 + 2;
+/// Largest payload of one chunk.
+const MAX_CHUNK: usize = 0xffff;
+/// Headers whose timestamp is further off than this are rejected, and salts
+/// are remembered at least twice as long to catch replays.
+const MAX_TIME_DIFF: u64 = 30;
+const SALT_TTL: Duration = Duration::from_secs(2 * MAX_TIME_DIFF + 10);
+/// Padding a request without payload gets, at most.
+const MAX_PADDING: usize = 900;
+/// Requests that take longer than this to arrive are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// A request that fails to authenticate is read and discarded for this
+/// long, so probes learn nothing from when the server hangs up.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The Shadowsocks 2022 (AEAD-2022) methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Aes128Gcm => "2022-blake3-aes-128-gcm",
+            Method::Aes256Gcm => "2022-blake3-aes-256-gcm",
+            Method::ChaCha20Poly1305 => "2022-blake3-chacha20-poly1305",
+        }
+    }
+
+    /// Length of the key, and of the salts.
+    fn key_len(self) -> usize {
+        match self {
+            Method::Aes128Gcm => 16,
+            Method::Aes256Gcm | Method::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Method::Aes128Gcm => &aead::AES_128_GCM,
+            Method::Aes256Gcm => &aead::AES_256_GCM,
+            Method::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Method::Aes128Gcm, Method::Aes256Gcm, Method::ChaCha20Poly1305].into_iter()
+            .find(|method| method.name() == s)
+            .ok_or_else(|| anyhow!("Unsupported Shadowsocks method '{}' (only 2022-blake3-* ones are)", s))
+    }
+}
+
+/// Method and pre-shared key, written `2022-blake3-aes-256-gcm:<base64 key>`
+/// as in other Shadowsocks configurations.
+#[derive(Clone)]
+pub struct ShadowsocksConfig {
+    method: Method,
+    key: Arc<[u8]>,
+}
+
+impl ShadowsocksConfig {
+    pub fn new(method: Method, key: &[u8]) -> Result<Self> {
+        if key.len() != method.key_len() {
+            return Err(anyhow!("{} needs a {}-byte key, not {}", method.name(), method.key_len(), key.len()));
+        }
+        Ok(Self { method, key: key.into() })
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// The AEAD key of a session, from its salt.
+    fn session_key(&self, salt: &[u8]) -> Cipher {
+        let mut hasher = blake3::Hasher::new_derive_key(SUBKEY_CONTEXT);
+        hasher.update(&self.key);
+        hasher.update(salt);
+        let subkey = hasher.finalize();
+        let key = UnboundKey::new(self.method.algorithm(), &subkey.as_bytes()[..self.method.key_len()])
+            .expect("key length matches the method");
+        Cipher { key: LessSafeKey::new(key), counter: 0 }
+    }
+
+    fn salt(&self) -> Vec<u8> {
+        let mut salt = vec![0u8; self.method.key_len()];
+        rand::thread_rng().fill(&mut salt[..]);
+        salt
+    }
+}
+
+impl FromStr for ShadowsocksConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (method, key) = s.split_once(':')
+            .ok_or_else(|| anyhow!("Expected <method>:<base64 key>, e.g. 2022-blake3-aes-256-gcm:..."))?;
+        let key = BASE64.decode(key.as_bytes())
+            .map_err(|_| anyhow!("Shadowsocks key is not base64"))?;
+        Self::new(method.parse()?, &key)
+    }
+}
+
+impl fmt::Display for ShadowsocksConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key
+        f.write_str(self.method.name())
+    }
+}
+
+/// Dials destinations through a Shadowsocks 2022 server, one TCP
+/// connection each.
+#[derive(Clone)]
+pub struct ShadowsocksClient {
+    config: ShadowsocksConfig,
+    server: String,
+    tcp: TcpTransport,
+}
+
+impl ShadowsocksClient {
+    /// `server` is the server's `host:port`.
+    pub fn new(config: ShadowsocksConfig, server: &str) -> Self {
+        Self { config, server: server.to_string(), tcp: TcpTransport::new() }
+    }
+
+    /// Socket settings of the connections to the server (see `TcpOptions`).
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Reaches the server through `proxy`, which also resolves its name
+    /// (see `TcpTransport::with_upstream_proxy`).
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.tcp = self.tcp.with_upstream_proxy(proxy);
+        self
+    }
+
+    /// A connection to `target` (`host:port`) through the server.
+    pub async fn connect(&self, target: &str) -> Result<Box<dyn super::Connection>> {
+        let mut stream = self.tcp.connect_host(&self.server).await?;
+
+        // Without a payload to go along, the request is padded
+        let mut request = BytesMut::new();
+        put_address(&mut request, target)?;
+        let padding = rand::thread_rng().gen_range(1..=MAX_PADDING);
+        request.put_u16(padding as u16);
+        request.put_bytes(0, padding);
+
+        let salt = self.config.salt();
+        let mut writer = self.config.session_key(&salt);
+        let mut header = Vec::with_capacity(REQUEST_HEADER_LEN);
+        header.put_u8(HEADER_CLIENT);
+        header.put_u64(unix_time());
+        header.put_u16(request.len() as u16);
+        let mut out = salt.clone();
+        writer.seal(&mut out, &header);
+        writer.seal(&mut out, &request);
+        stream.write_all(&out).await?;
+
+        Ok(Box::new(ShadowsocksConnection {
+            stream,
+            config: self.config.clone(),
+            reader: None,
+            read_state: ReadState::Salt,
+            buf: BytesMut::new(),
+            pending: None,
+            writer,
+            request_salt: salt,
+            response_salt: None,
+        }))
+    }
+}
+
+impl FromStr for ShadowsocksClient {
+    type Err = anyhow::Error;
+
+    /// Parses a SIP002 URL: `ss://<method>:<key>@host:port`, with the key
+    /// percent-encoded, or the `<method>:<key>` part in URL-safe base64.
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s.strip_prefix("ss://")
+            .ok_or_else(|| anyhow!("Invalid Shadowsocks URL (expected ss://method:key@host:port)"))?;
+        // Drop the plugin options and the name after '#'
+        let rest = rest.split(['#', '?']).next().unwrap_or(rest).trim_end_matches('/');
+        let (user_info, server) = rest.rsplit_once('@')
+            .ok_or_else(|| anyhow!("Shadowsocks URL needs method:key@host:port"))?;
+        if server.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+            return Err(anyhow!("Shadowsocks URL needs a host:port"));
+        }
+        let user_info = match user_info.contains(':') {
+            true => percent_decode(user_info)?,
+            false => BASE64URL_NOPAD.decode(user_info.trim_end_matches('=').as_bytes()).ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| anyhow!("Malformed Shadowsocks user info"))?,
+        };
+        Ok(Self::new(user_info.parse()?, server))
+    }
+}
+
+impl fmt::Display for ShadowsocksClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ss://{}@{}", self.config, self.server)
+    }
+}
+
+/// Accepts Shadowsocks 2022 clients; each connection comes with the
+/// destination it asked for.
+#[derive(Clone)]
+pub struct ShadowsocksServer {
+    config: ShadowsocksConfig,
+    tcp: TcpTransport,
+}
+
+impl ShadowsocksServer {
+    pub fn new(config: ShadowsocksConfig) -> Self {
+        Self { config, tcp: TcpTransport::new() }
+    }
+
+    /// Socket settings of accepted connections (see `TcpOptions`).
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Expects a PROXY protocol header ahead of the request from `trusted`
+    /// relays (see `TcpTransport::with_proxy_protocol`).
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.tcp = self.tcp.with_proxy_protocol(trusted);
+        self
+    }
+
+    pub fn config(&self) -> &ShadowsocksConfig {
+        &self.config
+    }
+
+    pub async fn listen(&self, endpoint: &super::Endpoint) -> Result<ShadowsocksListener> {
+        let addr = endpoint.inet()?;
+        let listener = self.tcp.bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(100);
+        let salts = Arc::new(SaltFilter::default());
+        tokio::spawn(accept_loop(listener, self.config.clone(), salts, self.tcp.proxy_protocol().cloned(), tx));
+        Ok(ShadowsocksListener { rx, local_addr })
+    }
+}
+
+type Accepted = Result<(ShadowsocksConnection, SocketAddr, String)>;
+
+pub struct ShadowsocksListener {
+    rx: mpsc::Receiver<Accepted>,
+    local_addr: SocketAddr,
+}
+
+impl ShadowsocksListener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The next client, with its address and the `host:port` it wants to
+    /// reach. Data it sent along with the request is the first to arrive.
+    pub async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint, String)> {
+        let (connection, addr, target) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("Shadowsocks listener closed"))??;
+        Ok((Box::new(connection), addr.into(), target))
+    }
+}
+
+/// Reads requests in the background so a slow client cannot stall
+/// `accept()` for everyone else.
+async fn accept_loop(
+    listener: tokio::net::TcpListener,
+    config: ShadowsocksConfig,
+    salts: Arc<SaltFilter>,
+    trusted: Option<TrustedProxies>,
+    tx: mpsc::Sender<Accepted>,
+) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if tx.send(Err(e.into())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        let config = config.clone();
+        let salts = salts.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            let addr = match proxy_protocol::accept_header(&mut stream, addr, trusted.as_ref()).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let mut buf = BytesMut::new();
+            let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_request(&mut stream, &mut buf, &config, &salts)).await
+                .unwrap_or_else(|_| Err(anyhow!("timed out")));
+            let (reader, request_salt, target, payload) = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    let _ = tx.send(Err(anyhow!("Shadowsocks request from {} rejected: {}", addr, e))).await;
+                    // Keep reading, as a server that doesn't know the key would
+                    let _ = tokio::time::timeout(DRAIN_TIMEOUT, tokio::io::copy(&mut stream, &mut tokio::io::sink())).await;
+                    return;
+                }
+            };
+            let salt = config.salt();
+            let connection = ShadowsocksConnection {
+                stream,
+                reader: Some(reader),
+                read_state: ReadState::Length,
+                buf,
+                pending: Some(payload).filter(|payload| !payload.is_empty()),
+                writer: config.session_key(&salt),
+                request_salt,
+                response_salt: Some(salt),
+                config,
+            };
+            let _ = tx.send(Ok((connection, addr, target))).await;
+        });
+    }
+}
+
+/// Reads and checks a client's request: salt, header and the variable part
+/// with the destination. Returns the session's reading half, the salt, the
+/// destination and any payload that came along.
+async fn read_request(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    config: &ShadowsocksConfig,
+    salts: &SaltFilter,
+) -> Result<(Cipher, Vec<u8>, String, Bytes)> {
+    let salt = read_exact(stream, buf, config.method.key_len()).await?.to_vec();
+    let mut reader = config.session_key(&salt);
+    let mut header = read_exact(stream, buf, REQUEST_HEADER_LEN + TAG_LEN).await?;
+    let mut header = reader.open(&mut header)?;
+    if header.get_u8() != HEADER_CLIENT {
+        return Err(anyhow!("not a request"));
+    }
+    check_time(header.get_u64())?;
+    if !salts.insert(&salt) {
+        return Err(anyhow!("replayed salt"));
+    }
+    let len = header.get_u16() as usize;
+    let mut request = read_exact(stream, buf, len + TAG_LEN).await?;
+    let mut request = reader.open(&mut request)?;
+    let target = get_address(&mut request)?;
+    if request.len() < 2 {
+        return Err(anyhow!("truncated request"));
+    }
+    let padding = request.get_u16() as usize;
+    if request.len() < padding {
+        return Err(anyhow!("truncated request"));
+    }
+    request.advance(padding);
+    if padding == 0 && request.is_empty() {
+        return Err(anyhow!("request without padding or payload"));
+    }
+    Ok((reader, salt, target, Bytes::copy_from_slice(request)))
+}
+
+/// Reads until `buf` holds `len` bytes, and takes those.
+async fn read_exact(stream: &mut TcpStream, buf: &mut BytesMut, len: usize) -> Result<BytesMut> {
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(anyhow!("connection closed early"));
+        }
+    }
+    Ok(buf.split_to(len))
+}
+
+/// The AEAD of one direction of a session; nonces count up from zero.
+struct Cipher {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Appends `plaintext`, sealed, to `out`.
+    fn seal(&mut self, out: &mut Vec<u8>, plaintext: &[u8]) {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        let nonce = self.nonce();
+        let tag = self.key.seal_in_place_separate_tag(nonce, Aad::empty(), &mut out[start..])
+            .expect("chunks are far below the AEAD limit");
+        out.extend_from_slice(tag.as_ref());
+    }
+
+    fn open<'a>(&mut self, sealed: &'a mut [u8]) -> Result<&'a [u8]> {
+        let nonce = self.nonce();
+        self.key.open_in_place(nonce, Aad::empty(), sealed)
+            .map(|plaintext| &*plaintext)
+            .map_err(|_| anyhow!("bad key or corrupt data"))
+    }
+}
+
+/// Salts seen lately, to turn away replayed requests.
+#[derive(Default)]
+struct SaltFilter {
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl SaltFilter {
+    /// False if the salt was seen before.
+    fn insert(&self, salt: &[u8]) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| now.duration_since(*at) < SALT_TTL);
+        seen.insert(salt.to_vec(), now).is_none()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReadState {
+    /// The server's salt.
+    Salt,
+    /// The server's response header, with the length of the first chunk.
+    ResponseHeader,
+    Length,
+    Payload(usize),
+}
+
+/// One session: a destination's traffic in AEAD chunks. The client's
+/// reading half is keyed by the server's salt once it arrives; the server
+/// answers its first send with a response header.
+struct ShadowsocksConnection {
+    stream: TcpStream,
+    config: ShadowsocksConfig,
+    reader: Option<Cipher>,
+    read_state: ReadState,
+    buf: BytesMut,
+    /// Payload that came with the request, to be received first.
+    pending: Option<Bytes>,
+    writer: Cipher,
+    request_salt: Vec<u8>,
+    /// The server's salt, while its response header is still to be sent.
+    response_salt: Option<Vec<u8>>,
+}
+
+impl ShadowsocksConnection {
+    /// The next chunk if `buf` holds all of it.
+    fn decode(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            match self.read_state {
+                ReadState::Salt => {
+                    let salt_len = self.config.method.key_len();
+                    if self.buf.len() < salt_len {
+                        return Ok(None);
+                    }
+                    let salt = self.buf.split_to(salt_len);
+                    self.reader = Some(self.config.session_key(&salt));
+                    self.read_state = ReadState::ResponseHeader;
+                }
+                ReadState::ResponseHeader => {
+                    let len = 1 + 8 + self.request_salt.len() + 2 + TAG_LEN;
+                    if self.buf.len() < len {
+                        return Ok(None);
+                    }
+                    let mut sealed = self.buf.split_to(len);
+                    let mut header = self.reader()?.open(&mut sealed)?;
+                    if header.get_u8() != HEADER_SERVER {
+                        return Err(anyhow!("Shadowsocks server sent no response header"));
+                    }
+                    check_time(header.get_u64())?;
+                    if header[..self.request_salt.len()] != self.request_salt[..] {
+                        return Err(anyhow!("Shadowsocks response is not for this request"));
+                    }
+                    header.advance(self.request_salt.len());
+                    self.read_state = ReadState::Payload(header.get_u16() as usize);
+                }
+                ReadState::Length => {
+                    if self.buf.len() < 2 + TAG_LEN {
+                        return Ok(None);
+                    }
+                    let mut sealed = self.buf.split_to(2 + TAG_LEN);
+                    let len = self.reader()?.open(&mut sealed)?.get_u16() as usize;
+                    if len == 0 {
+                        return Err(anyhow!("Empty Shadowsocks chunk"));
+                    }
+                    self.read_state = ReadState::Payload(len);
+                }
+                ReadState::Payload(len) => {
+                    if self.buf.len() < len + TAG_LEN {
+                        return Ok(None);
+                    }
+                    let mut chunk = self.buf.split_to(len + TAG_LEN);
+                    self.read_state = ReadState::Length;
+                    self.reader()?.open(&mut chunk)?;
+                    chunk.truncate(len);
+                    return Ok(Some(chunk.freeze()));
+                }
+            }
+        }
+    }
+
+    fn reader(&mut self) -> Result<&mut Cipher> {
+        self.reader.as_mut().ok_or_else(|| anyhow!("Shadowsocks session has no key yet"))
+    }
+}
+
+#[async_trait]
+impl super::Connection for ShadowsocksConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        for chunk in data.chunks(MAX_CHUNK) {
+            match self.response_salt.take() {
+                Some(salt) => {
+                    out.extend_from_slice(&salt);
+                    let mut header = Vec::with_capacity(1 + 8 + self.request_salt.len() + 2);
+                    header.put_u8(HEADER_SERVER);
+                    header.put_u64(unix_time());
+                    header.put_slice(&self.request_salt);
+                    header.put_u16(chunk.len() as u16);
+                    self.writer.seal(&mut out, &header);
+                }
+                None => self.writer.seal(&mut out, &(chunk.len() as u16).to_be_bytes()),
+            }
+            self.writer.seal(&mut out, chunk);
+        }
+        self.stream.write_all(&out).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        if let Some(payload) = self.pending.take() {
+            return Ok(Some(payload));
+        }
+        loop {
+            if let Some(chunk) = self.decode()? {
+                return Ok(Some(chunk));
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return match (self.read_state, self.buf.is_empty()) {
+                    (ReadState::Salt | ReadState::Length, true) => Ok(None),
+                    _ => Err(anyhow!("Shadowsocks stream ended mid-chunk")),
+                };
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn check_time(timestamp: u64) -> Result<()> {
+    if unix_time().abs_diff(timestamp) > MAX_TIME_DIFF {
+        return Err(anyhow!("timestamp {} is off, check the clocks", timestamp));
+    }
+    Ok(())
+}
+
+/// Writes `host:port` as a SOCKS5-style address.
+fn put_address(buf: &mut BytesMut, target: &str) -> Result<()> {
+    let (host, port) = target.rsplit_once(':')
+        .ok_or_else(|| anyhow!("Destination '{}' needs a port", target))?;
+    let port: u16 = port.parse().map_err(|_| anyhow!("Bad port in '{}'", target))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.put_u8(1);
+            buf.put_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| anyhow!("Host name too long"))?;
+            buf.put_u8(3);
+            buf.put_u8(len);
+            buf.put_slice(host.as_bytes());
+        }
+    }
+    buf.put_u16(port);
+    Ok(())
+}
+
+/// Reads a SOCKS5-style address as `host:port` (`[ip]:port` for IPv6).
+fn get_address(buf: &mut &[u8]) -> Result<String> {
+    let truncated = || anyhow!("truncated address");
+    let kind = *buf.first().ok_or_else(truncated)?;
+    buf.advance(1);
+    let host = match kind {
+        1 if buf.len() >= 4 => {
+            let ip: [u8; 4] = buf[..4].try_into().unwrap();
+            buf.advance(4);
+            IpAddr::from(ip).to_string()
+        }
+        4 if buf.len() >= 16 => {
+            let ip: [u8; 16] = buf[..16].try_into().unwrap();
+            buf.advance(16);
+            format!("[{}]", IpAddr::from(ip))
+        }
+        3 if !buf.is_empty() && buf.len() > buf[0] as usize => {
+            let len = buf[0] as usize;
+            let host = std::str::from_utf8(&buf[1..=len]).map_err(|_| anyhow!("bad host name"))?.to_string();
+            buf.advance(1 + len);
+            host
+        }
+        1 | 3 | 4 => return Err(truncated()),
+        _ => return Err(anyhow!("unknown address type {}", kind)),
+    };
+    if buf.len() < 2 {
+        return Err(truncated());
+    }
+    Ok(format!("{}:{}", host, buf.get_u16()))
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            out.push(byte);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let hex = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        out.push(hex.ok_or_else(|| anyhow!("Bad percent-encoding in '{}'", s))?);
+    }
+    String::from_utf8(out).map_err(|_| anyhow!("Bad percent-encoding in '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Endpoint};
+    use tokio::net::TcpListener;
+
+    fn config() -> ShadowsocksConfig {
+        ShadowsocksConfig::new(Method::Aes256Gcm, &[7; 32]).unwrap()
+    }
+
+    async fn listen() -> ShadowsocksListener {
+        let endpoint: Endpoint = "127.0.0.1:0".parse().unwrap();
+        ShadowsocksServer::new(config()).listen(&endpoint).await.unwrap()
+    }
+
+    /// Receives until `len` bytes have arrived.
+    async fn recv_len(connection: &mut Box<dyn Connection>, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            let data = connection.recv().await.unwrap().expect("closed early");
+            received.extend_from_slice(&data);
+        }
+        received
+    }
+
+    #[test]
+    fn session_key_matches_sip022() {
+        // blake3 derive_key("shadowsocks 2022 session subkey", key || salt) is
+        // 374fca03...92ddda; AES-256-GCM under it, nonce zero, computed apart
+        // from this crate.
+        let key: Vec<u8> = (0..32).collect();
+        let salt: Vec<u8> = (32..64).collect();
+        let config = ShadowsocksConfig::new(Method::Aes256Gcm, &key).unwrap();
+        let mut sealed = Vec::new();
+        config.session_key(&salt).seal(&mut sealed, b"shadowsocks 2022");
+        assert_eq!(
+            data_encoding::HEXLOWER.encode(&sealed),
+            "d9269ab6769c2feb6fe9091252165a4db0654cdf9fcb75ea7046ffde0d30cf1d",
+        );
+        assert_eq!(config.session_key(&salt).open(&mut sealed).unwrap(), b"shadowsocks 2022");
+    }
+
+    #[tokio::test]
+    async fn round_trip_on_localhost() {
+        let mut listener = listen().await;
+        let client = ShadowsocksClient::new(config(), &listener.local_addr().to_string());
+        let mut client = client.connect("example.com:443").await.unwrap();
+        // More than a chunk holds, so it is split
+        let upload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        client.send(Bytes::from(upload.clone())).await.unwrap();
+
+        let (mut server, _, target) = listener.accept().await.unwrap();
+        assert_eq!(target, "example.com:443");
+        assert_eq!(recv_len(&mut server, upload.len()).await, upload);
+
+        server.send(Bytes::from_static(b"response")).await.unwrap();
+        assert_eq!(recv_len(&mut client, 8).await, b"response");
+        client.send(Bytes::from_static(b"again")).await.unwrap();
+        assert_eq!(recv_len(&mut server, 5).await, b"again");
+    }
+
+    #[tokio::test]
+    async fn replayed_salt_is_rejected() {
+        // Capture a request as a passive observer would
+        let capture = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = ShadowsocksClient::new(config(), &capture.local_addr().unwrap().to_string());
+        let (connection, accepted) = tokio::join!(client.connect("example.com:443"), capture.accept());
+        drop(connection.unwrap());
+        let mut recorded = Vec::new();
+        accepted.unwrap().0.read_to_end(&mut recorded).await.unwrap();
+
+        let mut listener = listen().await;
+        let mut first = TcpStream::connect(listener.local_addr()).await.unwrap();
+        first.write_all(&recorded).await.unwrap();
+        let (_, _, target) = listener.accept().await.unwrap();
+        assert_eq!(target, "example.com:443");
+
+        let mut replay = TcpStream::connect(listener.local_addr()).await.unwrap();
+        replay.write_all(&recorded).await.unwrap();
+        let err = listener.accept().await.err().expect("replay accepted");
+        assert!(err.to_string().contains("replayed salt"), "{}", err);
+    }
+}
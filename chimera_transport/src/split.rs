@@ -0,0 +1,106 @@
+//! Halves of split connections: a task serving both for connections that
+//! cannot split themselves, and the channel-backed halves that task (and
+//! connections already driven by a task of their own, like KCP or a bond)
+//! hand out.
+
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::{Connection, RecvHalf, SendHalf};
+
+/// Hands `conn` to a task that serves both halves. Sends and receives
+/// still take turns on the connection, but neither half's caller waits
+/// for the other.
+pub(crate) fn serve(conn: Box<dyn Connection>) -> (Box<dyn RecvHalf>, Box<dyn SendHalf>) {
+    let (send_tx, send_rx) = mpsc::channel(64);
+    let (recv_tx, recv_rx) = mpsc::channel(64);
+    tokio::spawn(drive(conn, send_rx, recv_tx));
+    (Box::new(ChannelRecvHalf::new(recv_rx)), Box::new(ChannelSendHalf::new(Some(send_tx))))
+}
+
+async fn drive(
+    mut conn: Box<dyn Connection>,
+    mut send_rx: mpsc::Receiver<Bytes>,
+    recv_tx: mpsc::Sender<Result<Option<Bytes>>>,
+) {
+    let mut sending = true;
+    let mut receiving = true;
+    // Received, waiting for room in the reader's queue
+    let mut pending: Option<Result<Option<Bytes>>> = None;
+    while sending || receiving || pending.is_some() {
+        tokio::select! {
+            data = send_rx.recv(), if sending => match data {
+                Some(data) => {
+                    // Later sends fail instead of going nowhere
+                    if conn.send(data).await.is_err() {
+                        sending = false;
+                        send_rx.close();
+                    }
+                }
+                None => {
+                    sending = false;
+                    let _ = conn.close().await;
+                }
+            },
+            res = conn.recv(), if receiving && pending.is_none() => {
+                receiving = matches!(res, Ok(Some(_)));
+                pending = Some(res);
+            },
+            permit = recv_tx.reserve(), if pending.is_some() => match permit {
+                Ok(permit) => permit.send(pending.take().unwrap()),
+                Err(_) => {
+                    pending = None;
+                    receiving = false;
+                }
+            },
+            // Nobody left to read
+            _ = recv_tx.closed(), if receiving && pending.is_none() => receiving = false,
+        }
+    }
+}
+
+/// Receives what a task delivers; the task ending without an explicit
+/// error is a clean EOF.
+pub struct ChannelRecvHalf {
+    rx: mpsc::Receiver<Result<Option<Bytes>>>,
+}
+
+impl ChannelRecvHalf {
+    pub fn new(rx: mpsc::Receiver<Result<Option<Bytes>>>) -> Self {
+        Self { rx }
+    }
+}
+
+#[async_trait]
+impl RecvHalf for ChannelRecvHalf {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.rx.recv().await.transpose()?.flatten())
+    }
+}
+
+/// Queues data for a task to send; dropping the sender tells the task the
+/// sending direction is done (`None` if it already is).
+pub struct ChannelSendHalf {
+    tx: Option<mpsc::Sender<Bytes>>,
+}
+
+impl ChannelSendHalf {
+    pub fn new(tx: Option<mpsc::Sender<Bytes>>) -> Self {
+        Self { tx }
+    }
+}
+
+#[async_trait]
+impl SendHalf for ChannelSendHalf {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        let tx = self.tx.as_ref().ok_or_else(|| anyhow!("Connection closed"))?;
+        tx.send(data).await.map_err(|_| anyhow!("Connection terminated"))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+}
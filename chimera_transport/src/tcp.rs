@@ -1,22 +1,473 @@
 use async_trait::async_trait;
-use bytes::Bytes;
-use anyhow::Result;
-use std::net::SocketAddr;
-use tokio::net::{TcpStream, TcpListener};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::{Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io::IoSlice;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{TcpStream, TcpListener, TcpSocket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 
-pub struct TcpTransport;
+use crate::fault::parse_duration;
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::upstream::UpstreamProxy;
+
+/// Bounds of how much a `TcpConnection` asks for per read. It starts at the
+/// smallest, doubles whenever a read fills it, and halves whenever one
+/// comes back less than a quarter full.
+const MIN_READ: usize = 1024;
+const MAX_READ: usize = 64 * 1024;
+
+/// Pending TCP Fast Open requests a listener queues.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+
+/// Socket settings for TCP connections and listeners; anything unset keeps
+/// the system default. Connections accepted by a listener inherit its
+/// settings.
+///
+/// The textual form is a comma-separated list, e.g.
+/// `nodelay,keepalive=30s:10s,sndbuf=4194304,rcvbuf=4194304,fastopen,mark=100,interface=eth0,source=192.0.2.10,connect_timeout=5s`.
+/// `fastopen`, `mark` and `interface` are Linux only.
+#[derive(Debug, Clone, Default)]
+pub struct TcpOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<(Duration, Duration)>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+    fast_open: bool,
+    mark: Option<u32>,
+    interface: Option<String>,
+    source: Option<IpAddr>,
+    connect_timeout: Option<Duration>,
+}
+
+impl TcpOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// TCP_NODELAY: send small writes at once instead of coalescing them.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Keepalive probes after `idle` without traffic, then every
+    /// `interval`, so dead peers and expired NAT mappings show up.
+    pub fn with_keepalive(mut self, idle: Duration, interval: Duration) -> Self {
+        self.keepalive = Some((idle, interval));
+        self
+    }
+
+    /// SO_SNDBUF; raise it to fill long fat links.
+    pub fn with_send_buffer(mut self, bytes: usize) -> Self {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    /// SO_RCVBUF; raise it to fill long fat links.
+    pub fn with_recv_buffer(mut self, bytes: usize) -> Self {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+
+    /// TCP Fast Open: data in the SYN on reconnects, saving a round trip.
+    pub fn with_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    /// SO_MARK, for policy routing (`ip rule add fwmark ...`). Needs
+    /// CAP_NET_ADMIN.
+    pub fn with_mark(mut self, mark: u32) -> Self {
+        self.mark = Some(mark);
+        self
+    }
+
+    /// SO_BINDTODEVICE: only use this network interface.
+    pub fn with_interface(mut self, interface: &str) -> Self {
+        self.interface = Some(interface.to_string());
+        self
+    }
+
+    /// Local address connections are made from.
+    pub fn with_source(mut self, source: IpAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Gives up on connects that take longer than this.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// A non-blocking socket for `addr`'s family with every option set.
+    fn socket(&self, addr: SocketAddr) -> Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some((idle, interval)) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+            #[cfg(not(any(target_os = "openbsd", target_os = "solaris", target_os = "haiku")))]
+            let keepalive = keepalive.with_interval(interval);
+            #[cfg(any(target_os = "openbsd", target_os = "solaris", target_os = "haiku"))]
+            let _ = interval;
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(bytes) = self.send_buffer {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.recv_buffer {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        self.set_linux_options(&socket)?;
+        Ok(socket)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_linux_options(&self, socket: &Socket) -> Result<()> {
+        if let Some(mark) = self.mark {
+            socket.set_mark(mark)?;
+        }
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_linux_options(&self, _socket: &Socket) -> Result<()> {
+        if self.fast_open || self.mark.is_some() || self.interface.is_some() {
+            return Err(anyhow!("TCP fastopen, mark and interface are only supported on Linux"));
+        }
+        Ok(())
+    }
+
+    /// Lets a client socket put data in its SYN.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_fast_open_connect(&self, socket: &Socket) -> Result<()> {
+        if self.fast_open {
+            set_int_option(socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_fast_open_connect(&self, _socket: &Socket) -> Result<()> {
+        Ok(())
+    }
+
+    /// Lets a listening socket accept data in SYNs.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_fast_open_listen(&self, socket: &Socket) -> Result<()> {
+        if self.fast_open {
+            set_int_option(socket, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_fast_open_listen(&self, _socket: &Socket) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sets an `IPPROTO_TCP` option socket2 has no setter for.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_int_option(socket: &Socket, option: libc::c_int, value: libc::c_int) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is open for as long as `socket` is borrowed,
+    // and the value pointer and length describe a live c_int
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+impl FromStr for TcpOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut options = TcpOptions::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            options = match key {
+                "nodelay" => options.with_nodelay(value != "false"),
+                "keepalive" => {
+                    let (idle, interval) = value.split_once(':').unwrap_or((value, value));
+                    options.with_keepalive(parse_duration(idle)?, parse_duration(interval)?)
+                }
+                "sndbuf" => options.with_send_buffer(value.parse()?),
+                "rcvbuf" => options.with_recv_buffer(value.parse()?),
+                "fastopen" => options.with_fast_open(value != "false"),
+                "mark" => options.with_mark(value.parse()?),
+                "interface" => options.with_interface(value),
+                "source" => options.with_source(value.parse()?),
+                "connect_timeout" => options.with_connect_timeout(parse_duration(value)?),
+                _ => return Err(anyhow!("Unknown TCP option '{}'", key)),
+            };
+        }
+        Ok(options)
+    }
+}
+
+/// Send-side tricks against DPI boxes that only look at the first segment
+/// of a flow, or don't reassemble it: the first flight (the handshake the
+/// client opens with) goes out cut into small segments instead of one.
+///
+/// Without a segment size the first flight is cut in two. The textual
+/// form is a comma-separated list, e.g. `split=16,delay=20ms,disorder=1`.
+///
+/// Only the real bytes are ever sent. There is no fake segment, a decoy
+/// carrying junk at the same sequence position and dying on the way (as
+/// zapret's `fake` sends), since that takes raw sockets or splicing
+/// tricks. A box that reassembles by sequence number still sees the real
+/// first flight in the end.
+#[derive(Debug, Clone, Default)]
+pub struct Desync {
+    split: Option<usize>,
+    delay: Option<Duration>,
+    disorder: Option<u32>,
+}
+
+impl Desync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size of the segments the first flight is cut into.
+    pub fn with_split(mut self, bytes: usize) -> Self {
+        self.split = Some(bytes.max(1));
+        self
+    }
+
+    /// Pause between the segments, for boxes that give up reassembling
+    /// a flow that trickles in.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Sends the real first segment with this IP TTL (IPv6 hop limit), too
+    /// low to reach the server: a box further on sees the flow open with
+    /// the segments after it, and the server only gets the first when the
+    /// kernel retransmits it, out of order, with the usual TTL, and after a
+    /// retransmission timeout.
+    pub fn with_disorder(mut self, ttl: u32) -> Self {
+        self.disorder = Some(ttl);
+        self
+    }
+
+    /// Writes the first flight `data` to `stream` segment by segment.
+    async fn send_first_flight(&self, stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+        let size = self.split.unwrap_or(data.len().div_ceil(2)).max(1);
+        // Each write has to leave as a segment of its own
+        let nodelay = stream.nodelay()?;
+        stream.set_nodelay(true)?;
+        for (i, segment) in data.chunks(size).enumerate() {
+            if i > 0 {
+                if let Some(delay) = self.delay {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            match self.disorder.filter(|_| i == 0) {
+                Some(ttl) => {
+                    let usual = set_ttl(stream, ttl)?;
+                    let written = stream.write_all(segment).await;
+                    set_ttl(stream, usual)?;
+                    written?;
+                }
+                None => stream.write_all(segment).await?,
+            }
+        }
+        stream.set_nodelay(nodelay)?;
+        Ok(())
+    }
+}
+
+/// Sets the TTL (hop limit) of `stream`'s packets, returning the old one.
+fn set_ttl(stream: &TcpStream, ttl: u32) -> Result<u32> {
+    let socket = SockRef::from(stream);
+    if stream.peer_addr()?.is_ipv4() {
+        let old = socket.ttl_v4()?;
+        socket.set_ttl_v4(ttl)?;
+        Ok(old)
+    } else {
+        let old = socket.unicast_hops_v6()?;
+        socket.set_unicast_hops_v6(ttl)?;
+        Ok(old)
+    }
+}
+
+impl FromStr for Desync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut desync = Desync::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            desync = match key {
+                "split" => desync.with_split(value.parse()?),
+                "delay" => desync.with_delay(parse_duration(value)?),
+                "disorder" => desync.with_disorder(value.parse()?),
+                _ => return Err(anyhow!("Unknown desync option '{}'", key)),
+            };
+        }
+        Ok(desync)
+    }
+}
+
+#[derive(Clone)]
+pub struct TcpTransport {
+    options: TcpOptions,
+    desync: Option<Desync>,
+    proxy_protocol: Option<TrustedProxies>,
+    upstream: Option<UpstreamProxy>,
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Self { options: TcpOptions::new(), desync: None, proxy_protocol: None, upstream: None }
+    }
+
+    /// Socket settings for the connections it makes and the listeners it binds.
+    pub fn with_options(mut self, options: TcpOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Cuts up the first flight of the connections it makes.
+    pub fn with_desync(mut self, desync: Desync) -> Self {
+        self.desync = Some(desync);
+        self
+    }
+
+    /// Expects a PROXY protocol header (v1 or v2) from connections made by
+    /// `trusted` relays, and reports the client it names as the peer.
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.proxy_protocol = Some(trusted);
+        self
+    }
+
+    /// Dials every connection through `proxy` (SOCKS5 or HTTP CONNECT).
+    /// The socket settings apply to the connection to the proxy.
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.upstream = Some(proxy);
+        self
+    }
+
+    /// Relays whose PROXY protocol headers are accepted, if enabled.
+    pub(crate) fn proxy_protocol(&self) -> Option<&TrustedProxies> {
+        self.proxy_protocol.as_ref()
+    }
+
+    /// Dials a raw TCP stream, for transports layered on top of TCP.
+    pub(crate) async fn connect_stream(&self, addr: SocketAddr) -> Result<TcpStream> {
+        match &self.upstream {
+            Some(proxy) => {
+                let (host, port) = proxy.target(addr);
+                self.connect_through(proxy, &host, port).await
+            }
+            None => self.dial(addr).await,
+        }
+    }
+
+    /// Dials `host:port`, a name being resolved by the upstream proxy when
+    /// there is one and here otherwise.
+    pub(crate) async fn connect_host(&self, host_port: &str) -> Result<TcpStream> {
+        let (host, port) = host_port.rsplit_once(':')
+            .and_then(|(host, port)| Some((host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok()?)))
+            .ok_or_else(|| anyhow!("'{}' is not a host:port", host_port))?;
+        match &self.upstream {
+            Some(proxy) => self.connect_through(proxy, host, port).await,
+            None => {
+                let addr = tokio::net::lookup_host((host, port)).await?
+                    .next()
+                    .ok_or_else(|| anyhow!("Cannot resolve {}", host_port))?;
+                self.dial(addr).await
+            }
+        }
+    }
+
+    async fn connect_through(&self, proxy: &UpstreamProxy, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = self.dial(proxy.resolve().await?).await
+            .map_err(|e| anyhow!("Upstream proxy {}: {}", proxy, e))?;
+        proxy.handshake(&mut stream, host, port).await?;
+        Ok(stream)
+    }
+
+    async fn dial(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let socket = self.options.socket(addr)?;
+        self.options.set_fast_open_connect(&socket)?;
+        if let Some(source) = self.options.source {
+            socket.bind(&SocketAddr::new(source, 0).into())
+                .map_err(|e| anyhow!("Cannot connect to {} from {}: {}", addr, source, e))?;
+        }
+        let socket = TcpSocket::from_std_stream(socket.into());
+        let stream = match self.options.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, socket.connect(addr)).await
+                .map_err(|_| anyhow!("Connect to {} timed out ({:?})", addr, timeout))??,
+            None => socket.connect(addr).await?,
+        };
+        Ok(stream)
+    }
+
+    /// Binds a raw TCP listener, for transports layered on top of TCP.
+    pub(crate) async fn bind(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = self.options.socket(addr)?;
+        // Like tokio's own bind, so restarts don't wait out TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        // `[::]` takes IPv4 clients too, whatever the system default
+        if addr.is_ipv6() && addr.ip().is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+        self.options.set_fast_open_listen(&socket)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[async_trait]
 impl super::Transport for TcpTransport {
-    async fn connect(&self, addr: SocketAddr) -> Result<Box<dyn super::Connection>> {
-        let stream = TcpStream::connect(addr).await?;
-        Ok(Box::new(TcpConnection { stream }))
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let stream = self.connect_stream(addr).await?;
+        Ok(match &self.desync {
+            Some(desync) => Box::new(DesyncConnection { inner: TcpConnection::new(stream), desync: Some(desync.clone()) }),
+            None => Box::new(TcpConnection::new(stream)),
+        })
     }
 
-    async fn listen(&self, addr: SocketAddr) -> Result<Box<dyn super::Listener>> {
-        let listener = TcpListener::bind(addr).await?;
-        Ok(Box::new(TcpListenerWrapper { listener }))
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let listener = self.bind(addr).await?;
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(accept_loop(listener, self.proxy_protocol.clone(), tx));
+        Ok(Box::new(TcpListenerWrapper { rx }))
     }
 
     fn name(&self) -> &str {
@@ -24,24 +475,107 @@ impl super::Transport for TcpTransport {
     }
 }
 
-struct TcpConnection {
-    stream: TcpStream,
+/// Streams a `TcpConnection` runs over, which may sit on a TCP socket.
+pub(crate) trait TcpBacked {
+    /// The TCP socket underneath, if there is one.
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl TcpBacked for TcpStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// Byte-stream connection over TCP, or over any stream layered on it (e.g. TLS).
+///
+/// Reads land in one buffer that received chunks are split off, so its
+/// memory comes back for reuse once the receiver is done with them.
+pub(crate) struct TcpConnection<S = TcpStream> {
+    stream: S,
+    buffer: BytesMut,
+    read_size: usize,
+}
+
+impl<S> TcpConnection<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self { stream, buffer: BytesMut::new(), read_size: MIN_READ }
+    }
 }
 
 #[async_trait]
-impl super::Connection for TcpConnection {
+impl<S> super::Connection for TcpConnection<S>
+where
+    S: AsyncRead + AsyncWrite + TcpBacked + Unpin + Send + Sync + 'static,
+{
     async fn send(&mut self, data: Bytes) -> Result<()> {
-        self.stream.write_all(&data).await?;
-        Ok(())
+        super::SendHalf::send(self, data).await
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        super::SendHalf::send_vectored(self, data).await
     }
 
     async fn recv(&mut self) -> Result<Option<Bytes>> {
-        let mut buf = vec![0u8; 1024];
-        let n = self.stream.read(&mut buf).await?;
+        super::RecvHalf::recv(self).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        super::SendHalf::close(self).await
+    }
+
+    fn reset_handle(&self) -> Option<super::ResetHandle> {
+        let socket = SockRef::from(self.stream.tcp_stream()?).try_clone().ok()?;
+        Some(super::ResetHandle::new(socket))
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        let (reader, writer) = tokio::io::split(self.stream);
+        let reader = TcpConnection { stream: reader, buffer: self.buffer, read_size: self.read_size };
+        (Box::new(reader), Box::new(TcpConnection::new(writer)))
+    }
+}
+
+/// A split `TcpConnection` is two of them, one over each half of the stream.
+#[async_trait]
+impl<S: AsyncRead + Unpin + Send + Sync> super::RecvHalf for TcpConnection<S> {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        // Reclaims the buffer in place once earlier chunks are dropped
+        self.buffer.reserve(self.read_size);
+        let n = self.stream.read_buf(&mut self.buffer).await?;
         if n == 0 {
             return Ok(None);
         }
-        Ok(Some(Bytes::copy_from_slice(&buf[..n])))
+        if n >= self.read_size {
+            self.read_size = (self.read_size * 2).min(MAX_READ);
+        } else if n < self.read_size / 4 {
+            self.read_size = (self.read_size / 2).max(MIN_READ);
+        }
+        Ok(Some(self.buffer.split().freeze()))
+    }
+}
+
+#[async_trait]
+impl<S: AsyncWrite + Unpin + Send + Sync> super::SendHalf for TcpConnection<S> {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.stream.write_all(&data).await?;
+        Ok(())
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        let mut slices: Vec<IoSlice> = data.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        let mut remaining = &mut slices[..];
+        IoSlice::advance_slices(&mut remaining, 0);
+        while !remaining.is_empty() {
+            let n = self.stream.write_vectored(remaining).await?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+            }
+            IoSlice::advance_slices(&mut remaining, n);
+        }
+        Ok(())
     }
 
     async fn close(&mut self) -> Result<()> {
@@ -50,14 +584,182 @@ impl super::Connection for TcpConnection {
     }
 }
 
+/// TCP connection whose first send goes out as a `Desync` says.
+struct DesyncConnection {
+    inner: TcpConnection,
+    desync: Option<Desync>,
+}
+
+#[async_trait]
+impl super::Connection for DesyncConnection {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        match self.desync.take() {
+            Some(desync) => desync.send_first_flight(&mut self.inner.stream, &data).await,
+            None => self.inner.send(data).await,
+        }
+    }
+
+    async fn send_vectored(&mut self, data: &[Bytes]) -> Result<()> {
+        match self.desync {
+            // The first flight goes out on its own
+            Some(_) => {
+                for chunk in data {
+                    self.send(chunk.clone()).await?;
+                }
+                Ok(())
+            }
+            None => self.inner.send_vectored(data).await,
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        self.inner.recv().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    fn reset_handle(&self) -> Option<super::ResetHandle> {
+        self.inner.reset_handle()
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        match self.desync {
+            // The first flight needs the whole stream
+            Some(_) => crate::split::serve(self),
+            None => Box::new(self.inner).split(),
+        }
+    }
+}
+
+type Accepted = Result<(TcpStream, SocketAddr)>;
+
 struct TcpListenerWrapper {
-    listener: TcpListener,
+    rx: mpsc::Receiver<Accepted>,
 }
 
 #[async_trait]
 impl super::Listener for TcpListenerWrapper {
-    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, SocketAddr)> {
-        let (stream, addr) = self.listener.accept().await?;
-        Ok((Box::new(TcpConnection { stream }), addr))
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (stream, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("TCP listener closed"))??;
+        Ok((Box::new(TcpConnection::new(stream)), addr.into()))
+    }
+}
+
+/// Reads PROXY protocol headers in the background so a slow or broken
+/// relay cannot stall `accept()` for everyone else.
+async fn accept_loop(listener: TcpListener, trusted: Option<TrustedProxies>, tx: mpsc::Sender<Accepted>) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if tx.send(Err(e.into())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            let result = proxy_protocol::accept_header(&mut stream, addr, trusted.as_ref()).await
+                .map(|addr| (stream, addr));
+            let _ = tx.send(result).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Endpoint, Transport};
+
+    #[tokio::test]
+    async fn silent_relay_does_not_stall_accept() {
+        let probe = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = probe.local_addr().unwrap();
+        drop(probe);
+        let tcp = TcpTransport::new().with_proxy_protocol("127.0.0.1".parse().unwrap());
+        let mut listener = tcp.listen(&Endpoint::Inet(addr)).await.unwrap();
+
+        // A trusted relay that never sends its header, then one that does
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let mut relay = TcpStream::connect(addr).await.unwrap();
+        relay.write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 40000 443\r\nhello").await.unwrap();
+
+        let (mut conn, from) = tokio::time::timeout(Duration::from_secs(2), listener.accept()).await
+            .expect("accept waited on the silent relay")
+            .unwrap();
+        assert_eq!(from, Endpoint::Inet("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(&conn.recv().await.unwrap().unwrap()[..], b"hello");
+    }
+
+    /// A connected pair of streams on localhost.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn first_flight_trickles_in_split_and_delayed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let desync = Desync::new().with_split(4).with_delay(Duration::from_millis(150));
+        let tcp = TcpTransport::new().with_desync(desync);
+        let endpoint = Endpoint::Inet(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(tcp.connect(&endpoint), listener.accept());
+        let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+
+        let started = std::time::Instant::now();
+        let (sent, first) = tokio::join!(client.send(Bytes::from_static(b"0123456789")), async {
+            let mut buf = [0u8; 64];
+            let n = server.read(&mut buf).await.unwrap();
+            buf[..n].to_vec()
+        });
+        sent.unwrap();
+        // Two pauses between three segments, the first read on its own
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert_eq!(first, b"0123");
+        let mut rest = [0u8; 6];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"456789");
+
+        // Later sends go out as they are
+        let started = std::time::Instant::now();
+        client.send(Bytes::from_static(b"abcdefghij")).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(150));
+        let mut later = [0u8; 10];
+        server.read_exact(&mut later).await.unwrap();
+        assert_eq!(&later, b"abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn disorder_sends_everything_and_restores_the_socket() {
+        let (mut client, mut server) = pair().await;
+        let ttl = client.ttl().unwrap();
+        let desync = Desync::new().with_split(4).with_disorder(1);
+        // Loopback delivers even TTL 1, so all of it arrives straight away
+        desync.send_first_flight(&mut client, b"0123456789").await.unwrap();
+        let mut received = [0u8; 10];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"0123456789");
+
+        assert_eq!(client.ttl().unwrap(), ttl);
+        assert!(!client.nodelay().unwrap());
+    }
+
+    #[test]
+    fn desync_strategies_parse() {
+        let desync: Desync = "split=4, delay=50ms, disorder=3".parse().unwrap();
+        assert_eq!(desync.split, Some(4));
+        assert_eq!(desync.delay, Some(Duration::from_millis(50)));
+        assert_eq!(desync.disorder, Some(3));
+        assert!("ttl=3".parse::<Desync>().is_err());
+        assert!("split=x".parse::<Desync>().is_err());
     }
 }
@@ -0,0 +1,331 @@
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::tcp::{TcpBacked, TcpConnection, TcpOptions, TcpTransport};
+use crate::upstream::UpstreamProxy;
+
+/// TLS handshakes that take longer than this are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS 1.3 on top of `TcpTransport`, so the outer layer is ordinary HTTPS.
+///
+/// Clients verify the server against the web PKI roots, or, when a pin is
+/// set, only accept a certificate with that SHA-256 fingerprint. Pinning is
+/// what makes self-signed server certificates usable.
+pub struct TlsTransport {
+    tcp: TcpTransport,
+    server_name: Option<String>,
+    alpn: Vec<Vec<u8>>,
+    pinned_sha256: Option<Vec<u8>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl TlsTransport {
+    pub fn new() -> Self {
+        Self {
+            tcp: TcpTransport::new(),
+            server_name: None,
+            alpn: vec![b"http/1.1".to_vec()],
+            pinned_sha256: None,
+            identity: None,
+        }
+    }
+
+    /// SNI sent by the client. Without it the dialed IP is used and no SNI is sent.
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    /// ALPN protocols offered by the client, or accepted by the server.
+    pub fn with_alpn(mut self, protocols: &[&str]) -> Self {
+        self.alpn = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        self
+    }
+
+    /// Only accept a server certificate with this SHA-256 fingerprint
+    /// (hex, colons optional). Name and expiry checks are skipped.
+    pub fn with_pinned_cert(mut self, sha256_hex: &str) -> Result<Self> {
+        let hex: String = sha256_hex.chars().filter(|c| *c != ':').collect();
+        if hex.len() != 64 {
+            return Err(anyhow!("Certificate pin must be a SHA-256 fingerprint"));
+        }
+        let pin = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| anyhow!("Certificate pin is not valid hex"))?;
+        self.pinned_sha256 = Some(pin);
+        Ok(self)
+    }
+
+    /// Server certificate chain and private key, both PEM.
+    pub fn with_cert_files(mut self, cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .map_err(|e| anyhow!("Cannot read {}: {}", cert_path.display(), e))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| anyhow!("Cannot read {}: {}", key_path.display(), e))?;
+        self.identity = Some((certs, key));
+        Ok(self)
+    }
+
+    /// Generates a throwaway self-signed server certificate for `names`.
+    /// Clients need its `fingerprint()` as their pin.
+    pub fn with_self_signed(mut self, names: &[&str]) -> Result<Self> {
+        let names = names.iter().map(|n| n.to_string()).collect();
+        let (cert, key) = self_signed(names)?;
+        self.identity = Some((vec![cert], key));
+        Ok(self)
+    }
+
+    /// SHA-256 fingerprint (hex) of the server's leaf certificate, if one is set.
+    pub fn fingerprint(&self) -> Option<String> {
+        let (certs, _) = self.identity.as_ref()?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, certs.first()?.as_ref());
+        Some(digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Socket settings of the TCP connections underneath (see `TcpOptions`).
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Dials through `proxy` (see `TcpTransport::with_upstream_proxy`).
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.tcp = self.tcp.with_upstream_proxy(proxy);
+        self
+    }
+
+    /// Expects a PROXY protocol header ahead of the TLS handshake from
+    /// `trusted` relays (see `TcpTransport::with_proxy_protocol`).
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.tcp = self.tcp.with_proxy_protocol(trusted);
+        self
+    }
+
+    /// Dials a TLS stream, for transports layered on top of TLS.
+    pub(crate) async fn connect_stream(&self, addr: SocketAddr) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone())?,
+            None => ServerName::IpAddress(addr.ip().into()),
+        };
+        let connector = TlsConnector::from(Arc::new(self.client_config()?));
+
+        let stream = self.tcp.connect_stream(addr).await?;
+        Ok(connector.connect(server_name, stream).await?)
+    }
+
+    /// Binds a TCP listener plus the acceptor for its TLS handshakes,
+    /// for transports layered on top of TLS.
+    pub(crate) async fn bind(&self, addr: SocketAddr) -> Result<(tokio::net::TcpListener, TlsAcceptor)> {
+        let acceptor = TlsAcceptor::from(Arc::new(self.server_config()?));
+        let listener = self.tcp.bind(addr).await?;
+        Ok((listener, acceptor))
+    }
+
+    fn client_config(&self) -> Result<rustls::ClientConfig> {
+        let provider = crypto_provider();
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?;
+        let mut config = match &self.pinned_sha256 {
+            Some(pin) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCert { pin: pin.clone(), provider }))
+                .with_no_client_auth(),
+            None => {
+                let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+        };
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+
+    fn server_config(&self) -> Result<rustls::ServerConfig> {
+        let (certs, key) = self.identity.as_ref()
+            .ok_or_else(|| anyhow!("TLS listener needs a certificate (cert files or self-signed)"))?;
+        let mut config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+}
+
+impl Clone for TlsTransport {
+    fn clone(&self) -> Self {
+        Self {
+            tcp: self.tcp.clone(),
+            server_name: self.server_name.clone(),
+            alpn: self.alpn.clone(),
+            pinned_sha256: self.pinned_sha256.clone(),
+            identity: self.identity.as_ref().map(|(certs, key)| (certs.clone(), key.clone_key())),
+        }
+    }
+}
+
+impl Default for TlsTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for TlsTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let tls = self.connect_stream(addr).await?;
+        Ok(Box::new(TcpConnection::new(tls)))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let (listener, acceptor) = self.bind(addr).await?;
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(accept_loop(listener, acceptor, self.tcp.proxy_protocol().cloned(), tx));
+        Ok(Box::new(TlsListener { rx }))
+    }
+
+    fn name(&self) -> &str {
+        "TLS"
+    }
+}
+
+impl TcpBacked for tokio_rustls::client::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().0)
+    }
+}
+
+impl TcpBacked for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().0)
+    }
+}
+
+type Accepted = Result<(TcpConnection<tokio_rustls::server::TlsStream<TcpStream>>, SocketAddr)>;
+
+struct TlsListener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for TlsListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("TLS listener closed"))??;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Runs TLS handshakes in the background so a slow client
+/// cannot stall `accept()` for everyone else.
+async fn accept_loop(listener: tokio::net::TcpListener, acceptor: TlsAcceptor, trusted: Option<TrustedProxies>, tx: mpsc::Sender<Accepted>) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if tx.send(Err(e.into())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        let acceptor = acceptor.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            let addr = match proxy_protocol::accept_header(&mut stream, addr, trusted.as_ref()).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let result = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls)) => Ok((TcpConnection::new(tls), addr)),
+                Ok(Err(e)) => Err(anyhow!("TLS handshake from {} failed: {}", addr, e)),
+                Err(_) => Err(anyhow!("TLS handshake from {} timed out", addr)),
+            };
+            let _ = tx.send(result).await;
+        });
+    }
+}
+
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Generates a self-signed certificate and its key.
+pub(crate) fn self_signed(names: Vec<String>) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    Ok((cert, key.into()))
+}
+
+/// Accepts exactly one leaf certificate, identified by its SHA-256 digest.
+#[derive(Debug)]
+struct PinnedCert {
+    pin: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity.as_ref());
+        if digest.as_ref() == self.pin.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("Server certificate does not match pin".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
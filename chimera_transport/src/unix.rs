@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::tcp::{TcpBacked, TcpConnection};
+use super::Endpoint;
+
+/// Byte stream over a Unix domain socket, for chaining with daemons on the
+/// same host (sidecars, local relays) without going through loopback TCP.
+///
+/// Takes `Endpoint::Unix` socket files and, on Linux, `Endpoint::Abstract`
+/// names. A stale socket file left behind by a previous run is replaced
+/// on `listen`, and the listener removes its file when dropped.
+pub struct UnixTransport;
+
+#[async_trait]
+impl super::Transport for UnixTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Connection>> {
+        let stream = match endpoint {
+            Endpoint::Unix(path) => UnixStream::connect(path).await?,
+            Endpoint::Abstract(name) => connect_abstract(name).await?,
+            _ => return Err(anyhow!("Unix transport cannot connect to {}", endpoint)),
+        };
+        Ok(Box::new(TcpConnection::new(stream)))
+    }
+
+    async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn super::Listener>> {
+        let (listener, path) = match endpoint {
+            Endpoint::Unix(path) => (bind_path(path).await?, Some(path.clone())),
+            Endpoint::Abstract(name) => (bind_abstract(name)?, None),
+            _ => return Err(anyhow!("Unix transport cannot listen on {}", endpoint)),
+        };
+        Ok(Box::new(UnixListenerWrapper { listener, endpoint: endpoint.clone(), path }))
+    }
+
+    fn name(&self) -> &str {
+        "Unix"
+    }
+}
+
+/// Binds a socket file. If the path is taken by a socket nobody listens on
+/// any more, it is removed and bound again; anything else is an error.
+async fn bind_path(path: &Path) -> Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            let is_socket = std::fs::symlink_metadata(path)?.file_type().is_socket();
+            if !is_socket || UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow!("{} is already in use", path.display()));
+            }
+            std::fs::remove_file(path)?;
+            Ok(UnixListener::bind(path)?)
+        }
+        res => Ok(res?),
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn connect_abstract(name: &[u8]) -> Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    // Only std can address abstract sockets, and its connect blocks (briefly,
+    // unless the listener's backlog is full)
+    let stream = tokio::task::spawn_blocking(move || std::os::unix::net::UnixStream::connect_addr(&addr)).await??;
+    stream.set_nonblocking(true)?;
+    Ok(UnixStream::from_std(stream)?)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &[u8]) -> Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(UnixListener::from_std(listener)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_abstract(_name: &[u8]) -> Result<UnixStream> {
+    Err(anyhow!("Abstract Unix sockets are only available on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &[u8]) -> Result<UnixListener> {
+    Err(anyhow!("Abstract Unix sockets are only available on Linux"))
+}
+
+struct UnixListenerWrapper {
+    listener: UnixListener,
+    endpoint: Endpoint,
+    // Socket file to clean up on drop (none for abstract sockets)
+    path: Option<PathBuf>,
+}
+
+#[async_trait]
+impl super::Listener for UnixListenerWrapper {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, Endpoint)> {
+        let (stream, peer) = self.listener.accept().await?;
+        // Clients rarely bind a name of their own; those are reported by
+        // the socket they came in on
+        let peer = match peer.as_pathname() {
+            Some(path) => Endpoint::Unix(path.to_path_buf()),
+            None => self.endpoint.clone(),
+        };
+        Ok((Box::new(TcpConnection::new(stream)), peer))
+    }
+}
+
+// Unix sockets have no reset: a dropped connection is simply closed
+impl TcpBacked for UnixStream {}
+
+impl Drop for UnixListenerWrapper {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use anyhow::{Result, anyhow};
+use data_encoding::BASE64;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest CONNECT response head we are willing to read.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    /// HTTP proxy, via `CONNECT host:port`.
+    HttpConnect,
+}
+
+/// An upstream proxy to dial the server through, such as a mandatory
+/// corporate proxy or a local Tor SOCKS port.
+///
+/// The textual form is `socks5://[user:pass@]host:port` or
+/// `http://[user:pass@]host:port`. `TcpTransport::with_upstream_proxy`
+/// puts it under every transport built on TCP.
+#[derive(Clone)]
+pub struct UpstreamProxy {
+    kind: ProxyKind,
+    // host:port, resolved on every connect
+    addr: String,
+    credentials: Option<(String, String)>,
+    // Addresses asked for by name instead
+    names: Vec<(IpAddr, String)>,
+}
+
+impl UpstreamProxy {
+    pub fn new(kind: ProxyKind, addr: &str) -> Self {
+        Self { kind, addr: addr.to_string(), credentials: None, names: Vec::new() }
+    }
+
+    /// Username and password (SOCKS5 username/password authentication, or
+    /// HTTP Basic `Proxy-Authorization`).
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Asks the proxy for `name` whenever `ip` is dialed, so the name is
+    /// resolved at the proxy's end and never looked up here (`ip` is then
+    /// typically a placeholder such as 0.0.0.0).
+    pub fn with_remote_name(mut self, ip: IpAddr, name: &str) -> Self {
+        self.names.retain(|(known, _)| *known != ip);
+        self.names.push((ip, name.to_string()));
+        self
+    }
+
+    /// The proxy's own address.
+    pub(crate) async fn resolve(&self) -> Result<SocketAddr> {
+        tokio::net::lookup_host(self.addr.as_str()).await
+            .map_err(|e| anyhow!("Upstream proxy {}: {}", self.addr, e))?
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve upstream proxy {}", self.addr))
+    }
+
+    /// Host (name or IP literal) and port the proxy is asked for to reach
+    /// `target`.
+    pub(crate) fn target(&self, target: SocketAddr) -> (String, u16) {
+        let host = self.names.iter()
+            .find(|(ip, _)| *ip == target.ip())
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| target.ip().to_string());
+        (host, target.port())
+    }
+
+    /// Has the proxy at the other end of `stream` open a tunnel to
+    /// `host:port`.
+    pub(crate) async fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_handshake(stream, host, port).await,
+            ProxyKind::HttpConnect => self.connect_handshake(stream, host, port).await,
+        }
+    }
+
+    async fn socks5_handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        // Greeting: [VER, NMETHODS, METHODS...], offering username/password
+        // only when we have some
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[0x05, 0x02, 0x00, 0x02],
+            None => &[0x05, 0x01, 0x00],
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != 0x05 {
+            return Err(anyhow!("Upstream proxy is not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (0x00, _) => {}
+            (0x02, Some((username, password))) => {
+                // RFC 1929: [VER=1, ULEN, UNAME, PLEN, PASSWD]
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(anyhow!("SOCKS5 username and password are limited to 255 bytes"));
+                }
+                let mut auth = vec![0x01, username.len() as u8];
+                auth.extend_from_slice(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(anyhow!("SOCKS5 proxy rejected the credentials"));
+                }
+            }
+            _ => return Err(anyhow!("SOCKS5 proxy accepts none of our authentication methods")),
+        }
+
+        // Request: [VER, CMD=CONNECT, RSV, ATYP, DST.ADDR, DST.PORT]
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            // A name, for the proxy to resolve
+            Err(_) => {
+                if host.is_empty() || host.len() > 255 {
+                    return Err(anyhow!("SOCKS5 cannot ask for host name '{}'", host));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+        let target = authority(host, port);
+
+        // Reply: [VER, REP, RSV, ATYP, BND.ADDR, BND.PORT]
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(anyhow!("SOCKS5 proxy could not connect to {}: {}", target, socks5_error(reply[1])));
+        }
+        let bound_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            atyp => return Err(anyhow!("SOCKS5 proxy replied with unknown address type {}", atyp)),
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn connect_handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let target = authority(host, port);
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((username, password)) = &self.credentials {
+            let token = BASE64.encode(format!("{}:{}", username, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing after the head (the tunnel itself)
+        // is consumed here
+        let mut head = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_HEAD {
+                return Err(anyhow!("HTTP proxy response head too long"));
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        response.parse(&head)?;
+        match response.code {
+            Some(200..=299) => Ok(()),
+            Some(407) => Err(anyhow!("HTTP proxy requires authentication")),
+            Some(code) => Err(anyhow!("HTTP proxy refused CONNECT to {}: {} {}", target, code, response.reason.unwrap_or(""))),
+            None => Err(anyhow!("Malformed HTTP proxy response")),
+        }
+    }
+}
+
+/// `host:port`, with IPv6 literals in brackets.
+fn authority(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, rest) = s.split_once("://")
+            .ok_or_else(|| anyhow!("Invalid upstream proxy '{}' (expected socks5://host:port or http://host:port)", s))?;
+        let kind = match scheme {
+            "socks5" => ProxyKind::Socks5,
+            "http" => ProxyKind::HttpConnect,
+            _ => return Err(anyhow!("Unsupported upstream proxy scheme '{}'", scheme)),
+        };
+        let rest = rest.trim_end_matches('/');
+        // Passwords may contain '@', host names may not
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((credentials, addr)) => {
+                let (username, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((username.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        if addr.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+            return Err(anyhow!("Upstream proxy '{}' needs a host:port", s));
+        }
+        Ok(Self { kind, addr: addr.to_string(), credentials, names: Vec::new() })
+    }
+}
+
+impl fmt::Display for UpstreamProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the password
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::HttpConnect => "http",
+        };
+        match &self.credentials {
+            Some((username, _)) => write!(f, "{}://{}@{}", scheme, username, self.addr),
+            None => write!(f, "{}://{}", scheme, self.addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Side, TransportParams, TransportRegistry};
+    use crate::tcp::{TcpOptions, TcpTransport};
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    /// What a stub proxy was asked to connect to.
+    type Asked = tokio::task::JoinHandle<Vec<u8>>;
+
+    /// A SOCKS5 proxy that accepts one connection without authentication,
+    /// returns the address part of its request and echoes the tunnel.
+    async fn socks5_stub() -> (SocketAddr, Asked) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let asked = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = vec![0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..3], &[0x05, 0x01, 0x00]);
+            let len = match request[3] {
+                0x01 => 4,
+                0x04 => 16,
+                _ => {
+                    let len = stream.read_u8().await.unwrap();
+                    request.push(len);
+                    len as usize
+                }
+            };
+            let start = request.len();
+            request.resize(start + len + 2, 0);
+            stream.read_exact(&mut request[start..]).await.unwrap();
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            echo(stream).await;
+            request[3..].to_vec()
+        });
+        (addr, asked)
+    }
+
+    /// An HTTP proxy that accepts one CONNECT, returns its request head and
+    /// echoes the tunnel.
+    async fn connect_stub() -> (SocketAddr, Asked) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let asked = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+            echo(stream).await;
+            head
+        });
+        (addr, asked)
+    }
+
+    async fn echo(mut stream: TcpStream) {
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        stream.write_all(&buf[..n]).await.unwrap();
+    }
+
+    async fn round_trip(stream: &mut TcpStream) {
+        stream.write_all(b"through").await.unwrap();
+        let mut buf = [0u8; 7];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"through");
+    }
+
+    fn placeholder() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443))
+    }
+
+    #[tokio::test]
+    async fn socks5_is_asked_for_the_server_by_name() {
+        let (addr, asked) = socks5_stub().await;
+        let proxy = UpstreamProxy::new(ProxyKind::Socks5, &addr.to_string())
+            .with_remote_name(placeholder().ip(), "server.example");
+        let options: TcpOptions = "nodelay".parse().unwrap();
+        let tcp = TcpTransport::new().with_options(options).with_upstream_proxy(proxy);
+
+        let mut stream = tcp.connect_stream(placeholder()).await.unwrap();
+        // The socket settings are those of the connection to the proxy
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        round_trip(&mut stream).await;
+
+        let mut expected = vec![0x03, 14];
+        expected.extend_from_slice(b"server.example");
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(asked.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn socks5_is_asked_for_addresses_it_has_no_name_for() {
+        let (addr, asked) = socks5_stub().await;
+        let proxy = UpstreamProxy::new(ProxyKind::Socks5, &addr.to_string())
+            .with_remote_name(placeholder().ip(), "server.example");
+        let tcp = TcpTransport::new().with_upstream_proxy(proxy);
+
+        let mut stream = tcp.connect_stream("192.0.2.7:8443".parse().unwrap()).await.unwrap();
+        round_trip(&mut stream).await;
+        assert_eq!(asked.await.unwrap(), vec![0x01, 192, 0, 2, 7, 0x20, 0xfb]);
+    }
+
+    #[tokio::test]
+    async fn connect_is_asked_for_the_server_by_name() {
+        let (addr, asked) = connect_stub().await;
+        let proxy: UpstreamProxy = format!("http://user:p@ss@{}", addr).parse().unwrap();
+        let tcp = TcpTransport::new().with_upstream_proxy(proxy.with_remote_name(placeholder().ip(), "server.example"));
+
+        let mut stream = tcp.connect_stream(placeholder()).await.unwrap();
+        round_trip(&mut stream).await;
+
+        let head = String::from_utf8(asked.await.unwrap()).unwrap();
+        assert!(head.starts_with("CONNECT server.example:443 HTTP/1.1\r\n"), "{}", head);
+        assert!(head.contains("Host: server.example:443\r\n"), "{}", head);
+        let token = BASE64.encode(b"user:p@ss");
+        assert!(head.contains(&format!("Proxy-Authorization: Basic {}\r\n", token)), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn host_names_are_left_to_the_proxy() {
+        let (addr, asked) = connect_stub().await;
+        let tcp = TcpTransport::new().with_upstream_proxy(UpstreamProxy::new(ProxyKind::HttpConnect, &addr.to_string()));
+
+        // Would not resolve here
+        let mut stream = tcp.connect_host("ss.invalid:8388").await.unwrap();
+        round_trip(&mut stream).await;
+
+        let head = String::from_utf8(asked.await.unwrap()).unwrap();
+        assert!(head.starts_with("CONNECT ss.invalid:8388 HTTP/1.1\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn udp_paths_are_left_out_behind_a_proxy() {
+        let registry = TransportRegistry::builtin();
+        let params = TransportParams::new(Side::Client, placeholder())
+            .with_setting("SERVER_HOST", "server.example")
+            .with_setting("CLIENT_UPSTREAM_PROXY", "socks5://127.0.0.1:9050");
+        let names: Vec<String> = registry.build_all(&params).unwrap()
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        assert!(names.iter().any(|name| name == "WebSocket"));
+        for udp in ["QUIC", "KCP", "DNS"] {
+            assert!(!names.iter().any(|name| name == udp), "{} is dialed around the proxy", udp);
+        }
+    }
+}
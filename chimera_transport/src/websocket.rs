@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+use std::time::Duration;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::proxy_protocol::{self, TrustedProxies};
+use crate::tcp::{TcpOptions, TcpTransport};
+use crate::upstream::UpstreamProxy;
+
+/// Upgrades that take longer than this are dropped.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0 Safari/537.36";
+
+/// Tunnels over a WebSocket, so the server can sit behind nginx or a CDN.
+/// The byte stream is carried in binary messages, one message per `send`.
+pub struct WebSocketTransport {
+    path: String,
+    host: Option<String>,
+    proxy_protocol: Option<TrustedProxies>,
+    forwarded_for: Option<TrustedProxies>,
+    tcp: TcpTransport,
+}
+
+impl WebSocketTransport {
+    pub fn new() -> Self {
+        Self {
+            path: "/ws".to_string(),
+            host: None,
+            proxy_protocol: None,
+            forwarded_for: None,
+            tcp: TcpTransport::new(),
+        }
+    }
+
+    /// Request path used for the upgrade; the server rejects any other path.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Host header sent by the client (e.g. the CDN-fronted domain).
+    /// Defaults to the address being dialed.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Socket settings of the client's connections (see `TcpOptions`).
+    pub fn with_tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp = self.tcp.with_options(options);
+        self
+    }
+
+    /// Dials through `proxy` (see `TcpTransport::with_upstream_proxy`).
+    pub fn with_upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.tcp = self.tcp.with_upstream_proxy(proxy);
+        self
+    }
+
+    /// Expects a PROXY protocol header from `trusted` relays (see
+    /// `TcpTransport::with_proxy_protocol`).
+    pub fn with_proxy_protocol(mut self, trusted: TrustedProxies) -> Self {
+        self.proxy_protocol = Some(trusted);
+        self
+    }
+
+    /// Takes the client from `X-Forwarded-For` when the upgrade comes
+    /// through `trusted` reverse proxies (e.g. nginx or a CDN).
+    pub fn with_forwarded_for(mut self, trusted: TrustedProxies) -> Self {
+        self.forwarded_for = Some(trusted);
+        self
+    }
+}
+
+impl Default for WebSocketTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Transport for WebSocketTransport {
+    async fn connect(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Connection>> {
+        let addr = endpoint.inet()?;
+        let host = self.host.clone().unwrap_or_else(|| addr.to_string());
+        let mut request = format!("ws://{}{}", host, self.path).into_client_request()?;
+        request.headers_mut().insert("User-Agent", HeaderValue::from_static(USER_AGENT));
+
+        let stream = self.tcp.connect_stream(addr).await?;
+        let (ws, _response) = tokio_tungstenite::client_async(request, stream).await?;
+        Ok(Box::new(WebSocketConnection { ws }))
+    }
+
+    async fn listen(&self, endpoint: &super::Endpoint) -> Result<Box<dyn super::Listener>> {
+        let addr = endpoint.inet()?;
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel(100);
+        let trusted = Trusted {
+            proxy_protocol: self.proxy_protocol.clone(),
+            forwarded_for: self.forwarded_for.clone(),
+        };
+        tokio::spawn(accept_loop(listener, self.path.clone(), trusted, tx));
+        Ok(Box::new(WebSocketListener { rx }))
+    }
+
+    fn name(&self) -> &str {
+        "WebSocket"
+    }
+}
+
+struct WebSocketConnection<S> {
+    ws: WebSocketStream<S>,
+}
+
+#[async_trait]
+impl<S> super::Connection for WebSocketConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.ws.send(Message::Binary(data)).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        next_data(&mut self.ws).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn super::RecvHalf>, Box<dyn super::SendHalf>) {
+        let (sink, stream) = self.ws.split();
+        (Box::new(WebSocketRecvHalf { stream }), Box::new(WebSocketSendHalf { sink }))
+    }
+}
+
+/// The next binary message's data; `None` once the peer closes.
+async fn next_data<St>(stream: &mut St) -> Result<Option<Bytes>>
+where
+    St: Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    while let Some(message) = stream.next().await {
+        match message? {
+            Message::Binary(data) => return Ok(Some(data)),
+            Message::Close(_) => return Ok(None),
+            // Pings are answered by tungstenite itself
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            Message::Text(_) => return Err(anyhow!("Unexpected text message on tunnel")),
+        }
+    }
+    Ok(None)
+}
+
+struct WebSocketRecvHalf<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> super::RecvHalf for WebSocketRecvHalf<S> {
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        next_data(&mut self.stream).await
+    }
+}
+
+struct WebSocketSendHalf<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> super::SendHalf for WebSocketSendHalf<S> {
+    async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.sink.send(Message::Binary(data)).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.sink.close().await?;
+        Ok(())
+    }
+}
+
+type Accepted = Result<(WebSocketConnection<TcpStream>, SocketAddr)>;
+
+struct WebSocketListener {
+    rx: mpsc::Receiver<Accepted>,
+}
+
+#[async_trait]
+impl super::Listener for WebSocketListener {
+    async fn accept(&mut self) -> Result<(Box<dyn super::Connection>, super::Endpoint)> {
+        let (connection, addr) = self.rx.recv().await
+            .ok_or_else(|| anyhow!("WebSocket listener closed"))??;
+        Ok((Box::new(connection), addr.into()))
+    }
+}
+
+/// Relays the listener believes about who the client is.
+#[derive(Clone)]
+struct Trusted {
+    proxy_protocol: Option<TrustedProxies>,
+    forwarded_for: Option<TrustedProxies>,
+}
+
+/// Performs HTTP upgrades in the background so a slow client
+/// cannot stall `accept()` for everyone else.
+async fn accept_loop(listener: TcpListener, path: String, trusted: Trusted, tx: mpsc::Sender<Accepted>) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if tx.send(Err(e.into())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = tx.closed() => break,
+        };
+        let tx = tx.clone();
+        let path = path.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            let addr = match proxy_protocol::accept_header(&mut stream, addr, trusted.proxy_protocol.as_ref()).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let mut forwarded = None;
+            // Signature dictated by tungstenite's handshake callback
+            #[allow(clippy::result_large_err)]
+            let check_path = |request: &Request, response: Response| -> std::result::Result<Response, ErrorResponse> {
+                // Each relay may have added a line of its own: read as one list
+                let lines: Vec<_> = request.headers().get_all("x-forwarded-for").iter()
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                    .collect();
+                forwarded = (!lines.is_empty()).then(|| lines.join(", "));
+                if request.uri().path() == path {
+                    Ok(response)
+                } else {
+                    let mut not_found = ErrorResponse::new(None);
+                    *not_found.status_mut() = StatusCode::NOT_FOUND;
+                    Err(not_found)
+                }
+            };
+            let upgrade = tokio::time::timeout(UPGRADE_TIMEOUT, tokio_tungstenite::accept_hdr_async(stream, check_path)).await;
+            let result = match upgrade {
+                Ok(Ok(ws)) => {
+                    let client = proxy_protocol::forwarded_for(forwarded.as_deref(), addr, trusted.forwarded_for.as_ref());
+                    Ok((WebSocketConnection { ws }, client))
+                }
+                Ok(Err(e)) => Err(anyhow!("WebSocket upgrade from {} failed: {}", addr, e)),
+                Err(_) => Err(anyhow!("WebSocket upgrade from {} timed out", addr)),
+            };
+            let _ = tx.send(result).await;
+        });
+    }
+}
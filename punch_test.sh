@@ -0,0 +1,94 @@
+#!/usr/bin/env bash
+# Hole punching end to end, in network namespaces (run as root):
+#
+#   chm-a (10.1.0.2, server)  -- chm-nat-a --+
+#                                            +-- chm-wan (203.0.113.1, rendezvous)
+#   chm-b (10.2.0.2, client)  -- chm-nat-b --+
+#
+# Each NAT masquerades its LAN behind its WAN address (needs iptables), so
+# the server is only reachable through the rendezvous. BLOCK_DIRECT=1 cuts
+# the way between the NATs, which makes the client fall back to relaying.
+#
+#   cargo build && sudo ./punch_test.sh
+#   sudo BLOCK_DIRECT=1 ./punch_test.sh
+
+set -euo pipefail
+
+BIN=${BIN:-target/debug}
+BLOCK_DIRECT=${BLOCK_DIRECT:-0}
+NAMESPACES="chm-a chm-b chm-nat-a chm-nat-b chm-wan"
+
+cleanup() {
+    for ns in $NAMESPACES; do
+        ip netns pids "$ns" 2>/dev/null | xargs -r kill 2>/dev/null || true
+        ip netns del "$ns" 2>/dev/null || true
+    done
+}
+trap cleanup EXIT
+cleanup
+
+for ns in $NAMESPACES; do
+    ip netns add "$ns"
+    ip -n "$ns" link set lo up
+done
+
+# link NS1 IF1 ADDR1 NS2 IF2 ADDR2
+link() {
+    ip link add "$2" netns "$1" type veth peer name "$5" netns "$4"
+    ip -n "$1" addr add "$3" dev "$2"
+    ip -n "$4" addr add "$6" dev "$5"
+    ip -n "$1" link set "$2" up
+    ip -n "$4" link set "$5" up
+}
+link chm-wan wan-a 203.0.113.1/30 chm-nat-a nat-a-wan 203.0.113.2/30
+link chm-wan wan-b 203.0.113.5/30 chm-nat-b nat-b-wan 203.0.113.6/30
+link chm-nat-a nat-a-lan 10.1.0.1/24 chm-a a0 10.1.0.2/24
+link chm-nat-b nat-b-lan 10.2.0.1/24 chm-b b0 10.2.0.2/24
+
+ip -n chm-a route add default via 10.1.0.1
+ip -n chm-b route add default via 10.2.0.1
+ip -n chm-nat-a route add default via 203.0.113.1
+ip -n chm-nat-b route add default via 203.0.113.5
+for nat in chm-nat-a chm-nat-b chm-wan; do
+    ip netns exec "$nat" sysctl -qw net.ipv4.ip_forward=1
+done
+
+if command -v iptables >/dev/null; then
+    ip netns exec chm-nat-a iptables -t nat -A POSTROUTING -o nat-a-wan -j MASQUERADE
+    ip netns exec chm-nat-b iptables -t nat -A POSTROUTING -o nat-b-wan -j MASQUERADE
+    # Only what the LAN asked for comes back in
+    ip netns exec chm-nat-a iptables -A FORWARD -i nat-a-wan -m conntrack ! --ctstate ESTABLISHED,RELATED -j DROP
+    ip netns exec chm-nat-b iptables -A FORWARD -i nat-b-wan -m conntrack ! --ctstate ESTABLISHED,RELATED -j DROP
+else
+    echo "iptables not found: routing the LANs without NAT"
+    ip -n chm-wan route add 10.1.0.0/24 via 203.0.113.2
+    ip -n chm-wan route add 10.2.0.0/24 via 203.0.113.6
+fi
+
+if [ "$BLOCK_DIRECT" = 1 ]; then
+    ip -n chm-nat-a route add blackhole 203.0.113.6/32
+    ip -n chm-nat-a route add blackhole 10.2.0.0/24
+    ip -n chm-nat-b route add blackhole 203.0.113.2/32
+    ip -n chm-nat-b route add blackhole 10.1.0.0/24
+fi
+
+mkdir -p /tmp/chm-punch
+head -c 1000000 /dev/urandom > /tmp/chm-punch/blob.bin
+
+ip netns exec chm-wan env SERVER_RENDEZVOUS_BIND=203.0.113.1:3478 SERVER_PUNCH_KEY=punch-test \
+    "$BIN/server" > /tmp/chm-punch/rendezvous.log 2>&1 &
+ip netns exec chm-a python3 -m http.server -b 127.0.0.1 -d /tmp/chm-punch 8000 > /dev/null 2>&1 &
+ip netns exec chm-a env SERVER_PUNCH=203.0.113.1:3478 SERVER_PUNCH_ID=home SERVER_PUNCH_KEY=punch-test \
+    "$BIN/server" > /tmp/chm-punch/server.log 2>&1 &
+sleep 1
+ip netns exec chm-b env SERVER_HOST=10.1.0.2 SERVER_PUNCH=203.0.113.1:3478 SERVER_PUNCH_ID=home SERVER_PUNCH_KEY=punch-test CLIENT_BOND=Punch \
+    "$BIN/client" > /tmp/chm-punch/client.log 2>&1 &
+sleep 5
+
+ip netns exec chm-b curl -s -m 20 --socks5 127.0.0.1:1080 http://127.0.0.1:8000/blob.bin -o /tmp/chm-punch/got.bin
+if cmp -s /tmp/chm-punch/blob.bin /tmp/chm-punch/got.bin; then
+    echo "OK: fetched through the punched tunnel (logs in /tmp/chm-punch)"
+else
+    echo "FAILED (logs in /tmp/chm-punch)"
+    exit 1
+fi